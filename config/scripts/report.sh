@@ -0,0 +1,5 @@
+#!/bin/bash
+# Prints a short report of the server passed as the first argument
+echo "Report for $1 in ${REGION}"
+uptime
+df -h /
@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::executor::Assignment;
+use crate::inventory::Target;
+
+/// Rules mapping users and roles to the jobs they can run and the
+/// servers they can run them on, the `access` section of
+/// `basecamp.yaml`. Everyone can run everything until a rule is
+/// configured, then what no rule allows is denied
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AccessPolicy {
+    /// Users of each role
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roles: BTreeMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>
+}
+
+/// Jobs which some users and roles can run on some servers
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rule {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Servers selected by name, `group:NAME`, `tag:NAME` or `all`
+    #[serde(default = "Rule::all_servers")]
+    pub servers: Vec<String>,
+    /// Names of the jobs, `*` matching any text
+    pub jobs: Vec<String>
+}
+
+impl Rule {
+    fn all_servers() -> Vec<String> {
+        return vec![String::from("all")];
+    }
+
+    /// Returns whether the rule lets the job run on the target
+    fn allows(&self, target: &Target, job: &str) -> bool {
+        return self.servers.iter().any(|s| target.matches(s))
+            && self.jobs.iter().any(|pattern| glob_matches(pattern, job));
+    }
+}
+
+/// Returns whether the name matches the pattern,
+/// where `*` matches any text
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = format!("^{}$", regex::escape(pattern).replace("\\*", ".*"));
+    return Regex::new(&pattern).map(|r| r.is_match(name)).unwrap_or(false);
+}
+
+impl AccessPolicy {
+    /// Method to check that every rule names who it applies
+    /// to and that the roles it names are defined
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.users.is_empty() && rule.roles.is_empty() {
+                return Err(format!("Access rule {} must list users or roles", i + 1).into());
+            }
+            if rule.jobs.is_empty() {
+                return Err(format!("Access rule {} must list jobs", i + 1).into());
+            }
+            if let Some(role) = rule.roles.iter().find(|r| !self.roles.contains_key(*r)) {
+                return Err(format!("Access rule {} names the undefined role `{}`", i + 1, role).into());
+            }
+        }
+        return Ok(());
+    }
+
+    /// Method to get what the user can run, given by the
+    /// rules naming the user or one of their roles
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let access = settings.access.for_user(&user.username);
+    /// let denied = access.check(&plan);
+    /// ```
+    pub fn for_user(&self, username: &str) -> Access {
+        if self.rules.is_empty() {
+            return Access { username: username.to_string(), rules: None };
+        }
+
+        let roles: Vec<&String> = self.roles.iter()
+            .filter(|(_, users)| users.iter().any(|u| u == username))
+            .map(|(role, _)| role)
+            .collect();
+        let rules = self.rules.iter()
+            .filter(|rule| rule.users.iter().any(|u| u == username) || rule.roles.iter().any(|r| roles.contains(&r)))
+            .cloned()
+            .collect();
+        return Access { username: username.to_string(), rules: Some(rules) };
+    }
+}
+
+/// What a user can run. Without rules the user can run everything
+#[derive(Clone, Debug)]
+pub struct Access {
+    pub username: String,
+    rules: Option<Vec<Rule>>
+}
+
+impl Access {
+    /// Name allowing interactive shells in the rules, as a shell
+    /// can run any command on the server
+    pub const SHELL: &'static str = "@shell";
+
+    /// Name allowing broadcast sessions in the rules
+    pub const BROADCAST: &'static str = "@broadcast";
+
+    /// Name allowing tunnels through the server in the rules
+    pub const TUNNEL: &'static str = "@tunnel";
+
+    /// Returns whether the user can run the job on the target
+    pub fn allows(&self, target: &Target, job: &str) -> bool {
+        return match &self.rules {
+            Some(rules) => rules.iter().any(|rule| rule.allows(target, job)),
+            None => true
+        };
+    }
+
+    /// Returns whether the user can run any of the jobs on the target
+    pub fn allows_server(&self, target: &Target, jobs: &[String]) -> bool {
+        return jobs.iter().any(|job| self.allows(target, job));
+    }
+
+    /// Returns whether the user can run the job on any of the targets
+    pub fn allows_job(&self, job: &str, targets: &[Target]) -> bool {
+        return targets.iter().any(|target| self.allows(target, job));
+    }
+
+    /// Returns the reason why the user can not run the job on the target
+    pub fn denial(&self, target: &Target, job: &str) -> String {
+        return format!("{} is not allowed to run {} on {}", self.username, job, target.name);
+    }
+
+    /// Method to check that the user can run the job, or open the
+    /// shell, broadcast or tunnel it names, on every target
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let targets = access.allowed_targets(inventory.select(&selectors)?, Access::BROADCAST)?;
+    /// ```
+    pub fn allowed_targets(&self, targets: Vec<Target>, job: &str) -> Result<Vec<Target>, Box<dyn std::error::Error>> {
+        let denied: Vec<String> = targets.iter()
+            .filter(|target| !self.allows(target, job))
+            .map(|target| self.denial(target, job))
+            .collect();
+        if !denied.is_empty() {
+            return Err(denied.join("\n").into());
+        }
+        return Ok(targets);
+    }
+
+    /// Method to find the jobs of a plan the user can not run
+    pub fn check(&self, plan: &[Assignment]) -> Vec<String> {
+        let mut denied = Vec::new();
+        for assignment in plan {
+            for job in &assignment.jobs {
+                if !self.allows(&assignment.target, &job.name) {
+                    denied.push(self.denial(&assignment.target, &job.name));
+                }
+            }
+        }
+        return denied;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(name: &str, groups: &[&str]) -> Target {
+        let yaml = format!("{{ name: {}, host: 10.0.0.1, user: deploy, groups: [{}] }}", name, groups.join(", "));
+        return serde_yaml::from_str(&yaml).unwrap();
+    }
+
+    fn policy() -> AccessPolicy {
+        return serde_yaml::from_str("
+roles:
+  ops: [carol]
+rules:
+  - { users: [alice], servers: [group:web], jobs: ['deploy-*', '@shell'] }
+  - { roles: [ops], jobs: ['*'] }
+").unwrap();
+    }
+
+    #[test]
+    fn everything_is_allowed_without_rules() {
+        let access = AccessPolicy::default().for_user("anyone");
+        assert!(access.allows(&target("db-1", &[]), "drop-tables"));
+        assert!(access.allows(&target("db-1", &[]), Access::TUNNEL));
+    }
+
+    #[test]
+    fn rules_allow_jobs_on_servers() {
+        let access = policy().for_user("alice");
+        let web = target("web-1", &["web"]);
+        let db = target("db-1", &["db"]);
+        assert!(access.allows(&web, "deploy-app"));
+        assert!(access.allows(&web, Access::SHELL));
+        assert!(!access.allows(&web, "deploy"));
+        assert!(!access.allows(&web, Access::TUNNEL));
+        assert!(!access.allows(&db, "deploy-app"));
+        assert!(access.allows_server(&web, &[String::from("uptime"), String::from("deploy-app")]));
+        assert!(access.allows_job("deploy-app", &[db.clone(), web.clone()]));
+        assert_eq!(access.allowed_targets(vec![web.clone(), db], "deploy-app").unwrap_err().to_string(),
+            "alice is not allowed to run deploy-app on db-1");
+        assert_eq!(access.allowed_targets(vec![web], "deploy-app").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn roles_grant_their_rules() {
+        let policy = policy();
+        assert!(policy.for_user("carol").allows(&target("db-1", &[]), Access::BROADCAST));
+        assert!(!policy.for_user("mallory").allows(&target("web-1", &["web"]), "deploy-app"));
+    }
+
+    #[test]
+    fn rules_must_name_who_they_apply_to() {
+        assert!(policy().validate().is_ok());
+        let unnamed: AccessPolicy = serde_yaml::from_str("rules: [{ jobs: ['*'] }]").unwrap();
+        assert!(unnamed.validate().is_err());
+        let unknown_role: AccessPolicy = serde_yaml::from_str("rules: [{ roles: [dba], jobs: ['*'] }]").unwrap();
+        assert!(unknown_role.validate().is_err());
+    }
+}
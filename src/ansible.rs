@@ -0,0 +1,502 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::collections::{BTreeMap, BTreeSet};
+use serde_json::{Map, Value};
+
+use crate::inventory::{Group, Inventory, Target};
+
+/// Variables holding how to connect to a host, the first one set being used
+const HOST_VARS: &[&str] = &["ansible_host", "ansible_ssh_host"];
+const PORT_VARS: &[&str] = &["ansible_port", "ansible_ssh_port"];
+const USER_VARS: &[&str] = &["ansible_user", "ansible_ssh_user"];
+const KEY_VARS: &[&str] = &["ansible_ssh_private_key_file", "ansible_private_key_file"];
+
+/// A group of an Ansible inventory
+#[derive(Clone, Debug, Default)]
+struct AnsibleGroup {
+    hosts: Vec<String>,
+    vars: BTreeMap<String, Value>,
+    children: Vec<String>
+}
+
+/// Groups and hosts read from an Ansible inventory, in the
+/// shape of the JSON of `ansible-inventory --list`
+#[derive(Clone, Debug, Default)]
+pub struct AnsibleInventory {
+    groups: BTreeMap<String, AnsibleGroup>,
+    hostvars: BTreeMap<String, BTreeMap<String, Value>>
+}
+
+impl AnsibleInventory {
+    /// Method to run a dynamic inventory script with `--list` and
+    /// parse its JSON. Hosts are asked for with `--host NAME` when
+    /// the script gives no `_meta.hostvars`
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let inventory = AnsibleInventory::from_script(Path::new("inventory/ec2.py"))?.to_inventory()?;
+    /// ```
+    pub fn from_script(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let list = AnsibleInventory::run_script(path, &["--list"])?;
+        let has_hostvars = list.get("_meta").and_then(|meta| meta.get("hostvars")).is_some();
+        let mut inventory = AnsibleInventory::from_json(list, &path.display().to_string())?;
+        if !has_hostvars {
+            for host in inventory.hosts() {
+                let vars = AnsibleInventory::run_script(path, &["--host", &host])?;
+                inventory.hostvars.insert(host, object_vars(vars));
+            }
+        }
+        return Ok(inventory);
+    }
+
+    /// Method to run the script and parse what it prints as JSON
+    fn run_script(path: &Path, args: &[&str]) -> Result<Value, Box<dyn std::error::Error>> {
+        let output = Command::new(path).args(args).output()
+            .map_err(|err| format!("Could not run the inventory script {}: {}", path.display(), err))?;
+        if !output.status.success() {
+            return Err(format!(
+                "The inventory script {} {} failed with {}: {}",
+                path.display(), args.join(" "), output.status, String::from_utf8_lossy(&output.stderr).trim()
+            ).into());
+        }
+        return Ok(serde_json::from_slice(&output.stdout)
+            .map_err(|err| format!("Invalid JSON from the inventory script {}: {}", path.display(), err))?);
+    }
+
+    /// Method to parse the JSON of `--list`. Groups are objects
+    /// with `hosts`, `vars` and `children`, or lists of hosts
+    fn from_json(list: Value, source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let entries = match list {
+            Value::Object(entries) => entries,
+            _ => return Err(format!("The inventory of {} must be a JSON object", source).into())
+        };
+
+        let mut inventory = AnsibleInventory::default();
+        for (name, entry) in entries {
+            if name == "_meta" {
+                if let Some(Value::Object(hostvars)) = entry.get("hostvars") {
+                    for (host, vars) in hostvars {
+                        inventory.hostvars.insert(host.clone(), object_vars(vars.clone()));
+                    }
+                }
+                continue;
+            }
+
+            let group = match entry {
+                Value::Array(hosts) => AnsibleGroup { hosts: strings(&hosts), ..AnsibleGroup::default() },
+                Value::Object(fields) => AnsibleGroup {
+                    hosts: fields.get("hosts").and_then(|h| h.as_array()).map(|h| strings(h)).unwrap_or_default(),
+                    vars: fields.get("vars").cloned().map(object_vars).unwrap_or_default(),
+                    children: fields.get("children").and_then(|c| c.as_array()).map(|c| strings(c)).unwrap_or_default()
+                },
+                _ => return Err(format!("Invalid group {} in the inventory of {}", name, source).into())
+            };
+            inventory.groups.insert(name, group);
+        }
+        return Ok(inventory);
+    }
+
+    /// Method to read a static inventory, in YAML when the
+    /// file ends with `.yml` or `.yaml` and in INI otherwise
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let inventory = AnsibleInventory::from_file(Path::new("inventory/hosts.ini"))?.to_inventory()?;
+    /// ```
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read the inventory {}: {}", path.display(), err))?;
+        let source = path.display().to_string();
+        return match path.extension().and_then(|e| e.to_str()) {
+            Some("yml") | Some("yaml") => AnsibleInventory::from_yaml(&contents, &source),
+            _ => AnsibleInventory::from_ini(&contents, &source)
+        };
+    }
+
+    /// Method to parse an INI inventory. Hosts listed before the
+    /// first section are ungrouped, `[NAME:vars]` sections hold the
+    /// variables of a group and `[NAME:children]` its child groups
+    fn from_ini(contents: &str, source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut inventory = AnsibleInventory::default();
+        let mut section = (String::from("ungrouped"), String::from("hosts"));
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                let header = &line[1..line.len() - 1];
+                section = match header.split_once(':') {
+                    Some((name, kind)) if kind == "vars" || kind == "children" => (name.to_string(), kind.to_string()),
+                    Some(_) => return Err(format!("{}:{}: invalid section [{}]", source, i + 1, header).into()),
+                    None => (header.to_string(), String::from("hosts"))
+                };
+                inventory.groups.entry(section.0.clone()).or_default();
+                continue;
+            }
+
+            let group = inventory.groups.entry(section.0.clone()).or_default();
+            match section.1.as_str() {
+                "vars" => match line.split_once('=') {
+                    Some((key, value)) => {
+                        group.vars.insert(key.trim().to_string(), Value::String(unquote(value.trim()).to_string()));
+                    },
+                    None => return Err(format!("{}:{}: expected KEY=VALUE", source, i + 1).into())
+                },
+                "children" => group.children.push(line.to_string()),
+                _ => {
+                    let mut fields = split_fields(line).into_iter();
+                    let pattern = fields.next().unwrap_or_default();
+                    let mut vars = BTreeMap::new();
+                    for field in fields {
+                        match field.split_once('=') {
+                            Some((key, value)) => vars.insert(key.to_string(), ini_value(value)),
+                            None => return Err(format!("{}:{}: expected KEY=VALUE, found `{}`", source, i + 1, field).into())
+                        };
+                    }
+                    for host in expand_range(&pattern).map_err(|err| format!("{}:{}: {}", source, i + 1, err))? {
+                        group.hosts.push(host.clone());
+                        inventory.hostvars.entry(host).or_default().extend(vars.clone());
+                    }
+                }
+            }
+        }
+        return Ok(inventory);
+    }
+
+    /// Method to parse a YAML inventory, where each group
+    /// maps `hosts` to their variables and `children`
+    /// to the groups nested in it
+    fn from_yaml(contents: &str, source: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let yaml: serde_yaml::Value = serde_yaml::from_str(contents).map_err(|err| format!("{}: {}", source, err))?;
+        let root: Value = serde_json::to_value(yaml).map_err(|err| format!("{}: {}", source, err))?;
+
+        let mut inventory = AnsibleInventory::default();
+        match root {
+            Value::Object(groups) => {
+                for (name, group) in groups {
+                    inventory.add_yaml_group(&name, &group, source)?;
+                }
+            },
+            Value::Null => {},
+            _ => return Err(format!("{}: the inventory must map group names to groups", source).into())
+        }
+        return Ok(inventory);
+    }
+
+    fn add_yaml_group(&mut self, name: &str, group: &Value, source: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let fields = match group {
+            Value::Object(fields) => fields.clone(),
+            Value::Null => Map::new(),
+            _ => return Err(format!("{}: group {} must be a mapping", source, name).into())
+        };
+
+        let mut hosts = Vec::new();
+        if let Some(Value::Object(entries)) = fields.get("hosts") {
+            for (pattern, vars) in entries {
+                for host in expand_range(pattern).map_err(|err| format!("{}: {}", source, err))? {
+                    self.hostvars.entry(host.clone()).or_default().extend(object_vars(vars.clone()));
+                    hosts.push(host);
+                }
+            }
+        }
+        let mut children = Vec::new();
+        if let Some(Value::Object(entries)) = fields.get("children") {
+            for (child, group) in entries {
+                self.add_yaml_group(child, group, source)?;
+                children.push(child.clone());
+            }
+        }
+
+        // A group may be listed under several parents
+        let entry = self.groups.entry(name.to_string()).or_default();
+        entry.hosts.extend(hosts);
+        entry.children.extend(children);
+        entry.vars.extend(fields.get("vars").cloned().map(object_vars).unwrap_or_default());
+        return Ok(());
+    }
+
+    /// Returns the names of every host of the inventory
+    fn hosts(&self) -> Vec<String> {
+        let mut hosts = BTreeSet::new();
+        for group in self.groups.values() {
+            hosts.extend(group.hosts.iter().cloned());
+        }
+        hosts.extend(self.hostvars.keys().cloned());
+        return hosts.into_iter().collect();
+    }
+
+    /// Returns the groups of the host, parents before their
+    /// children. Every host belongs to `all`, which is only
+    /// listed when it has variables
+    fn groups_of(&self, host: &str) -> Vec<String> {
+        let mut groups: Vec<String> = Vec::new();
+        for (name, group) in &self.groups {
+            if group.hosts.iter().any(|h| h == host) {
+                self.add_with_parents(name, &mut groups, &mut Vec::new());
+            }
+        }
+        let has_all_vars = self.groups.get("all").map(|g| !g.vars.is_empty()).unwrap_or(false);
+        groups.retain(|g| g != "ungrouped" && g != "all");
+        if has_all_vars {
+            groups.insert(0, String::from("all"));
+        }
+        return groups;
+    }
+
+    /// Method to add the group after its parents, `path`
+    /// holding the groups being added to stop at cycles
+    fn add_with_parents(&self, name: &str, groups: &mut Vec<String>, path: &mut Vec<String>) {
+        if groups.iter().any(|g| g == name) || path.iter().any(|p| p == name) {
+            return;
+        }
+        path.push(name.to_string());
+        for (parent, group) in &self.groups {
+            if group.children.iter().any(|c| c == name) {
+                self.add_with_parents(parent, groups, path);
+            }
+        }
+        path.pop();
+        groups.push(name.to_string());
+    }
+
+    /// Method to convert the hosts to servers and the groups to
+    /// groups sharing their variables. The server connects to
+    /// `ansible_host`, `ansible_port` and as `ansible_user`, the
+    /// local user by default, using `ansible_ssh_private_key_file`
+    pub fn to_inventory(&self) -> Result<Inventory, Box<dyn std::error::Error>> {
+        let mut groups = BTreeMap::new();
+        for (name, group) in &self.groups {
+            if name != "ungrouped" && (name != "all" || !group.vars.is_empty()) {
+                groups.insert(name.clone(), Group { vars: group.vars.clone(), protected: false });
+            }
+        }
+
+        let mut servers = Vec::new();
+        for name in self.hosts() {
+            let host_groups = self.groups_of(&name);
+            let host_vars = self.hostvars.get(&name).cloned().unwrap_or_default();
+            let mut vars = BTreeMap::new();
+            for group in host_groups.iter().filter_map(|g| self.groups.get(g)) {
+                vars.extend(group.vars.clone());
+            }
+            vars.extend(host_vars.clone());
+
+            let port = match first_var(&vars, PORT_VARS) {
+                Some(port) => port.parse::<u16>().map_err(|_| format!("Invalid ansible_port `{}` of {}", port, name))?,
+                None => 22
+            };
+            servers.push(Target {
+                host: first_var(&vars, HOST_VARS).unwrap_or_else(|| name.clone()),
+                name,
+                port,
+                user: first_var(&vars, USER_VARS).unwrap_or_else(crate::auth::local_username),
+                key: first_var(&vars, KEY_VARS),
+                groups: host_groups,
+                tags: Vec::new(),
+                vars: host_vars,
+                jump: None,
+                via: None
+            });
+        }
+        return Ok(Inventory::new(groups, servers));
+    }
+}
+
+/// Returns the first of the variables which is set, as text
+fn first_var(vars: &BTreeMap<String, Value>, names: &[&str]) -> Option<String> {
+    return names.iter().filter_map(|name| vars.get(*name)).find_map(|value| match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None
+    });
+}
+
+/// Returns the variables of a JSON object, none for anything else
+fn object_vars(value: Value) -> BTreeMap<String, Value> {
+    return match value {
+        Value::Object(vars) => vars.into_iter().collect(),
+        _ => BTreeMap::new()
+    };
+}
+
+fn strings(values: &[Value]) -> Vec<String> {
+    return values.iter().filter_map(|v| v.as_str()).map(String::from).collect();
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in &['"', '\''] {
+        if value.len() >= 2 && value.starts_with(*quote) && value.ends_with(*quote) {
+            return &value[1..value.len() - 1];
+        }
+    }
+    return value;
+}
+
+/// Returns the value of a host variable of an INI inventory,
+/// where numbers and booleans are written as they are
+fn ini_value(value: &str) -> Value {
+    if value.starts_with('"') || value.starts_with('\'') {
+        return Value::String(unquote(value).to_string());
+    }
+    if let Ok(number) = value.parse::<i64>() {
+        return Value::from(number);
+    }
+    return match value {
+        "true" | "True" => Value::Bool(true),
+        "false" | "False" => Value::Bool(false),
+        _ => Value::String(value.to_string())
+    };
+}
+
+/// Method to split a host line on whitespace, keeping
+/// quoted values together
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => {
+                quote = None;
+                field.push(c);
+            },
+            Some(_) => field.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                field.push(c);
+            },
+            None if c == '#' && field.is_empty() => break,
+            None if c.is_whitespace() => {
+                if !field.is_empty() {
+                    fields.push(std::mem::take(&mut field));
+                }
+            },
+            None => field.push(c)
+        }
+    }
+    if !field.is_empty() {
+        fields.push(field);
+    }
+    return fields;
+}
+
+/// Method to expand a host pattern with a numeric range such
+/// as `web[01:10].example.com` or an alphabetic one like `db-[a:c]`
+fn expand_range(pattern: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let (prefix, rest) = match pattern.split_once('[') {
+        Some(parts) => parts,
+        None => return Ok(vec![pattern.to_string()])
+    };
+    let (range, suffix) = match rest.split_once(']') {
+        Some(parts) => parts,
+        None => return Err(format!("Unclosed range in host pattern `{}`", pattern).into())
+    };
+    let (start, end) = match range.split_once(':') {
+        Some(bounds) => bounds,
+        None => return Err(format!("Invalid range in host pattern `{}`", pattern).into())
+    };
+
+    let values: Vec<String> = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(first), Ok(last)) if first <= last => {
+            let width = if start.starts_with('0') { start.len() } else { 0 };
+            (first..=last).map(|n| format!("{:0width$}", n, width = width)).collect()
+        },
+        _ if start.len() == 1 && end.len() == 1 && start <= end => {
+            (start.as_bytes()[0]..=end.as_bytes()[0]).map(|c| (c as char).to_string()).collect()
+        },
+        _ => return Err(format!("Invalid range in host pattern `{}`", pattern).into())
+    };
+
+    let mut hosts = Vec::new();
+    for value in values {
+        for rest in expand_range(suffix)? {
+            hosts.push(format!("{}{}{}", prefix, value, rest));
+        }
+    }
+    return Ok(hosts);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server<'a>(inventory: &'a Inventory, name: &str) -> &'a Target {
+        return inventory.servers().iter().find(|s| s.name == name).unwrap();
+    }
+
+    #[test]
+    fn ranges_are_expanded() {
+        assert_eq!(expand_range("web[01:03].example.com").unwrap(), vec!["web01.example.com", "web02.example.com", "web03.example.com"]);
+        assert_eq!(expand_range("db-[a:c]").unwrap(), vec!["db-a", "db-b", "db-c"]);
+        assert_eq!(expand_range("r[1:2]n[8:9]").unwrap(), vec!["r1n8", "r1n9", "r2n8", "r2n9"]);
+        assert_eq!(expand_range("plain").unwrap(), vec!["plain"]);
+        assert!(expand_range("web[3:1]").is_err());
+        assert!(expand_range("web[1:3").is_err());
+        assert!(expand_range("web[1]").is_err());
+    }
+
+    #[test]
+    fn ini_inventories_have_groups_children_and_vars() {
+        let ini = r#"
+bastion ansible_host=203.0.113.1
+
+[web]
+web[1:2] ansible_user=deploy http_port=8080
+
+[db]
+db1 ansible_host=10.0.0.5 ansible_port=2222 role="primary db"
+
+[prod:children]
+web
+db
+
+[prod:vars]
+region = "eu-west-1"
+"#;
+        let inventory = AnsibleInventory::from_ini(ini, "hosts").unwrap().to_inventory().unwrap();
+        assert_eq!(inventory.servers().len(), 4);
+
+        let web = server(&inventory, "web2");
+        assert_eq!((web.host.as_str(), web.user.as_str(), web.port), ("web2", "deploy", 22));
+        assert_eq!(web.groups, vec!["prod", "web"]);
+        assert_eq!(web.vars.get("http_port"), Some(&Value::from(8080)));
+
+        let db = server(&inventory, "db1");
+        assert_eq!((db.host.as_str(), db.port), ("10.0.0.5", 2222));
+        assert_eq!(db.vars.get("role"), Some(&Value::from("primary db")));
+        assert_eq!(server(&inventory, "bastion").groups, Vec::<String>::new());
+
+        assert!(AnsibleInventory::from_ini("[web:hosts]", "hosts").is_err());
+        assert!(AnsibleInventory::from_ini("[web:vars]\nno value", "hosts").is_err());
+    }
+
+    #[test]
+    fn yaml_inventories_nest_children() {
+        let yaml = "
+all:
+  vars:
+    ansible_user: admin
+  children:
+    prod:
+      vars:
+        region: eu-west-1
+      children:
+        web:
+          hosts:
+            web[1:2]:
+              ansible_port: 2200
+        db:
+          hosts:
+            db1:
+";
+        let inventory = AnsibleInventory::from_yaml(yaml, "hosts.yml").unwrap().to_inventory().unwrap();
+        let web = server(&inventory, "web1");
+        assert_eq!((web.user.as_str(), web.port), ("admin", 2200));
+        assert_eq!(web.groups, vec!["all", "prod", "web"]);
+        assert_eq!(server(&inventory, "db1").groups, vec!["all", "prod", "db"]);
+        assert!(AnsibleInventory::from_yaml("- web1", "hosts.yml").is_err());
+    }
+}
@@ -0,0 +1,765 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use std::collections::BTreeMap;
+use chrono::Utc;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::body::Bytes;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::service::{make_service_fn, service_fn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::events::{Event, EventBus, EventKind, RunEvents};
+use crate::executor::{Assignment, Executor};
+use crate::guardrails::Acknowledgement;
+use crate::history::{History, JobResult, Run};
+use crate::inventory::Inventory;
+use crate::job::Job;
+use crate::settings::Settings;
+use crate::workflow;
+
+/// Settings of `basecamp serve`, the `api` section of `basecamp.yaml`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiSettings {
+    /// Address the API listens on
+    #[serde(default = "ApiSettings::default_bind")]
+    pub bind: String,
+    /// Tokens accepted by the API. Requests are made as
+    /// the user of their token
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<ApiToken>
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        return ApiSettings { bind: ApiSettings::default_bind(), tokens: Vec::new() };
+    }
+}
+
+impl ApiSettings {
+    fn default_bind() -> String {
+        return String::from("127.0.0.1:8754");
+    }
+
+    /// Returns the user of the token, if it is accepted
+    fn user_of(&self, token: &str) -> Option<String> {
+        let digest = hex::encode(Sha256::digest(token.as_bytes()));
+        return self.tokens.iter()
+            .find(|t| constant_time_eq(t.sha256.to_lowercase().as_bytes(), digest.as_bytes()))
+            .map(|t| t.user.clone());
+    }
+}
+
+/// A token of the API, stored as the hex SHA-256 of the token
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub user: String,
+    pub sha256: String
+}
+
+/// Compares the bytes without stopping at the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    return a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
+
+/// Body of `POST /runs`
+#[derive(Clone, Debug, Deserialize)]
+pub struct RunRequest {
+    /// Servers selected by name, `group:NAME`, `tag:NAME` or `all`
+    pub targets: Vec<String>,
+    pub jobs: Vec<String>,
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    /// Reason to run despite the guardrails, which can
+    /// not be confirmed through the API
+    #[serde(default)]
+    pub override_reason: Option<String>
+}
+
+/// A run checked and planned, ready to be executed
+pub struct Prepared {
+    pub user: String,
+    pub plan: Vec<Assignment>,
+    pub jobs: Vec<Job>,
+    pub params: BTreeMap<String, String>,
+    pub acknowledgement: Acknowledgement
+}
+
+/// Lets the API follow and cancel a run
+pub struct RunControl {
+    /// Id the run is stored under
+    pub id: String,
+    pub cancel: Arc<AtomicBool>,
+    /// Publishes the progress of the run
+    pub events: RunEvents
+}
+
+/// Executes a prepared run with the normal executor,
+/// stores it in the history and returns it
+pub type Runner = dyn Fn(Prepared, RunControl) -> Result<Run, String> + Send + Sync;
+
+/// State of a run started through the API
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RunState {
+    Running,
+    Finished,
+    Failed
+}
+
+/// A run started through the API, kept while the server runs
+struct ApiRun {
+    user: String,
+    state: RunState,
+    /// Results known so far
+    results: Vec<JobResult>,
+    cancel: Arc<AtomicBool>,
+    error: Option<String>
+}
+
+impl ApiRun {
+    fn describe(&self, id: &str) -> Value {
+        return json!({
+            "id": id,
+            "user": self.user,
+            "status": self.state,
+            "cancelled": self.cancel.load(Ordering::SeqCst),
+            "results": self.results,
+            "error": self.error
+        });
+    }
+}
+
+/// Shared by the requests of the API
+struct State {
+    settings: Settings,
+    /// Token of the user who started the server, used
+    /// to fetch the inventory
+    token: String,
+    runner: Arc<Runner>,
+    runs: Mutex<BTreeMap<String, ApiRun>>,
+    /// Events of the runs started through the API
+    events: EventBus
+}
+
+/// An error answered to the client
+struct ApiError {
+    status: StatusCode,
+    message: String
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl ToString) -> Self {
+        return ApiError { status, message: message.to_string() };
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for ApiError {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        return ApiError::new(StatusCode::BAD_REQUEST, err);
+    }
+}
+
+/// Interval at which the progress of a run is streamed
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Interval of the comments keeping idle event streams open
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Method to serve the API until the process is interrupted.
+/// `token` is used to fetch the inventory and `runner`
+/// executes the runs
+///
+/// # Examples
+/// ```no_run
+/// api::serve(settings, &user.token, Arc::new(|prepared, control| execute(prepared, control))).await?;
+/// ```
+pub async fn serve(settings: Settings, token: &str, runner: Arc<Runner>) -> Result<(), Box<dyn std::error::Error>> {
+    if settings.api.tokens.is_empty() {
+        return Err("No API tokens are configured in the `api` section of basecamp.yaml".into());
+    }
+    let address: SocketAddr = settings.api.bind.parse()
+        .map_err(|err| format!("Invalid API address {}: {}", settings.api.bind, err))?;
+
+    let state = Arc::new(State {
+        settings,
+        token: token.to_string(),
+        runner,
+        runs: Mutex::new(BTreeMap::new()),
+        events: EventBus::new()
+    });
+    let make_service = make_service_fn(move |_| {
+        let state = Arc::clone(&state);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let state = Arc::clone(&state);
+                async move { Ok::<_, Infallible>(handle(state, request).await) }
+            }))
+        }
+    });
+
+    println!("Serving the API on http://{}", address);
+    Server::try_bind(&address)
+        .map_err(|err| format!("Could not listen on {}: {}", address, err))?
+        .serve(make_service)
+        .await?;
+    return Ok(());
+}
+
+/// Method to authenticate the request and route it
+async fn handle(state: Arc<State>, request: Request<Body>) -> Response<Body> {
+    let token = request.headers().get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    let user = match token.and_then(|token| state.settings.api.user_of(&token)) {
+        Some(user) => user,
+        None => {
+            let mut response = error_response(&ApiError::new(StatusCode::UNAUTHORIZED, "A valid bearer token is required"));
+            response.headers_mut().insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            return response;
+        }
+    };
+
+    let method = request.method().clone();
+    let path: Vec<String> = request.uri().path().trim_matches('/').split('/').map(String::from).collect();
+    let path: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+    let result = match (&method, path.as_slice()) {
+        (&Method::GET, ["servers"]) => list_servers(&state, &user).await,
+        (&Method::GET, ["jobs"]) => list_jobs(&state, &user).await,
+        (&Method::GET, ["runs"]) => Ok(list_runs(&state, &user)),
+        (&Method::POST, ["runs"]) => match hyper::body::to_bytes(request.into_body()).await {
+            Ok(body) => start_run(&state, &user, &body).await,
+            Err(err) => Err(ApiError::new(StatusCode::BAD_REQUEST, err))
+        },
+        (&Method::GET, ["runs", id]) => get_run(&state, &user, id),
+        (&Method::GET, ["runs", id, "progress"]) => return stream_progress(&state, &user, id),
+        (&Method::GET, ["runs", id, "events"]) => return stream_events(&state, &user, Some(id.to_string())),
+        (&Method::GET, ["events"]) => {
+            let run = request.uri().query().into_iter()
+                .flat_map(|query| query.split('&'))
+                .find_map(|pair| pair.strip_prefix("run="))
+                .map(String::from);
+            return stream_events(&state, &user, run);
+        },
+        (&Method::POST, ["runs", id, "cancel"]) => cancel_run(&state, &user, id),
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, format!("No route for {} {}", method, path.join("/"))))
+    };
+
+    return match result {
+        Ok((status, body)) => json_response(status, &body),
+        Err(err) => error_response(&err)
+    };
+}
+
+fn json_response(status: StatusCode, body: &Value) -> Response<Body> {
+    return Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+}
+
+fn error_response(err: &ApiError) -> Response<Body> {
+    return json_response(err.status, &json!({ "error": err.message }));
+}
+
+/// Method to load the inventory and the jobs the user can see
+async fn load(state: &State) -> Result<(Inventory, Vec<Job>), ApiError> {
+    let inventory = crate::sources::load_inventory(&state.settings, &state.token, &crate::http::stderr_notices()).await
+        .map_err(|err| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    let catalog = Job::load_all(&crate::paths::config_dir().join(Job::FILE_NAME))
+        .map_err(|err| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err))?;
+    return Ok((inventory, catalog));
+}
+
+/// `GET /servers`, the servers the user can run a job on
+async fn list_servers(state: &State, user: &str) -> Result<(StatusCode, Value), ApiError> {
+    let (inventory, catalog) = load(state).await?;
+    let access = state.settings.access.for_user(user);
+    let names: Vec<String> = catalog.iter().map(|j| j.name.clone()).collect();
+    let servers: Vec<_> = inventory.servers().iter().filter(|s| access.allows_server(s, &names)).collect();
+    return Ok((StatusCode::OK, json!(servers)));
+}
+
+/// `GET /jobs`, the jobs the user can run on a server
+async fn list_jobs(state: &State, user: &str) -> Result<(StatusCode, Value), ApiError> {
+    let (inventory, catalog) = load(state).await?;
+    let access = state.settings.access.for_user(user);
+    let jobs: Vec<&Job> = catalog.iter().filter(|j| access.allows_job(&j.name, inventory.servers())).collect();
+    return Ok((StatusCode::OK, json!(jobs)));
+}
+
+/// Method to find a run started through the API,
+/// checking that it was started by the user
+fn owned_run<'a>(runs: &'a BTreeMap<String, ApiRun>, user: &str, id: &str) -> Result<&'a ApiRun, ApiError> {
+    return match runs.get(id) {
+        Some(run) if run.user == user => Ok(run),
+        Some(_) => Err(ApiError::new(StatusCode::FORBIDDEN, format!("Run {} was started by another user", id))),
+        None => Err(ApiError::new(StatusCode::NOT_FOUND, format!("Run {} is not running on this server", id)))
+    };
+}
+
+/// `GET /runs`, the runs the user started since the server started
+fn list_runs(state: &State, user: &str) -> (StatusCode, Value) {
+    let runs = state.runs.lock().unwrap();
+    let listed: Vec<Value> = runs.iter()
+        .filter(|(_, run)| run.user == user)
+        .map(|(id, run)| json!({ "id": id, "user": run.user, "status": run.state }))
+        .collect();
+    return (StatusCode::OK, json!(listed));
+}
+
+/// Method to plan the run the way `basecamp run` does. Guardrails
+/// can only be overridden with a reason and every job must be
+/// allowed for the user
+async fn prepare(state: &State, user: &str, request: RunRequest) -> Result<Prepared, ApiError> {
+    let (inventory, catalog) = load(state).await?;
+    let targets = inventory.select(&request.targets)?;
+    let mut selected = Vec::with_capacity(request.jobs.len());
+    for name in &request.jobs {
+        match catalog.iter().find(|j| &j.name == name) {
+            Some(job) => selected.push(job.clone()),
+            None => return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Unknown job {}", name)))
+        }
+    }
+    let jobs = workflow::with_dependencies(&selected, &catalog)?;
+
+    let missing = Executor::missing_params(&jobs, &request.vars)?;
+    if !missing.is_empty() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Missing vars {}", missing.join(", "))));
+    }
+    let plan = Executor::plan(&targets, &jobs, &request.vars)?;
+
+    let denied = state.settings.access.for_user(user).check(&plan);
+    if !denied.is_empty() {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, denied.join("; ")));
+    }
+    let violations = state.settings.guardrails.check(&plan, &inventory.protected_groups())?;
+    let messages: Vec<String> = violations.iter().map(|v| v.message.clone()).collect();
+    if !violations.is_empty() && request.override_reason.is_none() {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!(
+            "The run must be confirmed: {}. Pass an override_reason to run anyway",
+            messages.join("; ")
+        )));
+    }
+
+    return Ok(Prepared {
+        user: user.to_string(),
+        plan,
+        jobs,
+        params: request.vars,
+        acknowledgement: Acknowledgement { violations: messages, override_reason: request.override_reason }
+    });
+}
+
+/// `POST /runs`, starts the run in its own thread and
+/// answers with its id without waiting for it
+async fn start_run(state: &Arc<State>, user: &str, body: &Bytes) -> Result<(StatusCode, Value), ApiError> {
+    let request: RunRequest = serde_json::from_slice(body)
+        .map_err(|err| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid run request: {}", err)))?;
+    let prepared = prepare(state, user, request).await?;
+
+    let id = Run::generate_id(&Utc::now());
+    let cancel = Arc::new(AtomicBool::new(false));
+    let events = state.events.subscribe();
+    state.runs.lock().unwrap().insert(id.clone(), ApiRun {
+        user: user.to_string(),
+        state: RunState::Running,
+        results: Vec::new(),
+        cancel: Arc::clone(&cancel),
+        error: None
+    });
+
+    // Results are collected as they are published until the run
+    // is over, then replaced by the results of the run
+    let collector = Arc::clone(state);
+    let collected_id = id.clone();
+    thread::spawn(move || {
+        loop {
+            let event = events.recv_timeout(PROGRESS_INTERVAL);
+            let mut runs = collector.runs.lock().unwrap();
+            let run = match runs.get_mut(&collected_id) {
+                Some(run) if run.state == RunState::Running => run,
+                _ => break
+            };
+            match event {
+                Ok(Event { run: Some(ref event_run), kind: EventKind::JobFinished { result }, .. }) if *event_run == collected_id => {
+                    run.results.push(*result);
+                },
+                Ok(_) | Err(mpsc::RecvTimeoutError::Timeout) => {},
+                Err(mpsc::RecvTimeoutError::Disconnected) => break
+            }
+        }
+    });
+
+    let runner = Arc::clone(state);
+    let control = RunControl { id: id.clone(), cancel, events: RunEvents::new(state.events.clone(), &id) };
+    thread::spawn(move || {
+        let id = control.id.clone();
+        let outcome = (runner.runner)(prepared, control);
+        let mut runs = runner.runs.lock().unwrap();
+        if let Some(api_run) = runs.get_mut(&id) {
+            match outcome {
+                Ok(run) => {
+                    api_run.results = run.results;
+                    api_run.state = RunState::Finished;
+                },
+                Err(err) => {
+                    api_run.error = Some(err);
+                    api_run.state = RunState::Failed;
+                }
+            }
+        }
+    });
+
+    return Ok((StatusCode::ACCEPTED, json!({ "id": id, "status": RunState::Running })));
+}
+
+/// `GET /runs/ID`, a run started through the API or a run
+/// of the history, if the user started it
+fn get_run(state: &State, user: &str, id: &str) -> Result<(StatusCode, Value), ApiError> {
+    let runs = state.runs.lock().unwrap();
+    if runs.contains_key(id) {
+        let run = owned_run(&runs, user, id)?;
+        let mut described = run.describe(id);
+        if run.state == RunState::Finished {
+            if let Ok(stored) = History::open_default().load(id) {
+                described["run"] = json!(stored);
+            }
+        }
+        return Ok((StatusCode::OK, described));
+    }
+
+    return match History::open_default().load(id) {
+        Ok(run) if run.user != user => Err(ApiError::new(StatusCode::FORBIDDEN, format!("Run {} was started by another user", id))),
+        Ok(run) => Ok((StatusCode::OK, json!({
+            "id": id,
+            "user": run.user,
+            "status": RunState::Finished,
+            "results": run.results,
+            "run": run
+        }))),
+        Err(_) => Err(ApiError::new(StatusCode::NOT_FOUND, format!("Run {} not found", id)))
+    };
+}
+
+/// `GET /runs/ID/progress`, streams the results of the run as
+/// JSON lines as they come in, the last line holding its status
+fn stream_progress(state: &Arc<State>, user: &str, id: &str) -> Response<Body> {
+    if let Err(err) = owned_run(&state.runs.lock().unwrap(), user, id) {
+        return error_response(&err);
+    }
+
+    let (mut sender, body) = Body::channel();
+    let state = Arc::clone(state);
+    let id = id.to_string();
+    tokio::spawn(async move {
+        let mut sent = 0;
+        loop {
+            let (lines, finished) = {
+                let runs = state.runs.lock().unwrap();
+                let run = match runs.get(&id) {
+                    Some(run) => run,
+                    None => return
+                };
+                let mut lines: Vec<String> = run.results[sent.min(run.results.len())..].iter()
+                    .map(|result| json!({ "result": result }).to_string())
+                    .collect();
+                sent = run.results.len();
+                let finished = run.state != RunState::Running;
+                if finished {
+                    lines.push(json!({ "id": id, "status": run.state, "error": run.error }).to_string());
+                }
+                (lines, finished)
+            };
+
+            for line in lines {
+                if sender.send_data(Bytes::from(format!("{}\n", line))).await.is_err() {
+                    return;
+                }
+            }
+            if finished {
+                return;
+            }
+            tokio::time::delay_for(PROGRESS_INTERVAL).await;
+        }
+    });
+
+    return Response::builder()
+        .header(CONTENT_TYPE, "application/x-ndjson")
+        .body(body)
+        .unwrap();
+}
+
+/// `GET /events` and `GET /runs/ID/events`, streams the events of
+/// the runs the user started through the API as server-sent events,
+/// named after their type with the event as JSON data. `?run=ID`
+/// limits `/events` to a run
+fn stream_events(state: &Arc<State>, user: &str, run: Option<String>) -> Response<Body> {
+    if let Some(id) = &run {
+        if let Err(err) = owned_run(&state.runs.lock().unwrap(), user, id) {
+            return error_response(&err);
+        }
+    }
+
+    let (mut sender, body) = Body::channel();
+    let events = state.events.subscribe();
+    let state = Arc::clone(state);
+    let user = user.to_string();
+    // Events are received in their own thread, as receiving blocks
+    thread::spawn(move || {
+        loop {
+            let chunk = match events.recv_timeout(KEEPALIVE_INTERVAL) {
+                Ok(event) => {
+                    let visible = match &event.run {
+                        Some(id) => run.as_ref().map(|run| run == id).unwrap_or(true)
+                            && state.runs.lock().unwrap().get(id).map(|r| r.user == user).unwrap_or(false),
+                        None => false
+                    };
+                    if !visible {
+                        continue;
+                    }
+                    let data = serde_json::to_string(&event).unwrap_or_default();
+                    let chunk = format!("event: {}\ndata: {}\n\n", event.name(), data);
+                    // The stream of a run ends with the run
+                    if run.is_some() {
+                        if let EventKind::RunFinished { .. } = event.kind {
+                            let _ = futures::executor::block_on(sender.send_data(Bytes::from(chunk)));
+                            return;
+                        }
+                    }
+                    chunk
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => String::from(": keepalive\n\n"),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return
+            };
+            // Stops once the client is gone
+            if futures::executor::block_on(sender.send_data(Bytes::from(chunk))).is_err() {
+                return;
+            }
+        }
+    });
+
+    return Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap();
+}
+
+/// `POST /runs/ID/cancel`, cancels the jobs of the run which
+/// have not started. Only the user who started it can cancel it
+fn cancel_run(state: &State, user: &str, id: &str) -> Result<(StatusCode, Value), ApiError> {
+    let runs = state.runs.lock().unwrap();
+    let run = owned_run(&runs, user, id)?;
+    if run.state != RunState::Running {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("Run {} is over", id)));
+    }
+    run.cancel.store(true, Ordering::SeqCst);
+    return Ok((StatusCode::ACCEPTED, json!({ "id": id, "status": run.state, "cancelled": true })));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::history::Status;
+    use crate::paths::testing::TestHome;
+
+    const SERVERS: &str = "
+servers:
+  - { name: web-1, host: 10.0.0.1, user: deploy }
+  - { name: db-1, host: 10.0.0.2, user: deploy }
+";
+
+    const JOBS: &str = "
+jobs:
+  - { name: hello, command: echo hello }
+  - { name: uptime, command: uptime }
+";
+
+    /// Alice can run everything, Bob can only run `hello` on `web-1`
+    const SETTINGS: &str = "
+access:
+  rules:
+    - { users: [alice], jobs: ['*'] }
+    - { users: [bob], servers: [web-1], jobs: [hello] }
+";
+
+    /// Runs until the run is cancelled, then reports
+    /// its jobs as cancelled
+    fn runner(prepared: Prepared, control: RunControl) -> Result<Run, String> {
+        let started = Instant::now();
+        while !control.cancel.load(Ordering::SeqCst) && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut results = Vec::new();
+        for assignment in &prepared.plan {
+            for job in &assignment.jobs {
+                let result = JobResult {
+                    server: assignment.target.name.clone(),
+                    job: job.name.clone(),
+                    command: job.commands().join("\n"),
+                    status: Status::Cancelled,
+                    exit_status: None,
+                    output: String::new(),
+                    error: None,
+                    steps: Vec::new(),
+                    outputs: BTreeMap::new(),
+                    changed: None,
+                    duration_ms: 0
+                };
+                control.events.publish(EventKind::JobFinished { result: Box::new(result.clone()) });
+                results.push(result);
+            }
+        }
+        control.events.run_finished(&results, 0);
+        return Ok(Run {
+            id: control.id.clone(),
+            rerun_of: None,
+            schedule: None,
+            user: prepared.user,
+            started_at: Utc::now(),
+            duration_ms: 0,
+            targets: prepared.plan.into_iter().map(|a| a.target).collect(),
+            jobs: prepared.jobs,
+            params: prepared.params,
+            guardrails: Vec::new(),
+            override_reason: None,
+            results
+        });
+    }
+
+    fn state(home: &TestHome) -> Arc<State> {
+        home.config("server.yaml", SERVERS);
+        home.config("job.yaml", JOBS);
+        let mut settings: Settings = serde_yaml::from_str(SETTINGS).unwrap();
+        for user in &["alice", "bob"] {
+            let sha256 = hex::encode(Sha256::digest(format!("{}-token", user).as_bytes()));
+            settings.api.tokens.push(ApiToken { user: user.to_string(), sha256 });
+        }
+        return Arc::new(State {
+            settings,
+            token: String::new(),
+            runner: Arc::new(runner),
+            runs: Mutex::new(BTreeMap::new()),
+            events: EventBus::new()
+        });
+    }
+
+    /// Method to send a request as the user, returning
+    /// the status and the body of the answer
+    fn send(state: &Arc<State>, user: Option<&str>, method: Method, path: &str, body: &str) -> (StatusCode, String) {
+        let mut request = Request::builder().method(method).uri(path);
+        if let Some(user) = user {
+            request = request.header(AUTHORIZATION, format!("Bearer {}-token", user));
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        return runtime.block_on(async {
+            let response = handle(Arc::clone(state), request).await;
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        });
+    }
+
+    fn send_json(state: &Arc<State>, user: Option<&str>, method: Method, path: &str, body: &str) -> (StatusCode, Value) {
+        let (status, body) = send(state, user, method, path, body);
+        return (status, serde_json::from_str(&body).unwrap());
+    }
+
+    /// Returns the names listed by the endpoint
+    fn names(state: &Arc<State>, user: &str, path: &str) -> Vec<String> {
+        let (status, body) = send_json(state, Some(user), Method::GET, path, "");
+        assert_eq!(status, StatusCode::OK);
+        return body.as_array().unwrap().iter().map(|item| item["name"].as_str().unwrap().to_string()).collect();
+    }
+
+    fn start(state: &Arc<State>, user: &str) -> String {
+        let (status, body) = send_json(state, Some(user), Method::POST, "/runs", r#"{"targets": ["web-1"], "jobs": ["hello"]}"#);
+        assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+        return body["id"].as_str().unwrap().to_string();
+    }
+
+    #[test]
+    fn requests_need_a_valid_token() {
+        let home = TestHome::new();
+        let state = state(&home);
+        assert_eq!(send(&state, None, Method::GET, "/servers", "").0, StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder().uri("/servers").header(AUTHORIZATION, "Bearer nobody").body(Body::empty()).unwrap();
+        let response = tokio::runtime::Runtime::new().unwrap().block_on(handle(Arc::clone(&state), request));
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn servers_and_jobs_are_listed_for_the_user() {
+        let home = TestHome::new();
+        let state = state(&home);
+        assert_eq!(names(&state, "alice", "/servers"), vec!["web-1", "db-1"]);
+        assert_eq!(names(&state, "alice", "/jobs"), vec!["hello", "uptime"]);
+        assert_eq!(names(&state, "bob", "/servers"), vec!["web-1"]);
+        assert_eq!(names(&state, "bob", "/jobs"), vec!["hello"]);
+    }
+
+    #[test]
+    fn runs_are_started_followed_and_cancelled() {
+        let home = TestHome::new();
+        let state = state(&home);
+        let id = start(&state, "alice");
+
+        let (status, run) = send_json(&state, Some("alice"), Method::GET, &format!("/runs/{}", id), "");
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(run["status"], "running");
+        let (_, runs) = send_json(&state, Some("alice"), Method::GET, "/runs", "");
+        assert_eq!(runs[0]["id"], id.as_str());
+
+        let (status, _) = send_json(&state, Some("alice"), Method::POST, &format!("/runs/{}/cancel", id), "");
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        // The progress ends with the status of the run
+        let (status, progress) = send(&state, Some("alice"), Method::GET, &format!("/runs/{}/progress", id), "");
+        assert_eq!(status, StatusCode::OK);
+        let lines: Vec<Value> = progress.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.last().unwrap()["status"], "finished");
+
+        let (_, run) = send_json(&state, Some("alice"), Method::GET, &format!("/runs/{}", id), "");
+        assert_eq!(run["cancelled"], true);
+        assert_eq!(run["results"][0]["status"], "cancelled");
+        let (status, _) = send_json(&state, Some("alice"), Method::POST, &format!("/runs/{}/cancel", id), "");
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn runs_of_other_users_are_forbidden() {
+        let home = TestHome::new();
+        let state = state(&home);
+        let id = start(&state, "alice");
+
+        for (method, path) in &[
+            (Method::GET, format!("/runs/{}", id)),
+            (Method::GET, format!("/runs/{}/progress", id)),
+            (Method::GET, format!("/runs/{}/events", id)),
+            (Method::GET, format!("/events?run={}", id)),
+            (Method::POST, format!("/runs/{}/cancel", id))
+        ] {
+            assert_eq!(send(&state, Some("bob"), method.clone(), path, "").0, StatusCode::FORBIDDEN, "{} {}", method, path);
+        }
+        let (_, runs) = send_json(&state, Some("bob"), Method::GET, "/runs", "");
+        assert_eq!(runs, json!([]));
+
+        // Bob may not run jobs he is not allowed to
+        let (status, _) = send(&state, Some("bob"), Method::POST, "/runs", r#"{"targets": ["db-1"], "jobs": ["hello"]}"#);
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        send(&state, Some("alice"), Method::POST, &format!("/runs/{}/cancel", id), "");
+    }
+}
@@ -0,0 +1,370 @@
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::history::{Run, Status};
+use crate::inventory::Target;
+
+/// Hash used as the previous hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Head of the last log appended to by the process, with the
+/// length of the log once it was written. Locking it also
+/// serializes the appends of the threads of the process
+static LAST_HEAD: Mutex<Option<(PathBuf, u64, AuditHead)>> = Mutex::new(None);
+
+/// A command as it was sent to a server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditCommand {
+    /// Empty for the entries written before the commands were
+    /// rendered per server, skipped so their hash still matches
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub server: String,
+    pub job: String,
+    pub command: String
+}
+
+/// Outcome of a job on a server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditOutcome {
+    pub server: String,
+    pub job: String,
+    pub status: Status,
+    pub exit_status: Option<i32>
+}
+
+/// What was run, where, when and by whom
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+    pub local_host: String,
+    pub run_id: String,
+    /// The targets as `name (user@host:port)`
+    pub targets: Vec<String>,
+    pub commands: Vec<AuditCommand>,
+    pub outcomes: Vec<AuditOutcome>,
+    /// Guardrails which were confirmed or overridden
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub guardrails: Vec<String>,
+    /// Reason given to override the guardrails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub override_reason: Option<String>
+}
+
+impl AuditRecord {
+    /// Method to build the record of a finished run
+    pub fn from_run(run: &Run) -> Self {
+        return AuditRecord {
+            seq: 0,
+            timestamp: Utc::now(),
+            user: run.user.clone(),
+            local_host: gethostname::gethostname().to_string_lossy().to_string(),
+            run_id: run.id.clone(),
+            targets: run.targets.iter()
+                .map(|t| format!("{} ({}@{})", t.name, t.user, t.address()))
+                .collect(),
+            commands: run.results.iter()
+                .map(|r| AuditCommand { server: r.server.clone(), job: r.job.clone(), command: r.command.clone() })
+                .collect(),
+            outcomes: run.results.iter()
+                .map(|r| AuditOutcome {
+                    server: r.server.clone(),
+                    job: r.job.clone(),
+                    status: r.status,
+                    exit_status: r.exit_status
+                })
+                .collect(),
+            guardrails: run.guardrails.clone(),
+            override_reason: run.override_reason.clone()
+        };
+    }
+
+    /// Method to build the record of an interactive session opened
+    /// on the targets: a shell, a line sent by a broadcast session
+    /// or a tunnel. `job` names the kind of session
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let record = AuditRecord::from_session(&user.username, &id, &targets, Access::SHELL, "interactive shell", Vec::new());
+    /// AuditLog::open_default().append(record)?;
+    /// ```
+    pub fn from_session(user: &str, session_id: &str, targets: &[Target], job: &str, command: &str, guardrails: Vec<String>) -> Self {
+        return AuditRecord {
+            seq: 0,
+            timestamp: Utc::now(),
+            user: user.to_string(),
+            local_host: gethostname::gethostname().to_string_lossy().to_string(),
+            run_id: session_id.to_string(),
+            targets: targets.iter()
+                .map(|t| format!("{} ({}@{})", t.name, t.user, t.address()))
+                .collect(),
+            commands: targets.iter()
+                .map(|t| AuditCommand { server: t.name.clone(), job: job.to_string(), command: command.to_string() })
+                .collect(),
+            outcomes: Vec::new(),
+            guardrails,
+            override_reason: None
+        };
+    }
+}
+
+/// A line of the audit log. Every entry includes the hash of
+/// the previous entry so that modifying, removing or reordering
+/// entries breaks the chain
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    #[serde(flatten)]
+    pub record: AuditRecord,
+    pub prev_hash: String,
+    pub hash: String
+}
+
+impl AuditEntry {
+    /// Method to compute the hash of a record chained
+    /// to the previous hash
+    fn compute_hash(prev_hash: &str, record: &AuditRecord) -> Result<String, Box<dyn std::error::Error>> {
+        let mut hasher = Sha256::new();
+        hasher.update(prev_hash.as_bytes());
+        hasher.update(serde_json::to_string(record)?.as_bytes());
+        return Ok(hex::encode(hasher.finalize()));
+    }
+}
+
+/// Number of entries of the log and hash of the last one,
+/// stored next to the log so that removing entries from
+/// its end can be detected
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditHead {
+    pub count: u64,
+    pub hash: String
+}
+
+impl AuditHead {
+    fn of(entries: &[AuditEntry]) -> Self {
+        return match entries.last() {
+            Some(entry) => AuditHead { count: entries.len() as u64, hash: entry.hash.clone() },
+            None => AuditHead { count: 0, hash: String::from(GENESIS_HASH) }
+        };
+    }
+}
+
+/// Append-only log of the runs, stored as json lines
+pub struct AuditLog {
+    path: PathBuf
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        return AuditLog { path };
+    }
+
+    /// Method to open the audit log stored in the
+    /// basecamp data directory
+    pub fn open_default() -> Self {
+        return AuditLog::new(crate::paths::data_dir().join("audit.log"));
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        return &self.path;
+    }
+
+    /// Returns the path of the head of the log
+    pub fn head_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".head");
+        return self.path.with_file_name(name);
+    }
+
+    /// Method to read the head of the log, `None` if
+    /// it was never written
+    fn head(&self) -> Result<Option<AuditHead>, Box<dyn std::error::Error>> {
+        let path = self.head_path();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let head = serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        return Ok(Some(head));
+    }
+
+    /// Method to replace the head of the log
+    fn write_head(&self, head: &AuditHead) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.head_path();
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let temp = path.with_file_name(name);
+        fs::write(&temp, serde_json::to_string(head)?)?;
+        fs::rename(&temp, &path)?;
+        return Ok(());
+    }
+
+    /// Method to read every entry of the log
+    fn entries(&self) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        let reader = BufReader::new(fs::File::open(&self.path)?);
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: AuditEntry = serde_json::from_str(&line)
+                .map_err(|err| format!("Line {}: {}", i + 1, err))?;
+            entries.push(entry);
+        }
+        return Ok(entries);
+    }
+
+    /// Method to append a record to the log, chaining it to the
+    /// last entry, and update the head of the log. The log is
+    /// locked while appending, so that concurrent appends of
+    /// other threads and processes do not fork the chain
+    ///
+    /// # Examples
+    /// ```no_run
+    /// AuditLog::open_default().append(AuditRecord::from_run(&run))?;
+    /// ```
+    pub fn append(&self, mut record: AuditRecord) -> Result<AuditEntry, Box<dyn std::error::Error>> {
+        let mut last = LAST_HEAD.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        lock(&file)?;
+
+        // The log is only read again when another
+        // process appended to it since the last append
+        let length = file.metadata()?.len();
+        let head = match &*last {
+            Some((path, cached_length, head)) if *path == self.path && *cached_length == length => head.clone(),
+            _ => AuditHead::of(&self.entries()?)
+        };
+        record.seq = head.count;
+
+        let hash = AuditEntry::compute_hash(&head.hash, &record)?;
+        let entry = AuditEntry { record, prev_hash: head.hash, hash };
+
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        file.sync_all()?;
+        let head = AuditHead { count: entry.record.seq + 1, hash: entry.hash.clone() };
+        *last = Some((self.path.clone(), file.metadata()?.len(), head.clone()));
+        self.write_head(&head)?;
+        return Ok(entry);
+    }
+
+    /// Method to verify the hash chain of the log and that its
+    /// head is still part of it. Returns the number of entries and
+    /// the hash of the last entry, or an error describing the first
+    /// entry which has been tampered with
+    pub fn verify(&self) -> Result<(usize, String), Box<dyn std::error::Error>> {
+        let entries = self.entries()?;
+        let head = match self.head()? {
+            Some(head) => head,
+            None if entries.is_empty() => AuditHead::of(&entries),
+            None => return Err(format!("{} is missing, entries may have been removed from the end", self.head_path().display()).into())
+        };
+        // The log may be ahead of its head if the process
+        // appending to it stopped before updating the head
+        let anchored = match head.count {
+            0 => true,
+            count => entries.get(count as usize - 1).map(|e| e.hash == head.hash).unwrap_or(false)
+        };
+        if !anchored {
+            return Err(format!(
+                "the head records {} entries ending with {}, entries were removed from the end",
+                head.count,
+                head.hash
+            ).into());
+        }
+
+        let mut prev_hash = String::from(GENESIS_HASH);
+
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.record.seq != i as u64 {
+                return Err(format!("Entry {}: expected sequence number {}, found {}", i, i, entry.record.seq).into());
+            }
+            if entry.prev_hash != prev_hash {
+                return Err(format!("Entry {}: previous hash does not match, entries were removed or reordered", i).into());
+            }
+            if AuditEntry::compute_hash(&prev_hash, &entry.record)? != entry.hash {
+                return Err(format!("Entry {}: hash does not match, the entry was modified", i).into());
+            }
+            prev_hash = entry.hash.clone();
+        }
+        return Ok((entries.len(), prev_hash));
+    }
+}
+
+/// Method to take an exclusive lock on the file,
+/// released once it is closed
+#[cfg(unix)]
+fn lock(file: &fs::File) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &fs::File) -> std::io::Result<()> {
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn record(user: &str) -> AuditRecord {
+        return AuditRecord::from_session(user, "session", &[], "@shell", "interactive shell", Vec::new());
+    }
+
+    #[test]
+    fn concurrent_appends_form_a_single_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(AuditLog::new(dir.path().join("audit.log")));
+        let threads: Vec<_> = (0..4).map(|i| {
+            let log = Arc::clone(&log);
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    log.append(record(&format!("user-{}", i))).unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(log.verify().unwrap().0, 40);
+    }
+
+    #[test]
+    fn removing_the_last_entries_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path().join("audit.log"));
+        for user in &["alice", "bob", "carol"] {
+            log.append(record(user)).unwrap();
+        }
+        let (count, head) = log.verify().unwrap();
+        assert_eq!(count, 3);
+
+        let contents = fs::read_to_string(log.path()).unwrap();
+        let kept: Vec<&str> = contents.lines().take(2).collect();
+        fs::write(log.path(), format!("{}\n", kept.join("\n"))).unwrap();
+        let err = log.verify().unwrap_err().to_string();
+        assert!(err.contains(&head), "{}", err);
+
+        fs::remove_file(log.head_path()).unwrap();
+        assert!(log.verify().is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+use async_trait::async_trait;
+use reqwest::Method;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::escalation::check_private;
+use crate::http::{HttpClient, HttpSettings};
+
+/// Field of the login request holding the username by default
+const DEFAULT_USERNAME_FIELD: &str = "username";
+
+/// Field of the login response holding the token by default
+const DEFAULT_TOKEN_FIELD: &str = "token";
+
+/// Settings of the login, the `auth` section of `basecamp.yaml`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthSettings {
+    #[serde(flatten)]
+    pub backend: Backend,
+    /// Number of times the credentials can be entered
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    /// Minutes a login is remembered for, 0 to
+    /// log in on every run
+    #[serde(default = "default_session_ttl_minutes")]
+    pub session_ttl_minutes: u64
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        return AuthSettings {
+            backend: Backend::default(),
+            attempts: default_attempts(),
+            session_ttl_minutes: default_session_ttl_minutes()
+        };
+    }
+}
+
+fn default_attempts() -> u32 {
+    return 3;
+}
+
+fn default_session_ttl_minutes() -> u64 {
+    return 8 * 60;
+}
+
+/// Backend checking the credentials of the user
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum Backend {
+    /// Credentials are posted as JSON to the endpoint, which
+    /// answers with a token when they are valid
+    Http {
+        url: String,
+        #[serde(default = "default_username_field")]
+        username_field: String,
+        #[serde(default = "default_token_field")]
+        token_field: String
+    },
+    /// Users and their argon2 password hashes are kept in a
+    /// yaml file, `users.yaml` of the config directory by default
+    File {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>
+    },
+    /// Anyone can run jobs, as the local user
+    None
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        return Backend::File { path: None };
+    }
+}
+
+fn default_username_field() -> String {
+    return String::from(DEFAULT_USERNAME_FIELD);
+}
+
+fn default_token_field() -> String {
+    return String::from(DEFAULT_TOKEN_FIELD);
+}
+
+impl Backend {
+    /// Method to create the authenticator of the backend
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let authenticator = settings.auth.backend.authenticator(&settings.http)?;
+    /// let token = authenticator.authenticate("alice", &password).await?;
+    /// ```
+    pub fn authenticator(&self, http: &HttpSettings) -> Result<Box<dyn Authenticator>, Box<dyn std::error::Error>> {
+        return Ok(match self {
+            Backend::Http { url, username_field, token_field } => Box::new(HttpAuthenticator {
+                client: HttpClient::new(url, http)?,
+                username_field: username_field.clone(),
+                token_field: token_field.clone()
+            }),
+            Backend::File { path } => Box::new(FileAuthenticator { path: users_file(path.as_deref()) }),
+            Backend::None => Box::new(NoAuthenticator)
+        });
+    }
+
+    /// Returns a digest of the backend, so that a login is
+    /// forgotten once the backend changes
+    fn fingerprint(&self) -> String {
+        let settings = serde_json::to_string(self).unwrap_or_default();
+        return hex::encode(Sha256::digest(settings.as_bytes()));
+    }
+}
+
+/// A login remembered between runs, stored in `session.json`
+/// of the data directory. Only its owner can read it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedLogin {
+    pub username: String,
+    pub token: String,
+    backend: String,
+    pub expires_at: DateTime<Utc>
+}
+
+impl CachedLogin {
+    /// Name of the cache file inside the data directory
+    pub const FILE_NAME: &'static str = "session.json";
+
+    pub fn path() -> PathBuf {
+        return crate::paths::data_dir().join(CachedLogin::FILE_NAME);
+    }
+
+    /// Method to load the login remembered for the backend. Logins
+    /// which expired, were made with another backend or are stored
+    /// in a file readable by others are forgotten
+    ///
+    /// # Examples
+    /// ```no_run
+    /// if let Some(login) = CachedLogin::load(&settings.auth) {
+    ///     println!("Logged in as {}", login.username);
+    /// }
+    /// ```
+    pub fn load(settings: &AuthSettings) -> Option<CachedLogin> {
+        let path = CachedLogin::path();
+        if settings.session_ttl_minutes == 0 || !path.exists() {
+            return None;
+        }
+
+        let login = check_private(&path).ok()
+            .and_then(|_| fs::read_to_string(&path).ok())
+            .and_then(|contents| serde_json::from_str::<CachedLogin>(&contents).ok());
+        return match login {
+            Some(login) if login.backend == settings.backend.fingerprint() && login.expires_at > Utc::now() => Some(login),
+            _ => {
+                CachedLogin::clear();
+                None
+            }
+        };
+    }
+
+    /// Method to remember the login until the
+    /// session time to live has passed
+    pub fn save(settings: &AuthSettings, username: &str, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if settings.session_ttl_minutes == 0 {
+            return Ok(());
+        }
+        let login = CachedLogin {
+            username: username.to_string(),
+            token: token.to_string(),
+            backend: settings.backend.fingerprint(),
+            expires_at: Utc::now() + Duration::minutes(settings.session_ttl_minutes as i64)
+        };
+
+        let path = CachedLogin::path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // The file is replaced so that it is created private
+        CachedLogin::clear();
+        let mut file = private_file(&path)?;
+        file.write_all(serde_json::to_string(&login)?.as_bytes())?;
+        return Ok(());
+    }
+
+    /// Method to forget the remembered login
+    pub fn clear() {
+        let _ = fs::remove_file(CachedLogin::path());
+    }
+}
+
+/// Method to create a file only its owner can read and write
+#[cfg(unix)]
+fn private_file(path: &Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    return fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path);
+}
+
+#[cfg(not(unix))]
+fn private_file(path: &Path) -> std::io::Result<fs::File> {
+    return fs::OpenOptions::new().write(true).create_new(true).open(path);
+}
+
+/// Method to write a file only its owner can read and write,
+/// through a new private file which then replaces it at once
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let temp = PathBuf::from(format!("{}.tmp", path.display()));
+    let _ = fs::remove_file(&temp);
+    let mut file = private_file(&temp)?;
+    file.write_all(contents)?;
+    drop(file);
+    fs::rename(&temp, path)?;
+    return Ok(());
+}
+
+/// Returns the path of the users file, relative
+/// paths being inside the config directory
+pub fn users_file(path: Option<&str>) -> PathBuf {
+    return match path {
+        Some(path) if Path::new(path).is_absolute() => PathBuf::from(path),
+        Some(path) => crate::paths::config_dir().join(path),
+        None => crate::paths::config_dir().join(UsersFile::FILE_NAME)
+    };
+}
+
+/// Checks the credentials of the user before any job runs
+#[async_trait(?Send)]
+pub trait Authenticator {
+    /// Returns whether the user must enter credentials
+    fn needs_credentials(&self) -> bool {
+        return true;
+    }
+
+    /// Method to check the credentials. Returns the token of the
+    /// session, empty if the backend has none, or `None` when the
+    /// credentials are rejected. Errors mean the check could not
+    /// be made, e.g. the endpoint is unreachable
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Option<String>, Box<dyn std::error::Error>>;
+}
+
+/// Posts the credentials to a login endpoint
+pub struct HttpAuthenticator {
+    /// Client of the login endpoint
+    client: HttpClient,
+    username_field: String,
+    token_field: String
+}
+
+#[async_trait(?Send)]
+impl Authenticator for HttpAuthenticator {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let mut request_params: HashMap<&str, &str> = HashMap::new();
+        request_params.insert(&self.username_field, username);
+        request_params.insert("password", password);
+
+        let body = serde_json::to_value(&request_params)?;
+        let response = self.client.request(Method::POST, "", Some(&body)).await?;
+        if response.status.is_client_error() {
+            return Ok(None);
+        }
+        if !response.status.is_success() {
+            return Err(format!("The login endpoint {} answered {}", self.client.url(""), response.status).into());
+        }
+
+        let body = response.json::<serde_json::Value>()?;
+        return match body.get(&self.token_field).and_then(|token| token.as_str()) {
+            Some(token) if !token.is_empty() => Ok(Some(token.to_string())),
+            _ => Ok(None)
+        };
+    }
+}
+
+/// A user of the users file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserEntry {
+    pub name: String,
+    /// PHC string of the argon2 hash of the password
+    pub password_hash: String
+}
+
+/// The users file of the `file` backend
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsersFile {
+    #[serde(default)]
+    pub users: Vec<UserEntry>
+}
+
+impl UsersFile {
+    /// Name of the users file inside the config directory
+    pub const FILE_NAME: &'static str = "users.yaml";
+
+    /// Method to load the users from a yaml file. A
+    /// missing file has no users
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(UsersFile::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        if contents.trim().is_empty() {
+            return Ok(UsersFile::default());
+        }
+        return Ok(serde_yaml::from_str(&contents).map_err(|err| format!("{}: {}", path.display(), err))?);
+    }
+
+    /// Method to write the users to a new private file,
+    /// which then replaces the file at once
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        return write_private(path, serde_yaml::to_string(self)?.as_bytes());
+    }
+
+    /// Method to add the user, or replace the password
+    /// of an existing one, hashing it with argon2
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let mut users = UsersFile::load(&path)?;
+    /// users.set_password("alice", "correct horse battery staple")?;
+    /// users.save(&path)?;
+    /// ```
+    pub fn set_password(&mut self, name: &str, password: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default().hash_password(password.as_bytes(), &salt)
+            .map_err(|err| format!("Could not hash the password: {}", err))?
+            .to_string();
+
+        match self.users.iter_mut().find(|u| u.name == name) {
+            Some(user) => user.password_hash = password_hash,
+            None => self.users.push(UserEntry { name: name.to_string(), password_hash })
+        }
+        return Ok(());
+    }
+}
+
+/// Checks the credentials against the users file
+pub struct FileAuthenticator {
+    path: PathBuf
+}
+
+#[async_trait(?Send)]
+impl Authenticator for FileAuthenticator {
+    async fn authenticate(&self, username: &str, password: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let users = UsersFile::load(&self.path)?;
+        if users.users.is_empty() {
+            return Err(format!(
+                "No users are configured in {}, add one with `basecamp user add NAME`",
+                self.path.display()
+            ).into());
+        }
+
+        let user = match users.users.iter().find(|u| u.name == username) {
+            Some(user) => user,
+            None => return Ok(None)
+        };
+        let hash = PasswordHash::new(&user.password_hash)
+            .map_err(|err| format!("Invalid password hash of {} in {}: {}", username, self.path.display(), err))?;
+        if Argon2::default().verify_password(password.as_bytes(), &hash).is_err() {
+            return Ok(None);
+        }
+        return Ok(Some(String::new()));
+    }
+}
+
+/// Lets anyone in without credentials
+pub struct NoAuthenticator;
+
+#[async_trait(?Send)]
+impl Authenticator for NoAuthenticator {
+    fn needs_credentials(&self) -> bool {
+        return false;
+    }
+
+    async fn authenticate(&self, _username: &str, _password: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        Ok(Some(String::new()))
+    }
+}
+
+/// Returns the name of the local user, used
+/// when no credentials are asked for
+pub fn local_username() -> String {
+    return std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| String::from("unknown"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn users_are_saved_to_a_private_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(UsersFile::FILE_NAME);
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut users = UsersFile::default();
+        users.set_password("alice", "correct horse battery staple").unwrap();
+        users.save(&path).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(UsersFile::load(&path).unwrap().users[0].name, "alice");
+    }
+}
@@ -0,0 +1,353 @@
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+use chrono::Utc;
+use crossterm::terminal;
+use futures::executor::block_on;
+use ssh2::Channel;
+
+use crate::access::Access;
+use crate::audit::{AuditLog, AuditRecord};
+use crate::executor::Assignment;
+use crate::guardrails::{Guardrails, Violation};
+use crate::history::Run;
+use crate::inventory::Target;
+use crate::job::Job;
+use crate::server::Server;
+use crate::shell;
+
+/// Prompt shown while typing a line
+const PROMPT: &str = "broadcast> ";
+
+/// Prompt shown while typing the confirmation of a line
+const CONFIRM_PROMPT: &str = "confirm> ";
+
+/// A persistent shell on one of the servers
+struct Member {
+    name: String,
+    server: Server,
+    channel: Channel,
+    /// Lines are only sent to the active members
+    active: bool,
+    /// Output after the last new line
+    pending: Vec<u8>,
+    exited: bool
+}
+
+/// A line waiting for the guardrails it matches to be confirmed
+struct Pending {
+    line: String,
+    /// Messages of every violation of the line
+    violations: Vec<String>,
+    /// Violations left to confirm, in order
+    remaining: Vec<Violation>
+}
+
+/// A cluster-ssh style session sending every typed line
+/// to a shell on each of the servers. Lines are checked against
+/// the guardrails and written to the audit log before being sent
+pub struct Broadcast {
+    members: Vec<Member>,
+    input: String,
+    /// Id of the session in the audit log
+    id: String,
+    user: String,
+    guardrails: Guardrails,
+    protected_groups: Vec<String>,
+    /// Violations already confirmed during the session
+    confirmed: Vec<String>,
+    pending: Option<Pending>
+}
+
+impl Broadcast {
+    /// Method to connect to the targets and start a shell on each
+    /// of them. Targets which can not be reached are reported and
+    /// left out of the session
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let groups = inventory.protected_groups();
+    /// let mut broadcast = Broadcast::connect(&user.username, targets, settings.guardrails.clone(), groups)?;
+    /// broadcast.run()?;
+    /// ```
+    pub fn connect(user: &str, targets: Vec<Target>, guardrails: Guardrails, protected_groups: Vec<String>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut members = Vec::with_capacity(targets.len());
+        for target in targets {
+            let name = target.name.clone();
+            let mut server = Server::new(target);
+            let channel = block_on(server.connect()).and_then(|_| server.start_shell());
+            match channel {
+                Ok(channel) => members.push(Member { name, server, channel, active: true, pending: Vec::new(), exited: false }),
+                Err(err) => println!("Could not open a shell on {}: {}", name, err)
+            }
+        }
+        if members.is_empty() {
+            return Err("Could not open a shell on any server".into());
+        }
+        return Ok(Broadcast {
+            members,
+            input: String::new(),
+            id: Run::generate_id(&Utc::now()),
+            user: user.to_string(),
+            guardrails,
+            protected_groups,
+            confirmed: Vec::new(),
+            pending: None
+        });
+    }
+
+    /// Method to read lines from the terminal and send them to the
+    /// active servers while printing their output prefixed with the
+    /// server name, until `:quit` or until every shell has exited
+    pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for member in &self.members {
+            member.server.set_blocking(false);
+        }
+        self.print(&format!(
+            "Connected to {}. Lines are sent to every server, :help lists the commands",
+            self.names(|_| true)
+        ));
+
+        terminal::enable_raw_mode()?;
+        let result = self.pump();
+        terminal::disable_raw_mode()?;
+
+        for member in &mut self.members {
+            member.server.set_blocking(true);
+            let _ = member.channel.close();
+        }
+        println!();
+        return result;
+    }
+
+    /// Method to forward the input and output until the session ends
+    fn pump(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.redraw_prompt()?;
+        loop {
+            let mut idle = true;
+
+            for i in 0..self.members.len() {
+                if self.read_output(i)? {
+                    idle = false;
+                }
+            }
+            if self.members.iter().all(|m| m.exited) {
+                self.print("Every shell has exited");
+                return Ok(());
+            }
+
+            while poll(Duration::from_millis(0))? {
+                idle = false;
+                if let Event::Key(key) = read()? {
+                    if !self.handle_key(key)? {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if idle {
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+
+    /// Method to print the complete lines written by the shell
+    /// of a member. Returns true if any output was read
+    fn read_output(&mut self, index: usize) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut buffer = [0; 4096];
+        let mut lines = Vec::new();
+        let member = &mut self.members[index];
+        if member.exited {
+            return Ok(false);
+        }
+
+        let read = match member.channel.read(&mut buffer) {
+            Ok(0) if member.channel.eof() => {
+                // Print what is left of the last line
+                if !member.pending.is_empty() {
+                    lines.push(String::from_utf8_lossy(&member.pending).to_string());
+                    member.pending.clear();
+                }
+                lines.push(String::from("(shell exited)"));
+                member.exited = true;
+                0
+            },
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(err) => return Err(err.into())
+        };
+        member.pending.extend_from_slice(&buffer[..read]);
+        while let Some(end) = member.pending.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = member.pending.drain(..=end).collect();
+            lines.push(String::from_utf8_lossy(&line).trim_end_matches(&['\r', '\n'][..]).to_string());
+        }
+
+        let name = member.name.clone();
+        for line in &lines {
+            self.print(&format!("[{}] {}", name, line));
+        }
+        return Ok(read > 0 || !lines.is_empty());
+    }
+
+    /// Method to edit the typed line. Returns false
+    /// once the session should end
+    fn handle_key(&mut self, key: KeyEvent) -> Result<bool, Box<dyn std::error::Error>> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('d') if control && self.input.is_empty() => return Ok(false),
+            KeyCode::Char('c') if control => {
+                self.input.clear();
+                if self.pending.take().is_some() {
+                    self.print("The line was not sent");
+                }
+            },
+            KeyCode::Char(c) if !control => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            },
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                self.print(&format!("{}{}", self.prompt(), line));
+                if let Some(pending) = self.pending.take() {
+                    self.confirm(pending, &line)?;
+                } else if let Some(command) = line.trim().strip_prefix(':') {
+                    return self.handle_command(command);
+                } else {
+                    self.check(line)?;
+                }
+            },
+            _ => {}
+        }
+        self.redraw_prompt()?;
+        return Ok(true);
+    }
+
+    /// Method to run a command of the session itself. Returns
+    /// false once the session should end
+    fn handle_command(&mut self, command: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let (name, argument) = match command.split_once(' ') {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, "")
+        };
+        match name {
+            "quit" | "q" => return Ok(false),
+            "exclude" | "include" => {
+                let active = name == "include";
+                match self.members.iter_mut().find(|m| m.name == argument) {
+                    Some(member) => {
+                        member.active = active;
+                        let message = format!("{} {}", if active { "Included" } else { "Excluded" }, argument);
+                        self.print(&message);
+                    },
+                    None => self.print(&format!("Unknown server `{}`, the servers are {}", argument, self.names(|_| true)))
+                }
+            },
+            "servers" => {
+                let message = format!(
+                    "Active: {}. Excluded: {}",
+                    self.names(|m| m.active),
+                    self.names(|m| !m.active)
+                );
+                self.print(&message);
+            },
+            _ => self.print(":exclude NAME, :include NAME, :servers, :quit (or Ctrl-D)")
+        }
+        self.redraw_prompt()?;
+        return Ok(true);
+    }
+
+    /// Method to check a typed line against the guardrails. Lines
+    /// matching rules which were not confirmed yet during the
+    /// session wait for their confirmation, the others are sent
+    fn check(&mut self, line: String) -> Result<(), Box<dyn std::error::Error>> {
+        let plan: Vec<Assignment> = self.members.iter()
+            .filter(|m| m.active && !m.exited)
+            .map(|m| Assignment {
+                target: m.server.target().clone(),
+                jobs: vec![Job::new(String::from("broadcast"), line.clone())]
+            })
+            .collect();
+        let violations = match self.guardrails.check(&plan, &self.protected_groups) {
+            Ok(violations) => violations,
+            Err(err) => {
+                self.print(&format!("Not sent, the guardrails could not be checked: {}", err));
+                return Ok(());
+            }
+        };
+
+        let messages: Vec<String> = violations.iter().map(|v| v.message.clone()).collect();
+        let remaining: Vec<Violation> = violations.into_iter()
+            .filter(|v| !self.confirmed.contains(&v.message))
+            .collect();
+        return self.confirm(Pending { line, violations: messages, remaining }, "");
+    }
+
+    /// Method to handle the typed confirmation of the first
+    /// violation left, asking for the next one or sending
+    /// the line once they are all confirmed
+    fn confirm(&mut self, mut pending: Pending, typed: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(violation) = pending.remaining.first() {
+            if !typed.is_empty() {
+                if typed.trim() != violation.confirmation {
+                    self.print("Confirmation does not match, the line was not sent");
+                    return Ok(());
+                }
+                self.confirmed.push(pending.remaining.remove(0).message);
+            }
+        }
+        if let Some(violation) = pending.remaining.first() {
+            let message = format!("{}. Type `{}` to send the line", violation.message, violation.confirmation);
+            self.pending = Some(pending);
+            self.print(&message);
+            return Ok(());
+        }
+
+        let targets: Vec<Target> = self.members.iter()
+            .filter(|m| m.active && !m.exited)
+            .map(|m| m.server.target().clone())
+            .collect();
+        let record = AuditRecord::from_session(&self.user, &self.id, &targets, Access::BROADCAST, &pending.line, pending.violations);
+        if let Err(err) = AuditLog::open_default().append(record) {
+            self.print(&format!("Not sent, the audit log could not be written: {}", err));
+            return Ok(());
+        }
+        return self.send(format!("{}\n", pending.line).as_bytes());
+    }
+
+    /// Method to send data to the shells of the active members
+    fn send(&mut self, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        for member in self.members.iter_mut().filter(|m| m.active && !m.exited) {
+            shell::write_all(&mut member.channel, data)?;
+        }
+        return Ok(());
+    }
+
+    /// Returns the names of the members matching the filter
+    fn names<F>(&self, filter: F) -> String
+    where F : Fn(&Member) -> bool {
+        let names: Vec<&str> = self.members.iter().filter(|m| filter(m)).map(|m| m.name.as_str()).collect();
+        if names.is_empty() {
+            return String::from("none");
+        }
+        return names.join(", ");
+    }
+
+    /// Returns the prompt of the line being typed
+    fn prompt(&self) -> &'static str {
+        return if self.pending.is_some() { CONFIRM_PROMPT } else { PROMPT };
+    }
+
+    /// Method to print a line above the prompt
+    fn print(&self, line: &str) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\r\x1B[K{}\r\n{}{}", line, self.prompt(), self.input);
+        let _ = stdout.flush();
+    }
+
+    fn redraw_prompt(&self) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        write!(stdout, "\r\x1B[K{}{}", self.prompt(), self.input)?;
+        return stdout.flush();
+    }
+}
@@ -0,0 +1,316 @@
+use std::fs;
+use std::io::Read;
+use chrono::Utc;
+use ssh2::Session;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use similar::TextDiff;
+
+use crate::escalation::Escalation;
+use crate::job::shell_quote;
+use crate::template;
+use crate::transfer::{parse_mode, remote_exec, remote_mktemp, scp_write, TEMP_TEMPLATE};
+
+/// Permissions of a new file when no mode is set
+const DEFAULT_MODE: i32 = 0o644;
+
+/// A local template rendered for every server and written to
+/// a remote file, only when its content changed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TemplateFile {
+    /// Path to the local template
+    pub src: String,
+    /// Path of the file on the server
+    pub dest: String,
+    /// Octal permissions of the file. Replaced files keep
+    /// theirs by default, new files are `0644`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// Owner of the file, as `user` or `user:group`. Replaced
+    /// files keep theirs by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Keep a copy of the replaced file as `dest.<timestamp>.bak`
+    #[serde(default)]
+    pub backup: bool,
+    /// Command run on the servers where the file changed,
+    /// e.g. a service reload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notify: Option<String>,
+    /// The rendered template, read when the job is rendered
+    #[serde(skip)]
+    content: Option<String>
+}
+
+/// Outcome of deploying a template on a server
+pub struct DeployOutput {
+    pub output: String,
+    /// Exit status of the notify command, 0 if it did not run
+    pub exit_status: i32,
+    pub changed: bool
+}
+
+impl TemplateFile {
+    /// Method to check the template before it is rendered
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.src.is_empty() || self.dest.is_empty() {
+            return Err("a template must have a src and a dest".into());
+        }
+        parse_mode(self.mode.as_deref())?;
+        return Ok(());
+    }
+
+    /// Method to create a copy with its paths and the content
+    /// of the template rendered using the given variables. The
+    /// template is read on the first render
+    pub fn render(&self, context: &Value, deferred: Option<&str>) -> Result<TemplateFile, Box<dyn std::error::Error>> {
+        let render = |value: &str| template::render_partial(value, context, deferred);
+        let src = render(&self.src)?;
+        let content = match &self.content {
+            Some(content) => content.clone(),
+            None => fs::read_to_string(&src)
+                .map_err(|err| format!("Could not read template {}: {}", src, err))?
+        };
+        let content = render(&content).map_err(|err| format!("Template {}: {}", src, err))?;
+
+        let render_opt = |value: &Option<String>| -> Result<Option<String>, Box<dyn std::error::Error>> {
+            return match value {
+                Some(v) => Ok(Some(render(v)?)),
+                None => Ok(None)
+            };
+        };
+
+        return Ok(TemplateFile {
+            src,
+            dest: render(&self.dest)?,
+            mode: self.mode.clone(),
+            owner: render_opt(&self.owner)?,
+            backup: self.backup,
+            notify: match &self.notify {
+                Some(notify) => Some(template::render_command(notify, context, deferred)?),
+                None => None
+            },
+            content: Some(content)
+        });
+    }
+
+    /// Returns the templates used by the fields
+    pub fn templates(&self) -> Vec<&str> {
+        let mut templates: Vec<&str> = vec![&self.src, &self.dest];
+        templates.extend(self.owner.as_deref());
+        templates.extend(self.notify.as_deref());
+        return templates;
+    }
+
+    /// Returns the variables used by the content of the
+    /// template. The template is only read when its path
+    /// does not depend on variables
+    pub fn content_variables(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        if !template::variables(&self.src)?.is_empty() {
+            return Ok(Vec::new());
+        }
+        let content = match &self.content {
+            Some(content) => content.clone(),
+            None => fs::read_to_string(&self.src)
+                .map_err(|err| format!("Could not read template {}: {}", self.src, err))?
+        };
+        return template::variables(&content);
+    }
+
+    /// Returns a readable description of the deployment
+    pub fn describe(&self) -> String {
+        let mut line = format!("template {} -> {}", self.src, self.dest);
+        if let Some(mode) = &self.mode {
+            line.push_str(&format!(" mode={}", mode));
+        }
+        if let Some(owner) = &self.owner {
+            line.push_str(&format!(" owner={}", owner));
+        }
+        if self.backup {
+            line.push_str(" backup");
+        }
+        if let Some(notify) = &self.notify {
+            line.push_str(&format!(", then if changed: {}", notify));
+        }
+        return line;
+    }
+
+    /// Method to run commands on the server, as the
+    /// user of the escalation when one is given
+    fn exec(session: &Session, escalation: Option<&Escalation>, command: &str) -> Result<String, Box<dyn std::error::Error>> {
+        return match escalation {
+            Some(escalation) => escalation.exec(session, command),
+            None => remote_exec(session, command)
+        };
+    }
+
+    /// Method to compare the rendered template with the remote
+    /// file. Returns the checksum of the remote file, `None` if it
+    /// does not exist, and its content if it differs
+    fn compare(&self, session: &Session, escalation: Option<&Escalation>, content: &str) -> Result<(Option<String>, Option<String>), Box<dyn std::error::Error>> {
+        let dest = shell_quote(&self.dest);
+        let hash = hex::encode(Sha256::digest(content.as_bytes()));
+        let remote_hash = TemplateFile::exec(session, escalation, &format!("if [ -e {0} ]; then sha256sum -- {0}; fi", dest))?;
+        // Messages of sudo or su may come before the checksum
+        let remote_hash = remote_hash.lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .find(|word| word.len() == 64 && word.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(String::from);
+        let previous = match &remote_hash {
+            Some(remote_hash) if remote_hash == &hash => None,
+            Some(_) => Some(TemplateFile::exec(session, escalation, &format!("cat -- {}", dest))?),
+            None => Some(String::new())
+        };
+        return Ok((remote_hash, previous));
+    }
+
+    /// Returns the unified diff from the remote content
+    /// to the rendered template
+    fn diff(&self, previous: &str, content: &str) -> String {
+        return TextDiff::from_lines(previous, content)
+            .unified_diff()
+            .context_radius(3)
+            .header(&format!("{} (remote)", self.dest), &format!("{} (rendered)", self.src))
+            .to_string();
+    }
+
+    /// Method to compute the change the template would make
+    /// to the remote file without writing it, as the unified
+    /// diff or a line saying the file is unchanged
+    ///
+    /// # Examples
+    /// ```no_run
+    /// print!("{}", template.preview(&session, None)?);
+    /// ```
+    pub fn preview(&self, session: &Session, escalation: Option<&Escalation>) -> Result<String, Box<dyn std::error::Error>> {
+        let content = match &self.content {
+            Some(content) => content,
+            None => return Err(format!("Template {} was not rendered", self.src).into())
+        };
+        // Outputs of other jobs are only known once they ran
+        if !template::variables(&self.dest)?.is_empty() || !template::variables(content)?.is_empty() {
+            return Ok(format!("{} depends on the outputs of other jobs, its diff is shown when it runs\n", self.dest));
+        }
+        return match self.compare(session, escalation, content)? {
+            (_, Some(previous)) => Ok(self.diff(&previous, content)),
+            (_, None) => Ok(format!("unchanged {}\n", self.dest))
+        };
+    }
+
+    /// Method to write the rendered template to the server if
+    /// its checksum differs from the remote file. The output
+    /// holds the unified diff of the change. A replaced file
+    /// keeps its permissions and owner unless they are set.
+    /// With an escalation the remote file is read and replaced
+    /// as its user
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let deployed = template.execute(&session, None)?;
+    /// if deployed.changed {
+    ///     println!("{}", deployed.output);
+    /// }
+    /// ```
+    pub fn execute(&self, session: &Session, escalation: Option<&Escalation>) -> Result<DeployOutput, Box<dyn std::error::Error>> {
+        let content = match &self.content {
+            Some(content) => content,
+            None => return Err(format!("Template {} was not rendered", self.src).into())
+        };
+        let dest = shell_quote(&self.dest);
+        let exec = |command: &str| TemplateFile::exec(session, escalation, command);
+
+        // Compare the checksums, a missing file has no checksum
+        let (remote_hash, previous) = self.compare(session, escalation, content)?;
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return Ok(DeployOutput {
+                output: format!("unchanged {}\n", self.dest),
+                exit_status: 0,
+                changed: false
+            })
+        };
+        let mut output = self.diff(&previous, content);
+
+        // Write to a new temporary file first, so the file is replaced at
+        // once. The login user may not be able to write next to the file,
+        // in which case the upload is staged in /tmp and copied by the
+        // become user. The temporary files are removed on failure
+        let mode = parse_mode(self.mode.as_deref())?;
+        let upload = match escalation {
+            Some(_) => remote_mktemp(|command| remote_exec(session, command), TEMP_TEMPLATE)?,
+            None => String::new()
+        };
+        let remove_upload = || {
+            if escalation.is_some() {
+                let _ = remote_exec(session, &format!("rm -f -- {}", shell_quote(&upload)));
+            }
+        };
+        let temp = match remote_mktemp(exec, &format!("{}.basecamp.XXXXXXXXXX", self.dest)) {
+            Ok(temp) => temp,
+            Err(err) => {
+                remove_upload();
+                return Err(err);
+            }
+        };
+        let upload = if escalation.is_some() { upload.clone() } else { temp.clone() };
+
+        let mut commands = Vec::new();
+        if escalation.is_some() {
+            commands.push(format!("cp -- {} {}", shell_quote(&upload), shell_quote(&temp)));
+        }
+        // The owner is set first, as changing it may clear the setuid bits
+        match (&self.owner, &remote_hash) {
+            (Some(owner), _) => commands.push(format!("chown -- {} {}", shell_quote(owner), shell_quote(&temp))),
+            (None, Some(_)) => commands.push(format!("chown --reference={} -- {}", dest, shell_quote(&temp))),
+            (None, None) => {}
+        }
+        match (mode, &remote_hash) {
+            (Some(mode), _) => commands.push(format!("chmod {:o} -- {}", mode, shell_quote(&temp))),
+            (None, Some(_)) => commands.push(format!("chmod --reference={} -- {}", dest, shell_quote(&temp))),
+            (None, None) => commands.push(format!("chmod {:o} -- {}", DEFAULT_MODE, shell_quote(&temp)))
+        }
+        if self.backup && remote_hash.is_some() {
+            let backup = format!("{}.{}.bak", self.dest, Utc::now().format("%Y%m%d%H%M%S"));
+            commands.push(format!("cp -p -- {} {}", dest, shell_quote(&backup)));
+            output.push_str(&format!("backed up {} to {}\n", self.dest, backup));
+        }
+        commands.push(format!("mv -f -- {} {}", shell_quote(&temp), dest));
+        let replaced = scp_write(session, &upload, content.as_bytes()).and_then(|_| {
+            // Root can read the files of every user
+            match escalation {
+                Some(escalation) if escalation.user != "root" => {
+                    remote_exec(session, &format!("chmod 644 -- {}", shell_quote(&upload)))?;
+                },
+                _ => {}
+            }
+            return exec(&commands.join(" && "));
+        });
+        remove_upload();
+        if let Err(err) = replaced {
+            let _ = exec(&format!("rm -f -- {}", shell_quote(&temp)));
+            return Err(err);
+        }
+        output.push_str(&format!("changed {}\n", self.dest));
+
+        // Run the follow-up command where the file changed
+        let mut exit_status = 0;
+        if let Some(notify) = &self.notify {
+            let (notify_output, notify_status) = match escalation {
+                Some(escalation) => escalation.run(session, notify)?,
+                None => {
+                    let mut channel = session.channel_session()?;
+                    channel.exec(notify)?;
+                    let mut notify_output = String::new();
+                    channel.read_to_string(&mut notify_output)?;
+                    channel.wait_close()?;
+                    (notify_output, channel.exit_status()?)
+                }
+            };
+            exit_status = notify_status;
+            output.push_str(&notify_output);
+        }
+
+        return Ok(DeployOutput { output, exit_status, changed: true });
+    }
+}
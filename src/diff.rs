@@ -0,0 +1,164 @@
+use serde::Serialize;
+use similar::TextDiff;
+
+use crate::history::{JobResult, Run, Status};
+
+/// Changes of a single job on a single server
+/// between two runs
+#[derive(Debug, Serialize)]
+pub struct TargetDiff {
+    pub server: String,
+    pub job: String,
+    /// Status in the first run, `None` if the job did not run there
+    pub before: Option<Status>,
+    /// Status in the second run, `None` if the job did not run there
+    pub after: Option<Status>,
+    pub before_exit_status: Option<i32>,
+    pub after_exit_status: Option<i32>,
+    pub reachability_changed: bool,
+    pub exit_status_changed: bool,
+    pub output_changed: bool,
+    /// Unified diff of the output, empty if the output did not change
+    pub diff: String
+}
+
+impl TargetDiff {
+    /// Returns true if anything changed between the two runs
+    pub fn changed(&self) -> bool {
+        return self.reachability_changed || self.exit_status_changed || self.output_changed;
+    }
+}
+
+/// Differences between two stored runs
+#[derive(Debug, Serialize)]
+pub struct RunDiff {
+    pub from: String,
+    pub to: String,
+    pub targets: Vec<TargetDiff>
+}
+
+/// Returns true if the server could be reached to run the job
+fn reachable(result: Option<&JobResult>) -> bool {
+    return match result {
+        Some(r) => r.status != Status::Unreachable && r.status != Status::TimedOut,
+        None => false
+    };
+}
+
+/// Method to compare the results of two runs. The results are
+/// matched on the server and the job name, so jobs which ran
+/// on a server in only one of the runs are reported as well
+///
+/// # Examples
+/// ```no_run
+/// let history = History::open_default();
+/// let diff = diff::compare(&history.load("a")?, &history.load("b")?);
+/// ```
+pub fn compare(from: &Run, to: &Run) -> RunDiff {
+    // Collect the (server, job) pairs of both runs
+    // keeping the order in which they first appear
+    let mut keys: Vec<(&str, &str)> = Vec::new();
+    for result in from.results.iter().chain(to.results.iter()) {
+        let key = (result.server.as_str(), result.job.as_str());
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    let find = |run: &'_ Run, server: &str, job: &str| -> Option<JobResult> {
+        return run.results.iter().find(|r| r.server == server && r.job == job).cloned();
+    };
+
+    let mut targets = Vec::with_capacity(keys.len());
+    for (server, job) in keys {
+        let before = find(from, server, job);
+        let after = find(to, server, job);
+
+        let before_output = before.as_ref().map(|r| r.output.as_str()).unwrap_or("");
+        let after_output = after.as_ref().map(|r| r.output.as_str()).unwrap_or("");
+        let output_changed = before_output != after_output;
+
+        let diff = if output_changed {
+            TextDiff::from_lines(before_output, after_output)
+                .unified_diff()
+                .context_radius(3)
+                .header(&format!("{}/{}/{}", from.id, server, job), &format!("{}/{}/{}", to.id, server, job))
+                .to_string()
+        } else {
+            String::new()
+        };
+
+        let before_exit_status = before.as_ref().and_then(|r| r.exit_status);
+        let after_exit_status = after.as_ref().and_then(|r| r.exit_status);
+
+        targets.push(TargetDiff {
+            server: server.to_string(),
+            job: job.to_string(),
+            before: before.as_ref().map(|r| r.status),
+            after: after.as_ref().map(|r| r.status),
+            before_exit_status,
+            after_exit_status,
+            reachability_changed: reachable(before.as_ref()) != reachable(after.as_ref()),
+            exit_status_changed: before_exit_status != after_exit_status,
+            output_changed,
+            diff
+        });
+    }
+
+    return RunDiff {
+        from: from.id.clone(),
+        to: to.id.clone(),
+        targets
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Returns a run with results given as (server, job, status, exit status, output)
+    fn run(id: &str, results: &[(&str, &str, &str, Option<i32>, &str)]) -> Run {
+        let results: Vec<_> = results.iter()
+            .map(|(server, job, status, exit_status, output)| json!({
+                "server": server, "job": job, "status": status, "exit_status": exit_status,
+                "output": output, "error": null, "duration_ms": 0
+            }))
+            .collect();
+        return serde_json::from_value(json!({
+            "id": id, "user": "alice", "started_at": "2026-01-01T00:00:00Z", "duration_ms": 0,
+            "targets": [], "jobs": [], "results": results
+        })).unwrap();
+    }
+
+    #[test]
+    fn identical_results_did_not_change() {
+        let results = [("web-1", "uptime", "success", Some(0), "up 3 days\n")];
+        let diff = compare(&run("a", &results), &run("b", &results));
+        assert_eq!(diff.targets.len(), 1);
+        assert!(!diff.targets[0].changed());
+        assert!(diff.targets[0].diff.is_empty());
+    }
+
+    #[test]
+    fn output_and_exit_status_changes_are_reported() {
+        let from = run("a", &[("web-1", "disk", "success", Some(0), "/ 40%\n/var 10%\n")]);
+        let to = run("b", &[("web-1", "disk", "failed", Some(1), "/ 95%\n/var 10%\n")]);
+        let target = &compare(&from, &to).targets[0];
+        assert!(target.exit_status_changed && target.output_changed);
+        assert!(!target.reachability_changed);
+        assert!(target.diff.contains("--- a/web-1/disk"));
+        assert!(target.diff.contains("-/ 40%\n+/ 95%\n"));
+    }
+
+    #[test]
+    fn servers_in_one_run_only_are_reported() {
+        let from = run("a", &[("web-1", "uptime", "success", Some(0), "up\n")]);
+        let to = run("b", &[("web-1", "uptime", "unreachable", None, ""), ("web-2", "uptime", "success", Some(0), "up\n")]);
+        let diff = compare(&from, &to);
+        assert!(diff.targets[0].reachability_changed);
+        assert_eq!(diff.targets[1].server, "web-2");
+        assert_eq!(diff.targets[1].before, None);
+        assert!(diff.targets[1].reachability_changed);
+    }
+}
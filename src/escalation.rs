@@ -0,0 +1,341 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use ssh2::Session;
+use serde::{Deserialize, Serialize};
+
+use crate::job::shell_quote;
+
+/// Prompt given to sudo, so that it can be told
+/// apart from the output of the command
+const SUDO_PROMPT: &str = "[basecamp] become password:";
+
+/// Line printed once the user is authenticated, before the
+/// command runs. Prompts are only answered before it
+const AUTHENTICATED: &str = "[basecamp] authenticated";
+
+/// Number of bytes at the end of the output searched for
+/// the prompt, longer than the prompts of sudo and su
+const PROMPT_TAIL: usize = 256;
+
+/// Environment variable holding the become password
+pub const PASSWORD_ENV: &str = "BASECAMP_BECOME_PASSWORD";
+
+/// Text replacing the password if it shows up in the output
+const REDACTED: &str = "********";
+
+/// Command used to run as another user
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    Sudo,
+    Su
+}
+
+impl Default for Method {
+    fn default() -> Self {
+        return Method::Sudo;
+    }
+}
+
+/// A password which is never printed. The memory
+/// is overwritten when it is dropped
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        return Secret(value);
+    }
+
+    pub fn expose(&self) -> &str {
+        return &self.0;
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "Secret({})", REDACTED);
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        let mut bytes = std::mem::take(&mut self.0).into_bytes();
+        for byte in bytes.iter_mut() {
+            // Volatile so that the write is not optimized away
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// Settings of the privilege escalation, the `become`
+/// section of `basecamp.yaml`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EscalationSettings {
+    /// File holding the become password. It must only be
+    /// readable by its owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<String>
+}
+
+impl EscalationSettings {
+    /// Method to get the become password, from `BASECAMP_BECOME_PASSWORD`,
+    /// then the password file, then by prompting for it without echo.
+    /// Returns `None` when there is no terminal to prompt on, for
+    /// servers which do not ask for a password
+    pub fn password(&self) -> Result<Option<Secret>, Box<dyn std::error::Error>> {
+        if let Some(password) = self.stored_password()? {
+            return Ok(Some(password));
+        }
+
+        return match rpassword::prompt_password("Become password (empty if not needed): ") {
+            Ok(password) if password.is_empty() => Ok(None),
+            Ok(password) => Ok(Some(Secret::new(password))),
+            Err(_) => Ok(None)
+        };
+    }
+
+    /// Method to get the become password without prompting for it,
+    /// from `BASECAMP_BECOME_PASSWORD` then the password file. Returns
+    /// `None` when neither is set
+    pub fn stored_password(&self) -> Result<Option<Secret>, Box<dyn std::error::Error>> {
+        if let Ok(password) = std::env::var(PASSWORD_ENV) {
+            return Ok(Some(Secret::new(password)));
+        }
+
+        if let Some(path) = &self.password_file {
+            check_private(Path::new(path))?;
+            let contents = fs::read_to_string(path)
+                .map_err(|err| format!("Could not read the become password file {}: {}", path, err))?;
+            return Ok(Some(Secret::new(contents.trim_end_matches(&['\r', '\n'][..]).to_string())));
+        }
+        return Ok(None);
+    }
+}
+
+/// Method to check that a file holding a secret can
+/// not be read by other users
+#[cfg(unix)]
+pub fn check_private(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(format!("{} must only be accessible by its owner (chmod 600), found {:o}", path.display(), mode & 0o777).into());
+    }
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+pub fn check_private(_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    return Ok(());
+}
+
+/// What the last read added to the output of a command
+/// run as another user
+#[derive(Debug, PartialEq)]
+enum Scan {
+    Output,
+    /// The output ends with the password prompt
+    Prompt,
+    /// The user is authenticated, the rest of the output
+    /// comes from the command
+    Authenticated
+}
+
+/// Runs commands as another user on the server
+#[derive(Debug)]
+pub struct Escalation<'a> {
+    pub method: Method,
+    pub user: String,
+    pub password: Option<&'a Secret>
+}
+
+impl<'a> Escalation<'a> {
+    /// Returns the command line running the command as the user,
+    /// printing the authenticated line before the command
+    pub fn wrap(&self, command: &str) -> String {
+        let command = format!("echo {}; {}", shell_quote(AUTHENTICATED), command);
+        return match self.method {
+            Method::Sudo => format!(
+                "sudo -p {} -u {} -- sh -c {}",
+                shell_quote(SUDO_PROMPT),
+                shell_quote(&self.user),
+                shell_quote(&command)
+            ),
+            Method::Su => format!("su {} -c {}", shell_quote(&self.user), shell_quote(&command))
+        };
+    }
+
+    /// Returns a readable description of the command run as the user
+    pub fn describe(method: Method, user: &str, command: &str) -> String {
+        return match method {
+            Method::Sudo => format!("sudo -u {} -- {}", user, command),
+            Method::Su => format!("su {} -c {}", user, command)
+        };
+    }
+
+    /// Returns true if the output ends with the password prompt
+    fn is_prompt(&self, output: &[u8]) -> bool {
+        let text = String::from_utf8_lossy(output);
+        let text = text.trim_end();
+        return match self.method {
+            Method::Sudo => text.ends_with(SUDO_PROMPT),
+            Method::Su => text.to_lowercase().ends_with("password:")
+        };
+    }
+
+    /// Method to look at the end of the output, after `read` more
+    /// bytes were read, for the authenticated line or the prompt.
+    /// Both are removed from the output, with everything printed
+    /// before the authenticated line
+    fn scan(&self, output: &mut Vec<u8>, read: usize) -> Scan {
+        // The line may have been split between the last two reads
+        let from = output.len().saturating_sub(read + AUTHENTICATED.len());
+        let found = output[from..].windows(AUTHENTICATED.len()).position(|w| w == AUTHENTICATED.as_bytes());
+        if let Some(at) = found {
+            output.drain(..from + at + AUTHENTICATED.len());
+            return Scan::Authenticated;
+        }
+
+        let tail = output.len().saturating_sub(PROMPT_TAIL);
+        if !self.is_prompt(&output[tail..]) {
+            return Scan::Output;
+        }
+        let line_start = output[tail..].iter().rposition(|b| *b == b'\n').map(|i| tail + i + 1).unwrap_or(tail);
+        output.truncate(line_start);
+        return Scan::Prompt;
+    }
+
+    /// Method to run the command as the user over a PTY, answering
+    /// the first password prompt printed before the authenticated
+    /// line, so that the output of the command is never answered.
+    /// The password is never part of the output
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let (output, exit_status) = escalation.run(&session, "systemctl restart nginx")?;
+    /// ```
+    pub fn run(&self, session: &Session, command: &str) -> Result<(String, i32), Box<dyn std::error::Error>> {
+        let mut channel = session.channel_session()?;
+        channel.request_pty("xterm", None, None)?;
+        channel.exec(&self.wrap(command))?;
+
+        let mut output: Vec<u8> = Vec::new();
+        let mut buffer = [0; 4096];
+        let mut prompted = false;
+        let mut authenticated = false;
+        loop {
+            let read = channel.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            output.extend_from_slice(&buffer[..read]);
+
+            if authenticated {
+                continue;
+            }
+            match self.scan(&mut output, read) {
+                Scan::Output => continue,
+                Scan::Authenticated => {
+                    authenticated = true;
+                    continue;
+                },
+                Scan::Prompt => {}
+            }
+
+            let password = match (self.password, prompted) {
+                (Some(password), false) => password,
+                (None, _) => {
+                    let _ = channel.close();
+                    return Err(format!("{} asked for a password and none was given", self.method_name()).into());
+                },
+                (Some(_), true) => {
+                    let _ = channel.close();
+                    return Err(format!("{} rejected the become password", self.method_name()).into());
+                }
+            };
+            channel.write_all(password.expose().as_bytes())?;
+            channel.write_all(b"\n")?;
+            channel.flush()?;
+            prompted = true;
+        }
+        channel.wait_close()?;
+
+        // The PTY translates the new lines
+        let mut output = String::from_utf8_lossy(&output).replace("\r\n", "\n");
+        if authenticated && output.starts_with('\n') {
+            output.remove(0);
+        } else if prompted && !authenticated {
+            // Drop the new line ending the password prompt
+            output = output.trim_start_matches('\n').to_string();
+        }
+        if let Some(password) = self.password {
+            if !password.expose().is_empty() {
+                output = output.replace(password.expose(), REDACTED);
+            }
+        }
+        return Ok((output, channel.exit_status()?));
+    }
+
+    /// Method to run the command as the user, failing
+    /// when it exits with a nonzero status
+    pub fn exec(&self, session: &Session, command: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let (output, exit_status) = self.run(session, command)?;
+        if exit_status != 0 {
+            return Err(format!("`{}` exited with status {}: {}", command, exit_status, output.trim_end()).into());
+        }
+        return Ok(output);
+    }
+
+    fn method_name(&self) -> &'static str {
+        return match self.method {
+            Method::Sudo => "sudo",
+            Method::Su => "su"
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escalation(method: Method) -> Escalation<'static> {
+        return Escalation { method, user: String::from("root"), password: None };
+    }
+
+    /// Feeds the chunks read from the server one after the
+    /// other, returning what every read was and the output left
+    fn scan(escalation: &Escalation, chunks: &[&str]) -> (Vec<Scan>, String) {
+        let mut output = Vec::new();
+        let mut scans = Vec::new();
+        for chunk in chunks {
+            output.extend_from_slice(chunk.as_bytes());
+            scans.push(escalation.scan(&mut output, chunk.len()));
+        }
+        return (scans, String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn prompts_are_removed_from_the_output() {
+        let (scans, output) = scan(&escalation(Method::Sudo), &["[basecamp] become password: "]);
+        assert_eq!(scans, vec![Scan::Prompt]);
+        assert_eq!(output, "");
+
+        let (scans, output) = scan(&escalation(Method::Su), &["Last login: today\r\n", "Password: "]);
+        assert_eq!(scans, vec![Scan::Output, Scan::Prompt]);
+        assert_eq!(output, "Last login: today\r\n");
+    }
+
+    #[test]
+    fn the_output_of_the_command_follows_the_authenticated_line() {
+        let (scans, output) = scan(&escalation(Method::Su), &["\r\n[basecamp] auth", "enticated\r\nEnter the password:"]);
+        assert_eq!(scans, vec![Scan::Output, Scan::Authenticated]);
+        assert_eq!(output, "\r\nEnter the password:");
+    }
+
+    #[test]
+    fn commands_print_the_authenticated_line_first() {
+        let wrapped = escalation(Method::Su).wrap("id -u");
+        assert_eq!(wrapped, "su 'root' -c 'echo '\\''[basecamp] authenticated'\\''; id -u'");
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt;
+use std::sync::{mpsc, Arc, Mutex};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::history::{JobResult, StepResult};
+
+/// An event of the lifecycle of a run, serialized as a JSON
+/// object with the `type` of the event and its fields
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    pub at: DateTime<Utc>,
+    /// Id of the run the event belongs to, unset for
+    /// the messages of the UI
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind
+}
+
+/// What happened
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    RunStarted {
+        targets: Vec<String>,
+        jobs: Vec<String>
+    },
+    /// The first job of the target is about to connect to it
+    TargetConnecting {
+        server: String
+    },
+    /// A line printed by a job, without its new line
+    Output {
+        server: String,
+        job: String,
+        line: String
+    },
+    /// A step of a job finished, published before the job
+    StepFinished {
+        server: String,
+        job: String,
+        step: Box<StepResult>
+    },
+    JobFinished {
+        result: Box<JobResult>
+    },
+    /// Every job of the target has a result
+    TargetFinished {
+        server: String,
+        success: bool,
+        /// Jobs which did not succeed on the target
+        failed_jobs: Vec<String>
+    },
+    RunFinished {
+        success: bool,
+        duration_ms: u64
+    },
+    /// A line printed by the UI
+    Message {
+        text: String
+    }
+}
+
+impl Event {
+    pub fn new(run: Option<&str>, kind: EventKind) -> Self {
+        return Event { at: Utc::now(), run: run.map(String::from), kind };
+    }
+
+    /// Returns the name of the event, its `type` in JSON
+    pub fn name(&self) -> &'static str {
+        return match self.kind {
+            EventKind::RunStarted { .. } => "run_started",
+            EventKind::TargetConnecting { .. } => "target_connecting",
+            EventKind::Output { .. } => "output",
+            EventKind::StepFinished { .. } => "step_finished",
+            EventKind::JobFinished { .. } => "job_finished",
+            EventKind::TargetFinished { .. } => "target_finished",
+            EventKind::RunFinished { .. } => "run_finished",
+            EventKind::Message { .. } => "message"
+        };
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match &self.kind {
+            EventKind::RunStarted { targets, jobs } => {
+                write!(f, "Executing {} jobs on {} servers", jobs.len(), targets.len())
+            },
+            EventKind::TargetConnecting { server } => write!(f, "[{}] connecting", server),
+            EventKind::Output { server, job, line } => write!(f, "[{}] {}: {}", server, job, line),
+            EventKind::StepFinished { server, job, step } => match &step.error {
+                Some(err) => write!(f, "[{}] {} ({}) failed: {}", server, job, step.name, err),
+                None => write!(f, "[{}] {} ({}) exited with {}", server, job, step.name, step.exit_status)
+            },
+            EventKind::JobFinished { result } => match &result.error {
+                Some(err) => write!(f, "[{}] {} {}: {}", result.server, result.job, result.status, err),
+                None => write!(f, "[{}] {} {}", result.server, result.job, result.status)
+            },
+            EventKind::TargetFinished { server, success: true, .. } => write!(f, "[{}] finished", server),
+            EventKind::TargetFinished { server, failed_jobs, .. } => {
+                write!(f, "[{}] finished, {} did not succeed", server, failed_jobs.join(", "))
+            },
+            EventKind::RunFinished { success, duration_ms } => {
+                let outcome = if *success { "succeeded" } else { "failed" };
+                write!(f, "Run {} in {}s", outcome, duration_ms / 1000)
+            },
+            EventKind::Message { text } => write!(f, "{}", text)
+        };
+    }
+}
+
+/// Sends the published events to every subscriber. Clones
+/// publish to the same subscribers
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<mpsc::Sender<Event>>>>
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        return EventBus::default();
+    }
+
+    /// Method to receive the events published from now on
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let events = bus.subscribe();
+    /// for event in events {
+    ///     println!("{}", serde_json::to_string(&event)?);
+    /// }
+    /// ```
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        return rx;
+    }
+
+    /// Method to send the event to the subscribers,
+    /// dropping those which stopped receiving
+    pub fn publish(&self, run: Option<&str>, kind: EventKind) {
+        let event = Event::new(run, kind);
+        self.subscribers.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+/// Publishes the events of a single run
+#[derive(Clone)]
+pub struct RunEvents {
+    bus: EventBus,
+    run: String
+}
+
+impl RunEvents {
+    pub fn new(bus: EventBus, run: &str) -> Self {
+        return RunEvents { bus, run: run.to_string() };
+    }
+
+    /// Method to receive the events published from now on,
+    /// those of the other runs included
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        return self.bus.subscribe();
+    }
+
+    /// Returns the id of the run the events belong to
+    pub fn run_id(&self) -> &str {
+        return &self.run;
+    }
+
+    pub fn publish(&self, kind: EventKind) {
+        self.bus.publish(Some(&self.run), kind);
+    }
+
+    /// Method to publish every line of the output of a job
+    pub fn output(&self, server: &str, job: &str, text: &str) {
+        for line in text.lines() {
+            self.publish(EventKind::Output { server: server.to_string(), job: job.to_string(), line: line.to_string() });
+        }
+    }
+
+    /// Method to publish that every job of the target has a result
+    pub fn target_finished(&self, server: &str, results: &[JobResult]) {
+        let failed_jobs: Vec<String> = results.iter()
+            .filter(|r| r.status.is_failure())
+            .map(|r| r.job.clone())
+            .collect();
+        self.publish(EventKind::TargetFinished {
+            server: server.to_string(),
+            success: failed_jobs.is_empty(),
+            failed_jobs
+        });
+    }
+
+    /// Method to publish that the run is over
+    pub fn run_finished(&self, results: &[JobResult], duration_ms: u64) {
+        let success = results.iter().all(|r| !r.status.is_failure());
+        self.publish(EventKind::RunFinished { success, duration_ms });
+    }
+}
@@ -0,0 +1,492 @@
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::BTreeMap;
+use std::time::Instant;
+use futures::executor::block_on;
+use threadpool::ThreadPool;
+use serde::Serialize;
+
+use crate::access::Access;
+use crate::escalation::Secret;
+use crate::events::{EventKind, RunEvents};
+use crate::guardrails::Guardrails;
+use crate::history::{JobResult, Status};
+use crate::inventory::Target;
+use crate::job::Job;
+use crate::pool::SessionPool;
+use crate::server::{self, Server};
+use crate::template;
+use crate::workflow::{self, Condition};
+
+/// The jobs to run on a target, with their
+/// commands rendered for that target
+#[derive(Clone, Debug)]
+pub struct Assignment {
+    pub target: Target,
+    pub jobs: Vec<Job>
+}
+
+/// How a target would be connected to and what would
+/// run there, as shown by a dry run
+#[derive(Debug, Serialize)]
+pub struct PlannedTarget {
+    pub server: String,
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    /// Jump hosts as `user@host:port`, in connection order
+    pub jump_hosts: Vec<String>,
+    pub auth: String,
+    pub jobs: Vec<PlannedJob>
+}
+
+/// A job rendered for a target
+#[derive(Debug, Serialize)]
+pub struct PlannedJob {
+    pub job: String,
+    pub needs: Vec<String>,
+    pub when: Condition,
+    pub commands: Vec<String>,
+    /// Diff of the templates the job would deploy, set
+    /// once the remote files were compared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>
+}
+
+impl Assignment {
+    /// Method to describe the connection and the
+    /// rendered jobs without connecting
+    pub fn describe(&self) -> PlannedTarget {
+        return PlannedTarget {
+            server: self.target.name.clone(),
+            user: self.target.user.clone(),
+            host: self.target.host.clone(),
+            port: self.target.port,
+            jump_hosts: self.target.jump_hosts().iter()
+                .map(|j| format!("{}@{}", j.user, j.address()))
+                .collect(),
+            auth: self.target.auth_method(),
+            jobs: self.jobs.iter()
+                .map(|job| PlannedJob {
+                    job: job.name.clone(),
+                    needs: job.needs.clone(),
+                    when: job.when,
+                    commands: job.commands(),
+                    diff: None
+                })
+                .collect()
+        };
+    }
+
+    /// Method to connect to the target and compute the diffs of
+    /// the templates its jobs would deploy, one per job and `None`
+    /// for the jobs which deploy none. Nothing is written
+    pub fn preview(&self, password: Option<&Secret>) -> Vec<Option<String>> {
+        if !self.jobs.iter().any(|j| j.deploys_templates()) {
+            return vec![None; self.jobs.len()];
+        }
+
+        let mut server = Server::new(self.target.clone());
+        let session = match block_on(server.connect()) {
+            Ok(_) => server.session().unwrap(),
+            Err(err) => {
+                let message = format!("could not connect to compare the templates: {}\n", err);
+                return self.jobs.iter()
+                    .map(|j| if j.deploys_templates() { Some(message.clone()) } else { None })
+                    .collect();
+            }
+        };
+        return self.jobs.iter().map(|job| job.preview(session, password)).collect();
+    }
+}
+
+/// Runs jobs on remote servers using a pool
+/// of worker threads
+pub struct Executor {
+    workers: usize,
+    /// Password of the jobs running as another user
+    become_password: Option<Arc<Secret>>,
+    /// What the user running the jobs is allowed to run
+    access: Option<Access>,
+    /// Set to cancel the jobs which have not started yet
+    cancel: Option<Arc<AtomicBool>>,
+    /// Publishes the progress of the run
+    events: Option<RunEvents>,
+    /// Checked again once the outputs of other jobs are rendered
+    guardrails: Option<Guardrails>
+}
+
+/// Sends the result of a job started on the pool back to the
+/// executor. A failed result is sent if the job panics, so that
+/// the executor does not wait for it forever
+struct Reply {
+    tx: mpsc::Sender<(usize, usize, JobResult)>,
+    t: usize,
+    j: usize,
+    /// Sent when dropped without a result
+    panicked: Option<JobResult>
+}
+
+impl Reply {
+    fn new(tx: mpsc::Sender<(usize, usize, JobResult)>, t: usize, j: usize, target: &Target, job: &Job) -> Self {
+        let mut panicked = Executor::new_result(target, job);
+        panicked.status = Status::Failed;
+        panicked.error = Some(format!("Job {} stopped unexpectedly", job.name));
+        return Reply { tx, t, j, panicked: Some(panicked) };
+    }
+
+    fn send(&mut self, result: JobResult) {
+        self.panicked = None;
+        let _ = self.tx.send((self.t, self.j, result));
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        if let Some(result) = self.panicked.take() {
+            let _ = self.tx.send((self.t, self.j, result));
+        }
+    }
+}
+
+impl Executor {
+    /// Default number of worker threads
+    pub const NUM_WORKERS: usize = 5;
+
+    pub fn new(workers: usize) -> Self {
+        return Executor { workers, become_password: None, access: None, cancel: None, events: None, guardrails: None };
+    }
+
+    pub fn set_become_password(&mut self, password: Secret) {
+        self.become_password = Some(Arc::new(password));
+    }
+
+    pub fn set_access(&mut self, access: Access) {
+        self.access = Some(access);
+    }
+
+    pub fn set_guardrails(&mut self, guardrails: Guardrails) {
+        self.guardrails = Some(guardrails);
+    }
+
+    /// Method to cancel the run once the flag is set. Jobs
+    /// already running finish, the others are not started
+    pub fn set_cancel(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = Some(cancel);
+    }
+
+    /// Method to publish the progress of the run: when it starts,
+    /// targets are connected to, jobs print lines and finish,
+    /// targets finish and the run is over
+    pub fn set_events(&mut self, events: RunEvents) {
+        self.events = Some(events);
+    }
+
+    /// Method to store the result of a job of the target and
+    /// publish it after its steps, with the target once all its
+    /// jobs are done
+    fn finish(&self, target: &Target, results: &mut [Option<JobResult>], j: usize, result: JobResult) {
+        if let Some(events) = &self.events {
+            for step in &result.steps {
+                events.publish(EventKind::StepFinished {
+                    server: result.server.clone(),
+                    job: result.job.clone(),
+                    step: Box::new(step.clone())
+                });
+            }
+            events.publish(EventKind::JobFinished { result: Box::new(result.clone()) });
+        }
+        results[j] = Some(result);
+        if let Some(events) = &self.events {
+            if results.iter().all(Option::is_some) {
+                let finished: Vec<JobResult> = results.iter().flatten().cloned().collect();
+                events.target_finished(&target.name, &finished);
+            }
+        }
+    }
+
+    /// Method to render every job for every target. Fails on
+    /// the first job referencing an undefined variable
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let plan = Executor::plan(&targets, &jobs, &params)?;
+    /// ```
+    pub fn plan(targets: &[Target], jobs: &[Job], params: &BTreeMap<String, String>) -> Result<Vec<Assignment>, Box<dyn std::error::Error>> {
+        let mut plan = Vec::with_capacity(targets.len());
+        for target in targets {
+            let context = template::context(target, params);
+            let mut rendered = Vec::with_capacity(jobs.len());
+            for job in jobs {
+                let job = job.render(&context)
+                    .map_err(|err| format!("Could not render job {} for {}: {}", job.name, target.name, err))?;
+                rendered.push(job);
+            }
+            plan.push(Assignment { target: target.clone(), jobs: rendered });
+        }
+        return Ok(plan);
+    }
+
+    /// Returns the names of the `params` referenced by the jobs
+    /// which have no value yet
+    pub fn missing_params(jobs: &[Job], params: &BTreeMap<String, String>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut missing: Vec<String> = Vec::new();
+        for job in jobs {
+            for variable in job.variables()? {
+                if let Some(name) = variable.strip_prefix("params.") {
+                    if !params.contains_key(name) && !missing.iter().any(|m| m == name) {
+                        missing.push(name.to_string());
+                    }
+                }
+            }
+        }
+        return Ok(missing);
+    }
+
+    /// Method to execute the planned jobs and collect one result
+    /// per (target, job) pair. A job starts on a target once the
+    /// jobs it needs have finished there, so independent jobs and
+    /// targets run in parallel. The jobs on a target share one
+    /// session, kept alive until the run is over
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let results = Executor::new(5).run(&plan);
+    /// ```
+    pub fn run(&self, plan: &[Assignment]) -> Vec<JobResult> {
+        let start = Instant::now();
+        if let Some(events) = &self.events {
+            let mut jobs: Vec<String> = Vec::new();
+            for job in plan.iter().flat_map(|a| a.jobs.iter()) {
+                if !jobs.contains(&job.name) {
+                    jobs.push(job.name.clone());
+                }
+            }
+            events.publish(EventKind::RunStarted {
+                targets: plan.iter().map(|a| a.target.name.clone()).collect(),
+                jobs
+            });
+        }
+
+        // Create a thread pool to run the SSH jobs in parallel
+        let pool = ThreadPool::new(self.workers);
+        let mut sessions = SessionPool::new(SessionPool::KEEPALIVE_INTERVAL);
+        sessions.set_become_password(self.become_password.clone());
+        let sessions = Arc::new(sessions);
+        let (tx, rx) = mpsc::channel::<(usize, usize, JobResult)>();
+
+        // Holds the results of the jobs, indexed by target and job
+        let mut results: Vec<Vec<Option<JobResult>>> = plan.iter().map(|a| vec![None; a.jobs.len()]).collect();
+        let mut started: Vec<Vec<bool>> = plan.iter().map(|a| vec![false; a.jobs.len()]).collect();
+        let mut connecting: Vec<bool> = vec![false; plan.len()];
+        let mut running: usize = 0;
+
+        loop {
+            // Start or skip every job whose needed jobs have finished.
+            // Skipping a job can make the jobs needing it ready
+            let mut progress = true;
+            while progress {
+                progress = false;
+                for (t, assignment) in plan.iter().enumerate() {
+                    for (j, job) in assignment.jobs.iter().enumerate() {
+                        if started[t][j] {
+                            continue;
+                        }
+                        let upstream = match Executor::upstream_statuses(assignment, &results[t], job) {
+                            Some(upstream) => upstream,
+                            None => continue
+                        };
+
+                        started[t][j] = true;
+                        progress = true;
+                        if !job.when.is_met(&upstream) {
+                            let mut result = Executor::new_result(&assignment.target, job);
+                            result.status = Status::Skipped;
+                            self.finish(&assignment.target, &mut results[t], j, result);
+                            continue;
+                        }
+                        if self.cancel.as_ref().map(|c| c.load(Ordering::SeqCst)).unwrap_or(false) {
+                            let mut result = Executor::new_result(&assignment.target, job);
+                            result.status = Status::Cancelled;
+                            result.error = Some(String::from("The run was cancelled"));
+                            self.finish(&assignment.target, &mut results[t], j, result);
+                            continue;
+                        }
+                        // Checked again here so that nothing connects
+                        // to a server the user can not run the job on
+                        if let Some(access) = self.access.as_ref().filter(|a| !a.allows(&assignment.target, &job.name)) {
+                            let mut result = Executor::new_result(&assignment.target, job);
+                            result.status = Status::Denied;
+                            result.error = Some(access.denial(&assignment.target, &job.name));
+                            self.finish(&assignment.target, &mut results[t], j, result);
+                            continue;
+                        }
+
+                        // Outputs of the jobs which already ran on the target
+                        let outputs: BTreeMap<String, BTreeMap<String, String>> = results[t].iter()
+                            .flatten()
+                            .map(|r| (r.job.clone(), r.outputs.clone()))
+                            .collect();
+
+                        if !connecting[t] {
+                            connecting[t] = true;
+                            if let Some(events) = &self.events {
+                                events.publish(EventKind::TargetConnecting { server: assignment.target.name.clone() });
+                            }
+                        }
+
+                        let target = assignment.target.clone();
+                        let job = job.clone();
+                        let mut reply = Reply::new(tx.clone(), t, j, &target, &job);
+                        let sessions = Arc::clone(&sessions);
+                        let events = self.events.clone();
+                        let guardrails = self.guardrails.clone();
+                        pool.execute(move || {
+                            let rendered = job.render_outputs(&outputs);
+                            let refused = match (&rendered, &guardrails) {
+                                (Ok(rendered), Some(guardrails)) => guardrails.check_rendered(&job, rendered).err(),
+                                _ => None
+                            };
+                            let result = match (rendered, refused) {
+                                (Ok(rendered), Some(refusal)) => {
+                                    let mut result = Executor::new_result(&target, &rendered);
+                                    result.status = Status::Denied;
+                                    result.error = Some(refusal.to_string());
+                                    result
+                                },
+                                (Ok(job), None) => Executor::run_job(&sessions, target, &job, events.as_ref()),
+                                (Err(err), _) => {
+                                    let mut result = Executor::new_result(&target, &job);
+                                    result.status = Status::Failed;
+                                    result.error = Some(format!("Could not render job {}: {}", job.name, err));
+                                    result
+                                }
+                            };
+                            reply.send(result);
+                        });
+                        running += 1;
+                    }
+                }
+            }
+
+            if running == 0 {
+                break;
+            }
+
+            // Wait for a job to finish. Every started job replies,
+            // and the executor keeps a sender, so this can not fail
+            let (t, j, result) = rx.recv().expect("the executor keeps a sender");
+            self.finish(&plan[t].target, &mut results[t], j, result);
+            running -= 1;
+        }
+
+        let results: Vec<JobResult> = results.into_iter().flatten().flatten().collect();
+        if let Some(events) = &self.events {
+            events.run_finished(&results, start.elapsed().as_millis() as u64);
+        }
+        return results;
+    }
+
+    /// Returns the statuses of the jobs needed by the job on
+    /// the target, or `None` if some of them have not finished
+    fn upstream_statuses(assignment: &Assignment, results: &[Option<JobResult>], job: &Job) -> Option<Vec<Status>> {
+        let mut statuses = Vec::with_capacity(job.needs.len());
+        for name in &job.needs {
+            // Needed jobs which are not part of the run do not block
+            let index = match assignment.jobs.iter().position(|j| &j.name == name) {
+                Some(index) => index,
+                None => continue
+            };
+            statuses.push(results[index].as_ref()?.status);
+        }
+        return Some(statuses);
+    }
+
+    /// Returns a successful result without output
+    fn new_result(target: &Target, job: &Job) -> JobResult {
+        return JobResult {
+            server: target.name.clone(),
+            job: job.name.clone(),
+            command: job.commands().join("\n"),
+            status: Status::Success,
+            exit_status: None,
+            output: String::new(),
+            error: None,
+            steps: Vec::new(),
+            outputs: BTreeMap::new(),
+            changed: None,
+            duration_ms: 0
+        };
+    }
+
+    /// Method to run a single job on the session of the
+    /// target, connecting to it if no job did before. The
+    /// lines of its output are published as they are read
+    fn run_job(sessions: &SessionPool, target: Target, job: &Job, events: Option<&RunEvents>) -> JobResult {
+        let start = Instant::now();
+        let mut result = Executor::new_result(&target, job);
+
+        // Get the session of the server and execute the SSH job
+        let server = match sessions.get(&target) {
+            Ok(server) => server,
+            Err(err) => {
+                result.status = if err.timed_out { Status::TimedOut } else { Status::Unreachable };
+                result.error = Some(err.message);
+                result.duration_ms = start.elapsed().as_millis() as u64;
+                return result;
+            }
+        };
+
+        let on_line = |line: &str| {
+            if let Some(events) = events {
+                events.output(&target.name, &job.name, line);
+            }
+        };
+        match block_on(server.execute(job, &on_line)) {
+            Ok(output) => {
+                result.status = match &output.error {
+                    Some(err) if server::is_timeout(&**err) => Status::TimedOut,
+                    Some(_) => Status::Failed,
+                    None if output.exit_status != 0 => Status::Failed,
+                    None => Status::Success
+                };
+                result.exit_status = Some(output.exit_status).filter(|e| *e != 0 || output.error.is_none());
+                result.error = output.error.as_ref().map(|err| err.to_string());
+                result.outputs = workflow::capture_outputs(job, &output.output);
+                result.changed = output.changed();
+                result.output = output.output;
+                result.steps = output.steps;
+            },
+            Err(err) => {
+                result.status = if server::is_timeout(&*err) { Status::TimedOut } else { Status::Failed };
+                result.error = Some(err.to_string());
+            }
+        }
+        result.duration_ms = start.elapsed().as_millis() as u64;
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn jobs_which_panic_still_reply() {
+        let (tx, rx) = mpsc::channel();
+        let target: Target = serde_yaml::from_str("{ name: web-1, host: 10.0.0.1, user: deploy }").unwrap();
+        let job = Job::new(String::from("deploy"), String::from("true"));
+
+        let reply = Reply::new(tx, 1, 2, &target, &job);
+        let worker = thread::spawn(move || {
+            let _reply = reply;
+            panic!("the job failed");
+        });
+        assert!(worker.join().is_err());
+
+        let (t, j, result) = rx.recv().unwrap();
+        assert_eq!((t, j, result.status), (1, 2, Status::Failed));
+        assert!(rx.try_recv().is_err());
+    }
+}
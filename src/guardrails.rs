@@ -0,0 +1,196 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::executor::Assignment;
+use crate::job::Job;
+
+/// Rules making risky runs ask for a typed confirmation.
+/// Runs without a terminal are refused unless overridden
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Guardrails {
+    /// Regexes matched against every rendered command
+    #[serde(default)]
+    pub denylist: Vec<String>,
+    /// Number of servers above which a run must be confirmed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_targets: Option<usize>
+}
+
+/// A rule matched by a run
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub message: String,
+    /// Text the user has to type to confirm the run
+    pub confirmation: String
+}
+
+/// The guardrails which were confirmed or overridden
+/// for a run, recorded in its history and audit entry
+#[derive(Clone, Debug, Default)]
+pub struct Acknowledgement {
+    pub violations: Vec<String>,
+    /// Reason given to override the guardrails
+    pub override_reason: Option<String>
+}
+
+impl Guardrails {
+    /// Method to check that every regex of the denylist compiles
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for pattern in &self.denylist {
+            Regex::new(pattern).map_err(|err| format!("Invalid denylist regex `{}`: {}", pattern, err))?;
+        }
+        return Ok(());
+    }
+
+    /// Method to find the rules matched by a plan. Targets in
+    /// `protected_groups` must be confirmed by typing the group
+    /// name, denylisted commands by typing the job name and too
+    /// many targets by typing their number
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let violations = settings.guardrails.check(&plan, &inventory.protected_groups())?;
+    /// ```
+    pub fn check(&self, plan: &[Assignment], protected_groups: &[String]) -> Result<Vec<Violation>, Box<dyn std::error::Error>> {
+        let mut violations: Vec<Violation> = Vec::new();
+
+        for group in protected_groups {
+            let servers: Vec<&str> = plan.iter()
+                .filter(|a| a.target.groups.contains(group))
+                .map(|a| a.target.name.as_str())
+                .collect();
+            if !servers.is_empty() {
+                violations.push(Violation {
+                    message: format!("{} of the protected group {} are targeted", servers.join(", "), group),
+                    confirmation: group.clone()
+                });
+            }
+        }
+
+        // Jobs are confirmed once, whatever the rules and servers they match
+        let mut denied_jobs: Vec<&str> = Vec::new();
+        for pattern in &self.denylist {
+            let regex = Regex::new(pattern)?;
+            for assignment in plan {
+                for job in &assignment.jobs {
+                    let matched = job.commands().into_iter().find(|c| regex.is_match(c));
+                    let already_found = denied_jobs.contains(&job.name.as_str());
+                    if let (Some(command), false) = (matched, already_found) {
+                        denied_jobs.push(&job.name);
+                        violations.push(Violation {
+                            message: format!("Job {} runs `{}`, which matches the denylist rule `{}`", job.name, command, pattern),
+                            confirmation: job.name.clone()
+                        });
+                    }
+                }
+            }
+        }
+
+        if let Some(max_targets) = self.max_targets {
+            if plan.len() > max_targets {
+                violations.push(Violation {
+                    message: format!("{} servers are targeted, more than the maximum of {}", plan.len(), max_targets),
+                    confirmation: plan.len().to_string()
+                });
+            }
+        }
+        return Ok(violations);
+    }
+
+    /// Method to check a job once its commands are rendered with
+    /// the outputs of the jobs which ran before it, as they were
+    /// not known when the plan was checked. Commands which only
+    /// match the denylist once rendered are refused
+    pub fn check_rendered(&self, planned: &Job, rendered: &Job) -> Result<(), Box<dyn std::error::Error>> {
+        for (before, after) in planned.commands().iter().zip(rendered.commands()) {
+            if before == &after {
+                continue;
+            }
+            for pattern in &self.denylist {
+                let regex = Regex::new(pattern)?;
+                if regex.is_match(&after) && !regex.is_match(before) {
+                    return Err(format!(
+                        "Job {} runs `{}` once rendered with the outputs of other jobs, which matches the denylist rule `{}`",
+                        rendered.name, after, pattern
+                    ).into());
+                }
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::Target;
+    use std::collections::BTreeMap;
+
+    fn assignment(server: &str, groups: &[&str], command: &str) -> Assignment {
+        let yaml = format!("{{ name: {}, host: 10.0.0.1, user: deploy, groups: [{}] }}", server, groups.join(", "));
+        let target: Target = serde_yaml::from_str(&yaml).unwrap();
+        return Assignment { target, jobs: vec![Job::new(String::from("cleanup"), command.to_string())] };
+    }
+
+    fn guardrails(max_targets: Option<usize>) -> Guardrails {
+        return Guardrails { denylist: vec![String::from(r"rm\s+-rf\s+/")], max_targets };
+    }
+
+    #[test]
+    fn safe_plans_have_no_violations() {
+        let plan = vec![assignment("web-1", &["web"], "rm -rf ./tmp")];
+        assert_eq!(guardrails(Some(1)).check(&plan, &[String::from("prod")]).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn protected_groups_are_confirmed_with_their_name() {
+        let plan = vec![assignment("db-1", &["prod"], "uptime"), assignment("db-2", &["prod"], "uptime")];
+        let violations = guardrails(None).check(&plan, &[String::from("prod")]).unwrap();
+        assert_eq!(violations, vec![Violation {
+            message: String::from("db-1, db-2 of the protected group prod are targeted"),
+            confirmation: String::from("prod")
+        }]);
+    }
+
+    #[test]
+    fn denylisted_commands_are_confirmed_once_per_job() {
+        let plan = vec![assignment("web-1", &[], "rm -rf /var/cache"), assignment("web-2", &[], "rm -rf /var/cache")];
+        let violations = guardrails(None).check(&plan, &[]).unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].confirmation, "cleanup");
+    }
+
+    #[test]
+    fn too_many_targets_are_confirmed_with_their_number() {
+        let plan = vec![assignment("web-1", &[], "uptime"), assignment("web-2", &[], "uptime")];
+        let violations = guardrails(Some(1)).check(&plan, &[]).unwrap();
+        assert_eq!(violations[0].confirmation, "2");
+        assert!(Guardrails { denylist: vec![String::from("(")], max_targets: None }.validate().is_err());
+    }
+
+    #[test]
+    fn jobs_named_like_protected_groups_are_still_denied() {
+        let plan = vec![assignment("db-1", &["cleanup"], "rm -rf /var/cache")];
+        let violations = guardrails(None).check(&plan, &[String::from("cleanup")]).unwrap();
+        assert_eq!(violations.len(), 2);
+        assert!(violations[1].message.contains("denylist"));
+    }
+
+    #[test]
+    fn commands_matching_once_rendered_with_outputs_are_refused() {
+        let job = Job::new(String::from("cleanup"), String::from("rm -rf {{ outputs.build.path | raw }}"));
+        let planned = job.render(&serde_json::json!({})).unwrap();
+        let outputs = |path: &str| -> BTreeMap<String, BTreeMap<String, String>> {
+            let mut build = BTreeMap::new();
+            build.insert(String::from("path"), path.to_string());
+            let mut outputs = BTreeMap::new();
+            outputs.insert(String::from("build"), build);
+            return outputs;
+        };
+
+        let rendered = planned.render_outputs(&outputs("./dist")).unwrap();
+        assert!(guardrails(None).check_rendered(&planned, &rendered).is_ok());
+        let rendered = planned.render_outputs(&outputs("/")).unwrap();
+        assert!(guardrails(None).check_rendered(&planned, &rendered).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::inventory::Target;
+use crate::job::Job;
+
+/// Outcome of a single job on a single server
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Success,
+    Failed,
+    TimedOut,
+    Unreachable,
+    /// The job did not run because of the outcome
+    /// of the jobs it needs
+    Skipped,
+    /// The user is not allowed to run the job on the server
+    Denied,
+    /// The run was cancelled before the job started
+    Cancelled
+}
+
+impl Status {
+    /// Returns true if the job ran and did not complete
+    /// successfully
+    pub fn is_failure(&self) -> bool {
+        return *self != Status::Success && *self != Status::Skipped;
+    }
+
+    /// Returns true if the job failed, timed out or could not
+    /// reach its server, the jobs a rerun of the failures retries
+    pub fn is_retryable(&self) -> bool {
+        return matches!(self, Status::Failed | Status::TimedOut | Status::Unreachable);
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Status::Success => "success",
+            Status::Failed => "failed",
+            Status::TimedOut => "timed out",
+            Status::Unreachable => "unreachable",
+            Status::Skipped => "skipped",
+            Status::Denied => "denied",
+            Status::Cancelled => "cancelled"
+        };
+        return write!(f, "{}", text);
+    }
+}
+
+/// Result of a single step of a job
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepResult {
+    pub name: String,
+    pub command: String,
+    pub exit_status: i32,
+    pub output: String,
+    /// Whether a deployed template changed the remote file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed: Option<bool>,
+    /// Why the step could not run or complete
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+/// Result of running a job on a server
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobResult {
+    pub server: String,
+    pub job: String,
+    /// The command as rendered for the server
+    #[serde(default)]
+    pub command: String,
+    pub status: Status,
+    /// Exit status of the command, if it ran at all
+    pub exit_status: Option<i32>,
+    pub output: String,
+    /// Connection or execution error, if any
+    pub error: Option<String>,
+    /// Results of the steps which were run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<StepResult>,
+    /// Values captured from the output
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub outputs: BTreeMap<String, String>,
+    /// Whether the templates deployed by the job changed
+    /// the remote files, unset if it deploys none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changed: Option<bool>,
+    pub duration_ms: u64
+}
+
+/// A stored run of a set of jobs on a set of servers
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Run {
+    pub id: String,
+    /// Id of the run this run was re-run from
+    #[serde(default)]
+    pub rerun_of: Option<String>,
+    /// Schedule of the daemon which started the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    pub user: String,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub targets: Vec<Target>,
+    /// The jobs as defined, before rendering
+    pub jobs: Vec<Job>,
+    /// Run-level parameters used to render the jobs
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    /// Guardrails which were confirmed or overridden
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub guardrails: Vec<String>,
+    /// Reason given to override the guardrails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub override_reason: Option<String>,
+    pub results: Vec<JobResult>
+}
+
+impl Run {
+    /// Method to generate a new run id from the start time,
+    /// followed by 128 random bits so that ids can not be
+    /// guessed nor collide for runs started together
+    pub fn generate_id(started_at: &DateTime<Utc>) -> String {
+        return format!("{}-{:032x}", started_at.format("%Y%m%d-%H%M%S"), rand::random::<u128>());
+    }
+
+    /// Returns the names of the servers on which at least one
+    /// job failed, timed out or could not reach the server. Jobs
+    /// which were denied or cancelled are not retried
+    pub fn failed_servers(&self) -> Vec<String> {
+        let mut servers: Vec<String> = Vec::new();
+        for result in self.results.iter().filter(|r| r.status.is_retryable()) {
+            if !servers.contains(&result.server) {
+                servers.push(result.server.clone());
+            }
+        }
+        return servers;
+    }
+}
+
+/// Struct to store and retrieve runs. Every run is
+/// stored as a json file named after its id
+pub struct History {
+    dir: PathBuf
+}
+
+impl History {
+    pub fn new(dir: PathBuf) -> Self {
+        return History { dir };
+    }
+
+    /// Method to open the history stored in the
+    /// basecamp data directory
+    pub fn open_default() -> Self {
+        return History::new(crate::paths::data_dir().join("history"));
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        return self.dir.join(format!("{}.json", id));
+    }
+
+    /// Method to store a run in the history
+    pub fn save(&self, run: &Run) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(&run.id);
+        fs::write(&path, serde_json::to_string_pretty(run)?)?;
+        return Ok(path);
+    }
+
+    /// Method to load a run using its id
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let run = History::open_default().load("20201201-101500-3fa2c6d09b1e4f7a8d5c2b6e9f01a7c4")?;
+    /// ```
+    pub fn load(&self, id: &str) -> Result<Run, Box<dyn std::error::Error>> {
+        let path = self.path(id);
+        if !Path::new(&path).exists() {
+            return Err(format!("Run {} not found in {}", id, self.dir.display()).into());
+        }
+        let run: Run = serde_json::from_str(&fs::read_to_string(path)?)?;
+        return Ok(run);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_of_runs_started_together_differ() {
+        let started_at = Utc::now();
+        let first = Run::generate_id(&started_at);
+        let second = Run::generate_id(&started_at);
+
+        let prefix = started_at.format("%Y%m%d-%H%M%S-").to_string();
+        assert!(first.starts_with(&prefix));
+        assert_eq!(first.len(), prefix.len() + 32);
+        assert!(first[prefix.len()..].chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn denied_and_cancelled_jobs_are_not_retried() {
+        let result = |server: &str, status: &str| serde_json::json!({
+            "server": server, "job": "deploy", "status": status,
+            "exit_status": null, "output": "", "error": null, "duration_ms": 0
+        });
+        let run: Run = serde_json::from_value(serde_json::json!({
+            "id": "20261019-101500-0", "rerun_of": null, "user": "alice",
+            "started_at": "2026-10-19T10:15:00Z", "duration_ms": 0, "targets": [], "jobs": [],
+            "results": [
+                result("web-1", "success"), result("web-2", "failed"), result("web-3", "timed_out"),
+                result("web-4", "unreachable"), result("web-5", "denied"), result("web-6", "cancelled"),
+                result("web-7", "skipped")
+            ]
+        })).unwrap();
+        assert_eq!(run.failed_servers(), vec!["web-2", "web-3", "web-4"]);
+    }
+}
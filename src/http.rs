@@ -0,0 +1,295 @@
+use std::sync::Arc;
+use std::time::Duration;
+use reqwest::{Client, Method, Proxy, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Settings of the HTTP requests made by basecamp,
+/// the `http` section of `basecamp.yaml`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HttpSettings {
+    /// Seconds a request may take, including its response
+    #[serde(default = "HttpSettings::default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "HttpSettings::default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Number of times a request is sent again when the server
+    /// can not be reached or answers 429, 502, 503 or 504
+    #[serde(default = "HttpSettings::default_retries")]
+    pub retries: u32,
+    /// Proxy of every request, e.g. `http://proxy.internal:3128`.
+    /// `HTTP_PROXY` and `HTTPS_PROXY` are used otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        return HttpSettings {
+            timeout_secs: HttpSettings::default_timeout_secs(),
+            connect_timeout_secs: HttpSettings::default_connect_timeout_secs(),
+            retries: HttpSettings::default_retries(),
+            proxy: None
+        };
+    }
+}
+
+impl HttpSettings {
+    fn default_timeout_secs() -> u64 {
+        return 30;
+    }
+
+    fn default_connect_timeout_secs() -> u64 {
+        return 10;
+    }
+
+    fn default_retries() -> u32 {
+        return 2;
+    }
+}
+
+/// Status and body of an HTTP response
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub body: String
+}
+
+impl Response {
+    /// Method to parse the body as JSON
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, Box<dyn std::error::Error>> {
+        return Ok(serde_json::from_str(&self.body).map_err(|err| format!("Invalid JSON in the response: {}", err))?);
+    }
+}
+
+/// Receives the messages about requests which did not fail,
+/// such as their retries, so that the caller shows them where
+/// its output goes
+pub type Notices = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Returns notices printed to stderr, for the
+/// commands which do not draw a UI
+pub fn stderr_notices() -> Notices {
+    return Arc::new(|message| eprintln!("{}", message));
+}
+
+/// Wrapper class to handle HTTP requests. Every request of
+/// basecamp goes through it, so that they share the timeouts,
+/// retries, proxy and the token of the user
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client,
+    /// URL the relative paths are joined to
+    base_url: String,
+    /// Sent as a bearer token when not empty
+    token: String,
+    /// Headers added to every request
+    headers: Vec<(String, String)>,
+    retries: u32,
+    notices: Notices
+}
+
+impl HttpClient {
+    /// Delay before the first retry, doubled for each retry
+    const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+    /// Method to create a client sending the requests
+    /// relative to the base URL
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let client = HttpClient::new("https://inventory.internal/api", &settings.http)?
+    ///     .with_token(&user.token);
+    /// let servers: Vec<Target> = client.get_json("servers").await?;
+    /// ```
+    pub fn new(base_url: &str, settings: &HttpSettings) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+            .user_agent(concat!("basecamp/", env!("CARGO_PKG_VERSION")));
+        if let Some(proxy) = &settings.proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str()).map_err(|err| format!("Invalid proxy {}: {}", proxy, err))?);
+        }
+
+        return Ok(HttpClient {
+            client: builder.build()?,
+            base_url: base_url.to_string(),
+            token: String::new(),
+            headers: Vec::new(),
+            retries: settings.retries,
+            notices: stderr_notices()
+        });
+    }
+
+    /// Method to send the token as `Authorization: Bearer`
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = token.to_string();
+        return self;
+    }
+
+    /// Method to pass the retries to the notices
+    /// instead of printing them to stderr
+    pub fn with_notices(mut self, notices: &Notices) -> Self {
+        self.notices = Arc::clone(notices);
+        return self;
+    }
+
+    /// Method to add a header to every request
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        return self;
+    }
+
+    /// Returns the URL of the path. Absolute URLs are kept
+    /// and relative paths are joined to the base URL
+    pub fn url(&self, path: &str) -> String {
+        if path.starts_with("http://") || path.starts_with("https://") || self.base_url.is_empty() {
+            return path.to_string();
+        }
+        if path.is_empty() {
+            return self.base_url.clone();
+        }
+        return format!("{}/{}", self.base_url.trim_end_matches('/'), path.trim_start_matches('/'));
+    }
+
+    /// Method to send a request with an optional JSON body,
+    /// retrying while the server can not be reached or is
+    /// unavailable. Requests which are not idempotent, such
+    /// as POST, are only sent again when the connection could
+    /// not be made, as the server may have handled them.
+    /// Responses of any other status are returned to the caller
+    pub async fn request(&self, method: Method, path: &str, body: Option<&Value>) -> Result<Response, Box<dyn std::error::Error>> {
+        let url = self.url(path);
+        let mut delay = HttpClient::RETRY_DELAY;
+        let mut attempt = 0;
+        let idempotent = method.is_idempotent();
+        loop {
+            let mut request = self.client.request(method.clone(), &url);
+            if !self.token.is_empty() {
+                request = request.bearer_auth(&self.token);
+            }
+            for (name, value) in &self.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+
+            let error = match request.send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    if !idempotent || !HttpClient::is_transient(status) || attempt >= self.retries {
+                        let body = response.text().await?;
+                        return Ok(Response { status, body });
+                    }
+                    format!("{} answered {}", url, status)
+                },
+                Err(err) => {
+                    // Nothing was sent when the connection failed
+                    let retryable = err.is_connect() || (idempotent && err.is_timeout());
+                    if !retryable || attempt >= self.retries {
+                        return Err(format!("Could not reach {}: {}", url, err).into());
+                    }
+                    err.to_string()
+                }
+            };
+
+            attempt += 1;
+            (self.notices)(&format!("Retrying {} {} ({}/{}): {}", method, url, attempt, self.retries, error));
+            tokio::time::delay_for(delay).await;
+            delay *= 2;
+        }
+    }
+
+    /// Returns whether the status means the request may
+    /// succeed if it is sent again
+    fn is_transient(status: StatusCode) -> bool {
+        return status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::BAD_GATEWAY
+            || status == StatusCode::SERVICE_UNAVAILABLE
+            || status == StatusCode::GATEWAY_TIMEOUT;
+    }
+
+    /// Method to send the request, failing unless the
+    /// server answers with a success status
+    async fn expect_success(&self, method: Method, path: &str, body: Option<&Value>) -> Result<Response, Box<dyn std::error::Error>> {
+        let response = self.request(method.clone(), path, body).await?;
+        if !response.status.is_success() {
+            return Err(format!("{} {} answered {}: {}", method, self.url(path), response.status, response.body.trim()).into());
+        }
+        return Ok(response);
+    }
+
+    /// Method to get the body of the path
+    pub async fn get(&self, path: &str) -> Result<String, Box<dyn std::error::Error>> {
+        return Ok(self.expect_success(Method::GET, path, None).await?.body);
+    }
+
+    /// Method to get the path and parse its body as JSON
+    pub async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T, Box<dyn std::error::Error>> {
+        return self.expect_success(Method::GET, path, None).await?.json();
+    }
+
+    /// Method to post the body as JSON and parse the
+    /// body of the response as JSON
+    pub async fn post_json<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T, Box<dyn std::error::Error>> {
+        let body = serde_json::to_value(body)?;
+        return self.expect_success(Method::POST, path, Some(&body)).await?.json();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Starts a server answering every request with 503,
+    /// returning its URL and the number of requests it got
+    fn unavailable() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&hits);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                std::io::Read::read_exact(&mut reader, &mut body).unwrap();
+                counted.fetch_add(1, Ordering::SeqCst);
+                let _ = write!(stream, "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            }
+        });
+        return (url, hits);
+    }
+
+    #[test]
+    fn posts_are_not_sent_again_once_the_server_got_them() {
+        let settings: HttpSettings = serde_yaml::from_str("{ retries: 1 }").unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let (url, hits) = unavailable();
+        let client = HttpClient::new(&url, &settings).unwrap().with_notices(&(Arc::new(|_: &str| {}) as Notices));
+        let response = runtime.block_on(client.request(Method::POST, "/login", Some(&serde_json::json!({})))).unwrap();
+        assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let response = runtime.block_on(client.request(Method::GET, "/servers", None)).unwrap();
+        assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+}
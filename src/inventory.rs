@@ -0,0 +1,272 @@
+use std::fs;
+use std::path::Path;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A remote server that jobs can be executed on
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    pub name: String,
+    pub host: String,
+    #[serde(default = "Target::default_port")]
+    pub port: u16,
+    pub user: String,
+    /// Path to the PEM encoded private key. The ssh-agent
+    /// is used when no key is given
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Groups the target belongs to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Variables of the target, overriding the group variables
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, Value>,
+    /// Server to connect through, either the name of another
+    /// server or `[user@]host[:port]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jump: Option<String>,
+    /// The jump host, set when the target is resolved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<Box<Target>>
+}
+
+impl Target {
+    fn default_port() -> u16 {
+        return 22;
+    }
+
+    /// Returns whether the target is selected by its name, by one
+    /// of its groups (`group:NAME`), tags (`tag:NAME`) or by `all`
+    pub fn matches(&self, selector: &str) -> bool {
+        if selector == "all" {
+            return true;
+        }
+        if let Some(group) = selector.strip_prefix("group:") {
+            return self.groups.iter().any(|g| g == group);
+        }
+        if let Some(tag) = selector.strip_prefix("tag:") {
+            return self.tags.iter().any(|t| t == tag);
+        }
+        return self.name == selector;
+    }
+
+    /// Returns the `host:port` address of the target
+    pub fn address(&self) -> String {
+        return format!("{}:{}", self.host, self.port);
+    }
+
+    /// Returns how the user is authenticated
+    pub fn auth_method(&self) -> String {
+        return match &self.key {
+            Some(key) => format!("key {}", key),
+            None => String::from("ssh-agent")
+        };
+    }
+
+    /// Returns the jump hosts to connect through, the
+    /// first one being connected to directly
+    pub fn jump_hosts(&self) -> Vec<&Target> {
+        let mut hosts = Vec::new();
+        let mut via = self.via.as_deref();
+        while let Some(host) = via {
+            hosts.insert(0, host);
+            via = host.via.as_deref();
+        }
+        return hosts;
+    }
+}
+
+/// A named group of servers sharing variables
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Group {
+    #[serde(default)]
+    pub vars: BTreeMap<String, Value>,
+    /// Runs targeting the servers of the group must
+    /// be confirmed by typing the group name
+    #[serde(default)]
+    pub protected: bool
+}
+
+/// Struct to model the list of servers and groups
+/// configured in `server.yaml`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Inventory {
+    #[serde(default)]
+    groups: BTreeMap<String, Group>,
+    #[serde(default)]
+    servers: Vec<Target>
+}
+
+impl Inventory {
+    /// Name of the inventory file inside the config directory
+    pub const FILE_NAME: &'static str = "server.yaml";
+
+    /// Method to load the inventory from a yaml file
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let inventory = Inventory::load(Path::new("config/server.yaml"))?;
+    /// ```
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)?;
+        if contents.trim().is_empty() {
+            return Ok(Inventory::default());
+        }
+
+        let inventory: Inventory = serde_yaml::from_str(&contents)?;
+        inventory.validate()?;
+        return Ok(inventory);
+    }
+
+    /// Method to detect invalid and circular jump hosts
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for server in &self.servers {
+            self.resolve_jumps(&server.name, &mut Vec::new())?;
+        }
+        return Ok(());
+    }
+
+    /// Creates an inventory of the groups and servers
+    /// of another source
+    pub fn new(groups: BTreeMap<String, Group>, servers: Vec<Target>) -> Self {
+        return Inventory { groups, servers };
+    }
+
+    /// Method to add the groups and servers of another source.
+    /// Groups and servers already in the inventory keep
+    /// their configuration
+    ///
+    /// # Examples
+    /// ```no_run
+    /// inventory.merge(source.load(&settings.http, &user.token, &notices).await?)?;
+    /// ```
+    pub fn merge(&mut self, other: Inventory) -> Result<(), Box<dyn std::error::Error>> {
+        for (name, group) in other.groups {
+            self.groups.entry(name).or_insert(group);
+        }
+        for server in other.servers {
+            if self.get(&server.name).is_none() {
+                self.servers.push(server);
+            }
+        }
+        return self.validate();
+    }
+
+    pub fn servers(&self) -> &[Target] {
+        return &self.servers;
+    }
+
+    /// Returns the server with the given name
+    pub fn get(&self, name: &str) -> Option<&Target> {
+        return self.servers.iter().find(|s| s.name == name);
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        return self.groups.get(name);
+    }
+
+    /// Returns the names of the protected groups
+    pub fn protected_groups(&self) -> Vec<String> {
+        return self.groups.iter()
+            .filter(|(_, group)| group.protected)
+            .map(|(name, _)| name.clone())
+            .collect();
+    }
+
+    /// Returns the server with the given name with the
+    /// variables of its groups applied. Group variables are
+    /// applied in order, the variables of the server itself
+    /// take precedence
+    pub fn resolve(&self, name: &str) -> Option<Target> {
+        return self.resolve_jumps(name, &mut Vec::new()).ok();
+    }
+
+    /// Method to resolve a server and its jump hosts,
+    /// `path` holding the servers being resolved
+    fn resolve_jumps(&self, name: &str, path: &mut Vec<String>) -> Result<Target, Box<dyn std::error::Error>> {
+        let mut target = match self.get(name) {
+            Some(target) => target.clone(),
+            None => return Err(format!("Unknown server {}", name).into())
+        };
+        let mut vars = BTreeMap::new();
+        for group in target.groups.iter().filter_map(|g| self.groups.get(g)) {
+            vars.extend(group.vars.clone());
+        }
+        vars.extend(target.vars);
+        target.vars = vars;
+
+        if path.iter().any(|p| p == name) {
+            return Err(format!("Servers jump through each other: {} -> {}", path.join(" -> "), name).into());
+        }
+        path.push(name.to_string());
+        target.via = match &target.jump {
+            Some(jump) if self.get(jump).is_some() => Some(Box::new(self.resolve_jumps(jump, path)?)),
+            Some(jump) => Some(Box::new(Inventory::parse_jump(jump, &target)?)),
+            None => None
+        };
+        path.pop();
+        return Ok(target);
+    }
+
+    /// Method to build the jump host written as `[user@]host[:port]`,
+    /// using the user and key of the target by default
+    fn parse_jump(jump: &str, target: &Target) -> Result<Target, Box<dyn std::error::Error>> {
+        let (user, address) = match jump.split_once('@') {
+            Some((user, address)) => (user.to_string(), address),
+            None => (target.user.clone(), jump)
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port.parse::<u16>()
+                    .map_err(|_| format!("Invalid port in jump host `{}` of {}", jump, target.name))?;
+                (host.to_string(), port)
+            },
+            None => (address.to_string(), Target::default_port())
+        };
+        return Ok(Target {
+            name: jump.to_string(),
+            host,
+            port,
+            user,
+            key: target.key.clone(),
+            groups: Vec::new(),
+            tags: Vec::new(),
+            vars: BTreeMap::new(),
+            jump: None,
+            via: None
+        });
+    }
+
+    /// Method to select servers by name, by group (`group:NAME`),
+    /// by tag (`tag:NAME`) or all of them (`all`). The servers are
+    /// returned resolved, in the order of the inventory
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let targets = inventory.select(&["group:delhi".to_string(), "HRMAIN01".to_string()])?;
+    /// ```
+    pub fn select(&self, selectors: &[String]) -> Result<Vec<Target>, Box<dyn std::error::Error>> {
+        let mut names: Vec<&str> = Vec::new();
+        for selector in selectors {
+            let matched: Vec<&str> = self.servers.iter()
+                .filter(|s| s.matches(selector))
+                .map(|s| s.name.as_str())
+                .collect();
+            if matched.is_empty() {
+                return Err(format!("No server matches `{}`", selector).into());
+            }
+            names.extend(matched);
+        }
+
+        let mut targets = Vec::new();
+        for server in &self.servers {
+            if names.contains(&server.name.as_str()) {
+                targets.push(self.resolve_jumps(&server.name, &mut Vec::new())?);
+            }
+        }
+        return Ok(targets);
+    }
+}
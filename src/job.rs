@@ -1,38 +1,692 @@
-use ssh2;
-use ssh2::Channel;
+use std::fs;
+use std::path::Path;
+use std::collections::BTreeMap;
+use ssh2::{Channel, Session};
 use std::io::Read;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+use crate::deploy::TemplateFile;
+use crate::escalation::{Escalation, Method, Secret};
+use crate::history::StepResult;
+use crate::shell;
+use crate::template;
+use crate::transfer::{remote_exec, remote_mktemp, scp_write, Direction, Transfer, TEMP_TEMPLATE};
+use crate::workflow::{self, Condition, OutputSpec};
+
+/// A single command of a job, a local script which is
+/// uploaded to the server and run with an interpreter, a
+/// file upload or download, or a template deployment
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// Path to a local script file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    /// Interpreter used to run the script
+    #[serde(default = "Step::default_interpreter")]
+    pub interpreter: String,
+    /// Arguments passed to the script
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Working directory on the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload: Option<Transfer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download: Option<Transfer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<TemplateFile>,
+    /// Run the next steps even if this step fails
+    #[serde(default)]
+    pub continue_on_error: bool
+}
+
+impl Step {
+    fn default_interpreter() -> String {
+        return String::from("sh");
+    }
+
+    /// Returns the name of the step, falling back
+    /// to its position in the job
+    pub fn display_name(&self, index: usize) -> String {
+        return match &self.name {
+            Some(name) => name.clone(),
+            None => format!("step {}", index + 1)
+        };
+    }
+
+    /// Returns the transfer run by the step, if any
+    pub fn transfer(&self) -> Option<(Direction, &Transfer)> {
+        if let Some(upload) = &self.upload {
+            return Some((Direction::Upload, upload));
+        }
+        return self.download.as_ref().map(|download| (Direction::Download, download));
+    }
+
+    /// Returns the number of actions set on the step,
+    /// which must be exactly one
+    fn actions(&self) -> usize {
+        return [self.command.is_some(), self.script.is_some(), self.upload.is_some(), self.download.is_some(), self.template.is_some()]
+            .iter()
+            .filter(|set| **set)
+            .count();
+    }
+
+    /// Method to create a copy of the step with every
+    /// field rendered using the given variables
+    fn render(&self, context: &Value, deferred: Option<&str>) -> Result<Step, Box<dyn std::error::Error>> {
+        let render = |value: &str| template::render_partial(value, context, deferred);
+        let render_opt = |value: &Option<String>| -> Result<Option<String>, Box<dyn std::error::Error>> {
+            return match value {
+                Some(v) => Ok(Some(render(v)?)),
+                None => Ok(None)
+            };
+        };
+        // Commands are run by the shell, their values are quoted
+        let command = match &self.command {
+            Some(command) => Some(template::render_command(command, context, deferred)?),
+            None => None
+        };
+
+        let mut env = BTreeMap::new();
+        for (key, value) in &self.env {
+            env.insert(key.clone(), render(value)?);
+        }
+
+        let args: Result<Vec<String>, _> = self.args.iter().map(|a| render(a)).collect();
+        let render_transfer = |value: &Option<Transfer>| -> Result<Option<Transfer>, Box<dyn std::error::Error>> {
+            return match value {
+                Some(t) => Ok(Some(t.render(context, deferred)?)),
+                None => Ok(None)
+            };
+        };
+        let template = match &self.template {
+            Some(t) => Some(t.render(context, deferred)?),
+            None => None
+        };
+
+        return Ok(Step {
+            name: self.name.clone(),
+            command,
+            script: render_opt(&self.script)?,
+            interpreter: template::render_command(&self.interpreter, context, deferred)?,
+            args: args?,
+            cwd: render_opt(&self.cwd)?,
+            env,
+            upload: render_transfer(&self.upload)?,
+            download: render_transfer(&self.download)?,
+            template,
+            continue_on_error: self.continue_on_error
+        });
+    }
+
+    /// Returns the templates used by the step
+    fn templates(&self) -> Vec<&str> {
+        let mut templates: Vec<&str> = vec![&self.interpreter];
+        templates.extend(self.command.as_deref());
+        templates.extend(self.script.as_deref());
+        templates.extend(self.cwd.as_deref());
+        templates.extend(self.args.iter().map(|a| a.as_str()));
+        templates.extend(self.env.values().map(|v| v.as_str()));
+        if let Some((_, transfer)) = self.transfer() {
+            templates.extend(transfer.templates());
+        }
+        if let Some(template) = &self.template {
+            templates.extend(template.templates());
+        }
+        return templates;
+    }
+
+    /// Returns the shell prefix setting the environment
+    /// and the working directory of the step
+    fn prefix(&self) -> String {
+        let mut prefix = String::new();
+        for (key, value) in &self.env {
+            prefix.push_str(&format!("export {}={}; ", key, shell_quote(value)));
+        }
+        if let Some(cwd) = &self.cwd {
+            prefix.push_str(&format!("cd {} || exit 1; ", shell_quote(cwd)));
+        }
+        return prefix;
+    }
+
+    /// Returns the command line sent to the server. Scripts
+    /// are run from `remote_path`
+    fn command_line(&self, remote_path: &str) -> String {
+        return match (&self.command, &self.script) {
+            (Some(command), _) => format!("{}{}", self.prefix(), command),
+            (None, _) => {
+                let mut line = format!("{}{} {}", self.prefix(), self.interpreter, shell_quote(remote_path));
+                for arg in &self.args {
+                    line.push(' ');
+                    line.push_str(&shell_quote(arg));
+                }
+                line
+            }
+        };
+    }
+
+    /// Returns a readable description of what the step runs
+    pub fn describe(&self) -> String {
+        return match (&self.command, &self.script) {
+            (Some(_), _) => self.command_line(""),
+            (None, Some(script)) => {
+                let mut line = format!("{}{} {}", self.prefix(), self.interpreter, script);
+                for arg in &self.args {
+                    line.push(' ');
+                    line.push_str(&shell_quote(arg));
+                }
+                line
+            },
+            (None, None) => match (self.transfer(), &self.template) {
+                (Some((direction, transfer)), _) => transfer.describe(direction),
+                (None, Some(template)) => template.describe(),
+                (None, None) => String::new()
+            }
+        };
+    }
+
+    /// Method to upload the script of the step to a new
+    /// temporary file on the server, removed if the upload
+    /// fails. Only the login user can read it unless it is
+    /// run as another user than root
+    fn upload_script(&self, session: &Session, script: &str, mode: i32) -> Result<String, Box<dyn std::error::Error>> {
+        let contents = fs::read(script)
+            .map_err(|err| format!("Could not read script {}: {}", script, err))?;
+
+        let remote_path = remote_mktemp(|command| remote_exec(session, command), TEMP_TEMPLATE)?;
+        let uploaded = scp_write(session, &remote_path, &contents)
+            .and_then(|_| remote_exec(session, &format!("chmod {:o} -- {}", mode, shell_quote(&remote_path))));
+        if let Err(err) = uploaded {
+            let _ = remote_exec(session, &format!("rm -f -- {}", shell_quote(&remote_path)));
+            return Err(err);
+        }
+        return Ok(remote_path);
+    }
+
+    /// Returns a successful result without output
+    fn new_result(&self, index: usize, escalation: Option<&Escalation>) -> StepResult {
+        return StepResult {
+            name: self.display_name(index),
+            command: match escalation {
+                Some(escalation) if self.transfer().is_none() => {
+                    Escalation::describe(escalation.method, &escalation.user, &self.describe())
+                },
+                _ => self.describe()
+            },
+            exit_status: 0,
+            output: String::new(),
+            changed: None,
+            error: None
+        };
+    }
+
+    /// Method to run the step on a new channel of the session,
+    /// as another user when an escalation is given and on a PTY
+    /// when `pty` is set. Transfers always run as the login user.
+    /// The lines of commands are passed on as they are read, the
+    /// others once the step is over
+    fn execute(&self, index: usize, session: &Session, server: &str, escalation: Option<&Escalation>, pty: bool, on_line: &dyn Fn(&str)) -> Result<StepResult, Box<dyn std::error::Error>> {
+        let mut result = self.new_result(index, escalation);
+
+        if let Some((direction, transfer)) = self.transfer() {
+            result.output = transfer.execute(direction, session, server, on_line)?;
+            result.output.lines().for_each(on_line);
+            return Ok(result);
+        }
+        if let Some(template) = &self.template {
+            let deployed = template.execute(session, escalation)?;
+            result.output = deployed.output;
+            result.exit_status = deployed.exit_status;
+            result.changed = Some(deployed.changed);
+            result.output.lines().for_each(on_line);
+            return Ok(result);
+        }
+
+        // Root can read the scripts of every user
+        let mode = match escalation {
+            Some(escalation) if escalation.user != "root" => 0o755,
+            _ => 0o700
+        };
+        let remote_path = match &self.script {
+            Some(script) if self.command.is_none() => self.upload_script(session, script, mode)?,
+            _ => String::new()
+        };
+
+        let executed = match escalation {
+            Some(escalation) => escalation.run(session, &self.command_line(&remote_path)).map(|(output, exit_status)| {
+                output.lines().for_each(on_line);
+                return (output, exit_status);
+            }),
+            None => {
+                let mut channel = session.channel_session()?;
+                if pty {
+                    channel.request_pty(shell::TERM, None, None)?;
+                }
+                run_command(&mut channel, &self.command_line(&remote_path), on_line).and_then(|output| {
+                    channel.wait_close()?;
+                    // The PTY translates the new lines
+                    let output = if pty { output.replace("\r\n", "\n") } else { output };
+                    return Ok((output, channel.exit_status()?));
+                })
+            }
+        };
+        // The script is owned by the login user, so it is removed as that user
+        if !remote_path.is_empty() {
+            let _ = remote_exec(session, &format!("rm -f -- {}", shell_quote(&remote_path)));
+        }
+        let (output, exit_status) = executed?;
+        result.output = output;
+        result.exit_status = exit_status;
+        return Ok(result);
+    }
+}
+
+/// Output of a job executed on a server
+pub struct JobOutput {
+    pub output: String,
+    /// Exit status of the first failing step, 0 if
+    /// every step which ran exited successfully
+    pub exit_status: i32,
+    pub steps: Vec<StepResult>,
+    /// Error of the first step which could not run or complete
+    pub error: Option<Box<dyn std::error::Error>>
+}
+
+impl JobOutput {
+    /// Returns whether a template deployed by the job changed
+    /// a remote file, `None` if the job deploys no template
+    pub fn changed(&self) -> Option<bool> {
+        return self.steps.iter()
+            .filter_map(|s| s.changed)
+            .fold(None, |changed, c| Some(changed.unwrap_or(false) || c));
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Job {
-    task: String
+    pub name: String,
+    /// Shorthand for a job made of a single step
+    #[serde(default, rename = "command", skip_serializing_if = "Option::is_none")]
+    task: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    steps: Vec<Step>,
+    /// Shorthand for a job uploading a file or directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload: Option<Transfer>,
+    /// Shorthand for a job downloading a file or directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download: Option<Transfer>,
+    /// Shorthand for a job deploying a template
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<TemplateFile>,
+    #[serde(default)]
+    pub description: String,
+    /// Jobs which have to finish on a server before
+    /// this job runs there
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub needs: Vec<String>,
+    /// When to run, given the outcome of the needed jobs
+    #[serde(default)]
+    pub when: Condition,
+    /// Values captured from the output, available to the jobs
+    /// which need this job as `{{ outputs.JOB.NAME }}`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub outputs: BTreeMap<String, OutputSpec>,
+    /// Run the commands, scripts and templates of the
+    /// job as another user
+    #[serde(default, rename = "become")]
+    pub escalate: bool,
+    /// User to run as, `root` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub become_user: Option<String>,
+    #[serde(default)]
+    pub become_method: Method,
+    /// Run the commands and scripts on a PTY, for
+    /// commands which need a terminal
+    #[serde(default)]
+    pub pty: bool,
+    /// The job before it was rendered and the variables it was
+    /// rendered with, so the outputs of the upstream jobs are
+    /// rendered in a single pass once they are known
+    #[serde(skip)]
+    unrendered: Option<Box<(Job, Value)>>
+}
+
+/// Layout of the `job.yaml` file
+#[derive(Default, Deserialize)]
+struct JobFile {
+    #[serde(default)]
+    jobs: Vec<Job>
+}
+
+/// Quotes a value to be used as a single shell word
+pub fn shell_quote(value: &str) -> String {
+    return format!("'{}'", value.replace('\'', "'\\''"));
+}
+
+/// Method to run a command over a specified channel and read
+/// its output, passing on every line as soon as it is read
+fn run_command(channel: &mut Channel, command: &str, on_line: &dyn Fn(&str)) -> Result<String, Box<dyn std::error::Error>> {
+    // Execute the job on the server
+    channel.exec(command)?;
+
+    // Read the output from the server
+    let mut output: Vec<u8> = Vec::new();
+    let mut buffer = [0; 4096];
+    let mut line_start = 0;
+    loop {
+        let read = channel.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        output.extend_from_slice(&buffer[..read]);
+        while let Some(end) = output[line_start..].iter().position(|b| *b == b'\n') {
+            on_line(String::from_utf8_lossy(&output[line_start..line_start + end]).trim_end_matches('\r'));
+            line_start += end + 1;
+        }
+    }
+    if line_start < output.len() {
+        on_line(&String::from_utf8_lossy(&output[line_start..]));
+    }
+
+    // Return the output of the command
+    return Ok(String::from_utf8_lossy(&output).into_owned());
 }
 
 impl Job {
-    pub fn new(task: String) -> Self {
+    /// Name of the job file inside the config directory
+    pub const FILE_NAME: &'static str = "job.yaml";
+
+    pub fn new(name: String, task: String) -> Self {
         return Job {
-            task
+            name,
+            task: Some(task),
+            steps: Vec::new(),
+            upload: None,
+            download: None,
+            template: None,
+            description: String::new(),
+            needs: Vec::new(),
+            when: Condition::default(),
+            outputs: BTreeMap::new(),
+            escalate: false,
+            become_user: None,
+            become_method: Method::default(),
+            pty: false,
+            unrendered: None
         };
     }
 
+    /// Method to load the jobs defined in
+    /// a yaml file
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let jobs = Job::load_all(Path::new("config/job.yaml"))?;
+    /// ```
+    pub fn load_all(path: &Path) -> Result<Vec<Job>, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)?;
+        if contents.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let file: JobFile = serde_yaml::from_str(&contents)?;
+        for job in &file.jobs {
+            job.validate()?;
+        }
+        // Detect unknown and circular dependencies
+        workflow::with_dependencies(&file.jobs, &file.jobs)?;
+        workflow::validate(&file.jobs)?;
+        return Ok(file.jobs);
+    }
+
+    /// Method to check that the job runs either a command,
+    /// a transfer or a list of steps, each step running either
+    /// a command, a script or a transfer
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let kinds = [self.task.is_some(), !self.steps.is_empty(), self.upload.is_some(), self.download.is_some(), self.template.is_some()];
+        if kinds.iter().filter(|set| **set).count() != 1 {
+            return Err(format!("Job {} must have exactly one of command, steps, upload, download or template", self.name).into());
+        }
+        for (i, step) in self.steps().iter().enumerate() {
+            if step.actions() != 1 {
+                return Err(format!("Step {} of job {} must have exactly one of command, script, upload, download or template", i + 1, self.name).into());
+            }
+            if let Some(template) = &step.template {
+                template.validate()
+                    .map_err(|err| format!("Step {} of job {}: {}", i + 1, self.name, err))?;
+            }
+            if let Some((direction, transfer)) = step.transfer() {
+                transfer.validate(direction)
+                    .map_err(|err| format!("Step {} of job {}: {}", i + 1, self.name, err))?;
+            }
+            let invalid_key = step.env.keys().find(|key| {
+                key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+            if let Some(key) = invalid_key {
+                return Err(format!("Step {} of job {} has an invalid environment variable `{}`", i + 1, self.name, key).into());
+            }
+        }
+        for (name, output) in &self.outputs {
+            output.validate().map_err(|err| format!("Output {} of job {}: {}", name, self.name, err))?;
+        }
+        return Ok(());
+    }
+
     /// Method to assign a new task to
-    /// the job
+    /// the job, replacing its steps
     pub fn assign_task(&mut self, task: String) {
-        self.task = task;
+        self.task = Some(task);
+        self.steps.clear();
+        self.upload = None;
+        self.download = None;
+        self.template = None;
+    }
+
+    /// Returns the steps of the job. A job with a single
+    /// command, transfer or template is a job with a single step
+    pub fn steps(&self) -> Vec<Step> {
+        if self.task.is_none() && self.upload.is_none() && self.download.is_none() && self.template.is_none() {
+            return self.steps.clone();
+        }
+        return vec![Step {
+            command: self.task.clone(),
+            interpreter: Step::default_interpreter(),
+            upload: self.upload.clone(),
+            download: self.download.clone(),
+            template: self.template.clone(),
+            ..Step::default()
+        }];
+    }
+
+    /// Returns a readable description of every
+    /// step of the job
+    pub fn commands(&self) -> Vec<String> {
+        return self.steps().iter().map(|step| {
+            if self.escalate && step.transfer().is_none() {
+                return Escalation::describe(self.become_method, self.become_user(), &step.describe());
+            }
+            return step.describe();
+        }).collect();
     }
 
-    /// Method to run the particular
-    /// job over a specified channel
-    pub async fn execute(&self, channel: &mut Channel) -> Result<String, Box<dyn std::error::Error>> {
-        // Execute the job on the server
-        channel.exec(&*self.task).unwrap();
+    /// Returns the user the job runs as when it escalates
+    pub fn become_user(&self) -> &str {
+        return self.become_user.as_deref().unwrap_or("root");
+    }
+
+    /// Returns how to run the job as another user, `None`
+    /// if it runs as the login user
+    pub fn escalation<'a>(&self, password: Option<&'a Secret>) -> Option<Escalation<'a>> {
+        if !self.escalate {
+            return None;
+        }
+        return Some(Escalation {
+            method: self.become_method,
+            user: self.become_user().to_string(),
+            password
+        });
+    }
 
-        // Read the output from the server
+    /// Returns the variables referenced by the job
+    pub fn variables(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut names: Vec<String> = Vec::new();
+        for step in self.steps() {
+            let mut variables = Vec::new();
+            for text in step.templates() {
+                variables.extend(template::variables(text)?);
+            }
+            if let Some(template) = &step.template {
+                variables.extend(template.content_variables()?);
+            }
+            for name in variables {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        return Ok(names);
+    }
+
+    /// Method to create a copy of the job with every
+    /// step rendered using the given variables. The outputs
+    /// of the upstream jobs are not known yet and are left
+    /// as is until `render_outputs`
+    pub fn render(&self, context: &Value) -> Result<Job, Box<dyn std::error::Error>> {
+        let mut job = self.render_with(context, Some(workflow::OUTPUTS))?;
+        job.unrendered = Some(Box::new((self.clone(), context.clone())));
+        return Ok(job);
+    }
+
+    /// Method to render the job again once the outputs of the
+    /// upstream jobs are known. The job is rendered from its
+    /// definition, so the values inserted by `render` are never
+    /// read as templates
+    pub fn render_outputs(&self, outputs: &BTreeMap<String, BTreeMap<String, String>>) -> Result<Job, Box<dyn std::error::Error>> {
+        let (job, context) = match &self.unrendered {
+            Some(unrendered) => (&unrendered.0, &unrendered.1),
+            None => return Ok(self.clone())
+        };
+        let mut context = context.clone();
+        if let Value::Object(map) = &mut context {
+            map.insert(workflow::OUTPUTS.to_string(), serde_json::to_value(outputs)?);
+        }
+        return job.render_with(&context, None);
+    }
+
+    fn render_with(&self, context: &Value, deferred: Option<&str>) -> Result<Job, Box<dyn std::error::Error>> {
+        let mut job = self.clone();
+        job.task = match &self.task {
+            Some(task) => Some(template::render_command(task, context, deferred)?),
+            None => None
+        };
+        let steps: Result<Vec<Step>, _> = self.steps.iter().map(|s| s.render(context, deferred)).collect();
+        job.steps = steps?;
+        job.upload = match &self.upload {
+            Some(upload) => Some(upload.render(context, deferred)?),
+            None => None
+        };
+        job.download = match &self.download {
+            Some(download) => Some(download.render(context, deferred)?),
+            None => None
+        };
+        job.template = match &self.template {
+            Some(template) => Some(template.render(context, deferred)?),
+            None => None
+        };
+        return Ok(job);
+    }
+
+    /// Method to run the steps of the job in order, each
+    /// over its own channel of the session. Stops at the first
+    /// step which fails or can not run, unless the step allows to
+    /// continue; the job fails either way. The results of the steps
+    /// which ran are kept. The password answers the prompt of sudo
+    /// or su. Every line of the output is passed to `on_line`
+    pub async fn execute(&self, session: &Session, server: &str, password: Option<&Secret>, on_line: &dyn Fn(&str)) -> Result<JobOutput, Box<dyn std::error::Error>> {
+        let escalation = self.escalation(password);
         let mut output = String::new();
-        channel.read_to_string(&mut output).unwrap();
+        let mut exit_status = 0;
+        let mut steps = Vec::new();
+        let mut error = None;
+
+        for (i, step) in self.steps().iter().enumerate() {
+            let (result, failed) = match step.execute(i, session, server, escalation.as_ref(), self.pty, on_line) {
+                Ok(result) => {
+                    let failed = result.exit_status != 0;
+                    if failed && exit_status == 0 {
+                        exit_status = result.exit_status;
+                    }
+                    (result, failed)
+                },
+                Err(err) => {
+                    let mut result = step.new_result(i, escalation.as_ref());
+                    result.error = Some(err.to_string());
+                    error = error.or(Some(err));
+                    (result, true)
+                }
+            };
+            output.push_str(&result.output);
+            steps.push(result);
+
+            if failed && !step.continue_on_error {
+                break;
+            }
+        }
+
+        return Ok(JobOutput { output, exit_status, steps, error });
+    }
+
+    /// Returns whether a step of the job deploys a template
+    pub fn deploys_templates(&self) -> bool {
+        return self.steps().iter().any(|s| s.template.is_some());
+    }
+
+    /// Method to compute the diffs of the templates the job would
+    /// deploy without writing them, reading the remote files as the
+    /// user the job runs as. Returns `None` if it deploys none
+    pub fn preview(&self, session: &Session, password: Option<&Secret>) -> Option<String> {
+        let escalation = self.escalation(password);
+        let templates: Vec<TemplateFile> = self.steps().into_iter().filter_map(|s| s.template).collect();
+        if templates.is_empty() {
+            return None;
+        }
 
-        // Return the output of the command
-        return Ok(output);
+        let mut preview = String::new();
+        for template in &templates {
+            match template.preview(session, escalation.as_ref()) {
+                Ok(diff) => preview.push_str(&diff),
+                Err(err) => preview.push_str(&format!("could not compare {}: {}\n", template.dest, err))
+            }
+        }
+        return Some(preview);
     }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
 
+    #[test]
+    fn outputs_are_rendered_in_a_single_pass() {
+        let job = Job::new(String::from("deploy"), String::from("echo {{ params.text }} {{ outputs.build.version }}"));
+        let context = json!({ "params": { "text": "{{ outputs.build.version }} {{foo" } });
 
-}
\ No newline at end of file
+        let planned = job.render(&context).unwrap();
+        assert_eq!(planned.commands(), vec!["echo '{{ outputs.build.version }} {{foo' {{ outputs.build.version }}"]);
+
+        let mut outputs = BTreeMap::new();
+        outputs.insert(String::from("build"), vec![(String::from("version"), String::from("1.2"))].into_iter().collect());
+        let rendered = planned.render_outputs(&outputs).unwrap();
+        assert_eq!(rendered.commands(), vec!["echo '{{ outputs.build.version }} {{foo' 1.2"]);
+    }
+}
@@ -1,40 +1,140 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
+#![allow(clippy::needless_return)]
 mod timer;
 mod job;
+mod paths;
+mod inventory;
+mod history;
+mod server;
+mod executor;
+mod diff;
+mod audit;
+mod deploy;
+mod template;
+mod transfer;
+mod workflow;
+mod settings;
+mod escalation;
+mod shell;
+mod broadcast;
+mod pool;
+mod tunnel;
+mod auth;
+mod access;
+mod http;
+mod sources;
+mod ansible;
+mod webhooks;
+mod schedule;
+mod guardrails;
+mod events;
+mod api;
 
 use std::sync::{Arc,Mutex};
 use std::io::{self, Write};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::thread;
 use std::sync::mpsc::{self, TryRecvError, Sender, Receiver};
-use std::net::TcpStream;
-use ssh2::Session;
-use std::path::Path;
-use futures::executor::block_on;
-use threadpool::ThreadPool;
 use cli_table::{format::Justify, print_stdout, Style as TableStyle, Cell, Table, CellStruct};
 use tui::Terminal;
 use tui::backend::CrosstermBackend;
 use tui::widgets::{Block, Borders, ListItem, Paragraph, ListState};
 use tui::layout::{Layout, Constraint, Direction, Alignment};
 use tui::style::{Style, Color};
-use crossterm::{style::{SetBackgroundColor}, ExecutableCommand};
+use crossterm::{style::{ResetColor, SetBackgroundColor}, ExecutableCommand};
 use crossterm::event::{poll, read, Event, KeyCode};
+use clap::{App, AppSettings, Arg, ArgGroup, SubCommand};
+use chrono::Utc;
+use futures::executor::block_on;
 
 use crate::timer::Timer;
 use crate::job::Job;
+use crate::inventory::{Inventory, Target};
+use crate::history::{History, JobResult, Run};
+use crate::executor::{Assignment, Executor};
+use crate::server::Server;
+use crate::broadcast::Broadcast;
+use crate::tunnel::{Tunnel, TunnelSpec};
+use crate::auth::{Authenticator, CachedLogin, UsersFile};
+use crate::access::Access;
+use crate::escalation::Secret;
+use crate::audit::{AuditLog, AuditRecord};
+use crate::settings::Settings;
+use crate::guardrails::{Acknowledgement, Violation};
+use crate::schedule::{Daemon, Schedule, ScheduleFile};
+use crate::events::{EventBus, EventKind, RunEvents};
+use crate::api::{Prepared, RunControl};
 use tui::text::{Span, Spans};
 use tokio::time::Duration;
 
+/// What the key presses in the UI are used for
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InputMode {
+    /// Selecting servers and jobs
+    Select,
+    /// Typing the value of a parameter
+    Prompt,
+    /// Confirming the rendered commands
+    Confirm,
+    /// Typing the confirmation of a guardrail
+    Guard,
+    /// Typing the spec of a tunnel to open
+    Tunnel,
+    /// Typing the id of a tunnel to close
+    CloseTunnel
+}
+
+/// How a run started from the command line proceeds
+#[derive(Clone, Debug, Default)]
+struct RunOptions {
+    /// Only print the plan, without running anything
+    dry_run: bool,
+    /// Print the plan as json
+    json: bool,
+    /// Do not ask for a confirmation
+    assume_yes: bool,
+    /// Reason given to run despite the guardrails
+    override_reason: Option<String>
+}
+
 struct ConsoleCLI {
     active_listener_index: usize,
     terminal: Terminal<CrosstermBackend<std::io::Stdout>>,
     task_listener: Arc<Mutex<Listener<String>>>,
     server_listener: Arc<Mutex<Listener<String>>>,
+    inventory: Inventory,
+    jobs: Vec<Job>,
     selected_servers: Vec<String>,
     selected_jobs: Vec<String>,
-    console_text: String,
+    /// Run-level parameters used to render the jobs
+    params: BTreeMap<String, String>,
+    /// Parameters which still have to be prompted for
+    pending_params: Vec<String>,
+    input: String,
+    mode: InputMode,
+    /// The rendered jobs waiting for confirmation
+    plan: Vec<Assignment>,
+    /// Stop once the plan is rendered, to print it
+    /// with its guardrails instead of running it
+    dry_run: bool,
+    settings: Settings,
+    /// The logged in user and what they can run
+    user: User,
+    access: Access,
+    /// Guardrails matched by the plan which still
+    /// have to be confirmed
+    violations: Vec<Violation>,
+    acknowledgement: Acknowledgement,
+    /// Tunnels opened from the UI
+    tunnels: Vec<Tunnel>,
+    /// Server the tunnel being typed is opened on
+    tunnel_server: String,
+    /// Events printed in the output panel, received from
+    /// the event bus of the UI
+    events: EventBus,
+    feed: Receiver<events::Event>,
+    console: Vec<events::Event>,
     render: bool
 }
 
@@ -52,8 +152,25 @@ impl ConsoleCLI {
     const BACKGROUND_COLOR: Color = Color::Rgb(42, 3, 33);
 
     /// Method to construct a new cli with
-    /// the crossterm backend
-    fn new() -> Result<Self, Box<dyn std::error::Error>> {
+    /// the crossterm backend listing the configured
+    /// servers and jobs
+    fn new(inventory: Inventory, jobs: Vec<Job>, params: BTreeMap<String, String>, settings: Settings, user: User) -> Result<Self, Box<dyn std::error::Error>> {
+        if inventory.servers().is_empty() {
+            return Err(format!("No servers configured in {}", Inventory::FILE_NAME).into());
+        }
+        if jobs.is_empty() {
+            return Err(format!("No jobs configured in {}", Job::FILE_NAME).into());
+        }
+
+        // Only the servers and jobs the user can run are listed
+        let access = settings.access.for_user(&user.username);
+        let job_names: Vec<String> = jobs.iter().map(|j| j.name.clone()).collect();
+        let listed_jobs: Vec<&Job> = jobs.iter().filter(|j| access.allows_job(&j.name, inventory.servers())).collect();
+        let listed_servers: Vec<&Target> = inventory.servers().iter().filter(|s| access.allows_server(s, &job_names)).collect();
+        if listed_jobs.is_empty() || listed_servers.is_empty() {
+            return Err(format!("{} is not allowed to run any job", user.username).into());
+        }
+
         let stdout = io::stdout();
         let backend = CrosstermBackend::new(stdout);
         let terminal = Terminal::new(backend)?;
@@ -64,41 +181,44 @@ impl ConsoleCLI {
 
 
         let task_listener = Listener::new(
-            vec![
-                String::from("ping"),
-                String::from("hostname -I"),
-                String::from("ls")
-            ],
-            vec![
-                String::from("Check connectivity"),
-                String::from("Get server IP address"),
-                String::from("List files in current directory")
-            ]
-
+            listed_jobs.iter().map(|j| j.name.clone()).collect(),
+            listed_jobs.iter().map(|j| j.description.clone()).collect()
         );
 
 
         let server_listener = Listener::new(
-            vec![
-                String::from("DELMAIN01"),
-                String::from("DELBACKUP01"),
-                String::from("HRMAIN01")
-            ],
-            vec![
-                String::from("PATH/TO/PEM"),
-                String::from("PATH/TO/PEM"),
-                String::from("PATH/TO/PEM")
-            ]
+            listed_servers.iter().map(|s| s.name.clone()).collect(),
+            listed_servers.iter().map(|s| s.host.clone()).collect()
         );
 
+        let events = EventBus::new();
+        let feed = events.subscribe();
+
         return Ok(ConsoleCLI {
             terminal,
             active_listener_index: 0,
             task_listener: Arc::new(Mutex::new(task_listener)),
             server_listener: Arc::new(Mutex::new(server_listener)),
+            inventory,
+            jobs,
             selected_jobs: Vec::new(),
             selected_servers: Vec::new(),
-            console_text: String::new(),
+            params,
+            pending_params: Vec::new(),
+            input: String::new(),
+            mode: InputMode::Select,
+            plan: Vec::new(),
+            dry_run: false,
+            settings,
+            user,
+            access,
+            violations: Vec::new(),
+            acknowledgement: Acknowledgement::default(),
+            tunnels: Vec::new(),
+            tunnel_server: String::new(),
+            events,
+            feed,
+            console: Vec::new(),
             render: true
         });
     }
@@ -113,7 +233,7 @@ impl ConsoleCLI {
     /// cli.clear();
     /// ```
     fn clear(&mut self) {
-        self.console_text = String::new();
+        self.console.clear();
     }
 
     /// Method to print text to the terminal
@@ -124,16 +244,15 @@ impl ConsoleCLI {
     ///  let mut cli = ConsoleCLI::new();
     ///  cli.print("Hey!");
     /// ```
-    fn print(&mut self, mut text: String) {
-        text.push_str("\n");
-        self.console_text.push_str(&*text);
+    fn print(&mut self, text: String) {
+        self.events.publish(None, EventKind::Message { text });
     }
 
     /// Method to render the UI
     fn render(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         loop {
             // Stop rendering the cli
-            if self.render == false {
+            if !self.render {
                 break Ok(());
             }
 
@@ -150,24 +269,43 @@ impl ConsoleCLI {
                 _ => unimplemented!()
             };
 
-            let terminal_text = self.console_text.clone();
+            // Show the events published since the last frame
+            while let Ok(event) = self.feed.try_recv() {
+                self.console.push(event);
+            }
+            let mut terminal_text: String = self.console.iter().map(|event| format!("{}\n", event)).collect();
+            if self.mode != InputMode::Select && self.mode != InputMode::Confirm {
+                terminal_text.push_str(&format!("> {}", self.input));
+            }
 
             if poll(Duration::from_millis(200))? {
                 match read()? {
+                    Event::Key(event) if self.mode == InputMode::Prompt => {
+                        self.handle_prompt_key(event.code);
+                    },
+                    Event::Key(event) if self.mode == InputMode::Confirm => {
+                        self.handle_confirm_key(event.code);
+                    },
+                    Event::Key(event) if self.mode == InputMode::Guard => {
+                        self.handle_guard_key(event.code);
+                    },
+                    Event::Key(event) if self.mode == InputMode::Tunnel || self.mode == InputMode::CloseTunnel => {
+                        self.handle_tunnel_key(event.code);
+                    },
                     Event::Key(event) => {
                         let key_code = event.code;
 
                         match key_code {
                             KeyCode::Char(' ') => {
 
-                                if self.selected_servers.len() == 0 {
+                                if self.selected_servers.is_empty() {
                                     self.print(
                                         format!(
                                             "Please select atleast 1 server! Selected {}",
                                             self.selected_servers.len()
                                         )
                                     );
-                                } else if self.selected_jobs.len() == 0 {
+                                } else if self.selected_jobs.is_empty() {
                                     self.print(
                                         format!(
                                             "Please select atleast 1 job! Selected {}",
@@ -175,17 +313,50 @@ impl ConsoleCLI {
                                         )
                                     );
                                 } else {
-                                    self.print(
-                                        format!(
-                                            "Executing {} jobs on {} servers!",
-                                            self.selected_jobs.len(),
-                                            self.selected_servers.len()
-                                        )
-                                    );
-                                    // Stop rendering the ui and
-                                    // clear the terminal
-                                    self.render = false;
-                                    ConsoleCLI::clear_screen();
+                                    self.prepare_run();
+                                }
+                            },
+
+                            KeyCode::Char('b') => {
+                                if self.selected_servers.is_empty() {
+                                    self.print(String::from("Please select the servers to broadcast to!"));
+                                } else {
+                                    self.open_broadcast();
+                                }
+                            },
+
+                            KeyCode::Char('t') => {
+                                match server_listener.get_selected().cloned() {
+                                    Some(name) => {
+                                        self.print(format!(
+                                            "Tunnel through {}: -L [bind:]port:host:port, -R port:host:port or -D [bind:]port (Esc to cancel)",
+                                            name
+                                        ));
+                                        self.tunnel_server = name;
+                                        self.mode = InputMode::Tunnel;
+                                    },
+                                    None => self.print(String::from("Highlight a server to open a tunnel through it"))
+                                }
+                            },
+
+                            KeyCode::Char('l') => {
+                                self.list_tunnels();
+                            },
+
+                            KeyCode::Char('x') => {
+                                if self.tunnels.is_empty() {
+                                    self.print(String::from("No tunnels are open"));
+                                } else {
+                                    self.list_tunnels();
+                                    self.print(String::from("Enter the id of the tunnel to close (Esc to cancel)"));
+                                    self.mode = InputMode::CloseTunnel;
+                                }
+                            },
+
+                            KeyCode::Char('s') => {
+                                match server_listener.get_selected().cloned() {
+                                    Some(name) => self.open_shell(&name),
+                                    None => self.print(String::from("Highlight a server to open a shell on it"))
                                 }
                             },
 
@@ -201,33 +372,25 @@ impl ConsoleCLI {
                                 active_listener.next();
                             },
                             KeyCode::Enter => {
-                                let selected_item = match active_listener.get_selected() {
-                                    Some(item) => item,
-                                    None => {  panic!("Could not fetch element"); }
-                                };
-
-                                match self.active_listener_index {
-                                    0 => {
-                                        self.print(format!("Selected server: {}", selected_item));
-                                        self.selected_servers.push(selected_item.to_string());
-                                    },
-                                    1 => {
-                                        self.print(format!("Selected job: {}", selected_item));
-                                        self.selected_jobs.push(selected_item.to_string());
-                                    },
-                                    _ => panic!("Ye kaise hogaya?")
-                                };
-
+                                // Nothing is highlighted yet, so there is nothing to select
+                                if let Some(selected_item) = active_listener.get_selected() {
+                                    match self.active_listener_index {
+                                        0 => {
+                                            self.print(format!("Selected server: {}", selected_item));
+                                            self.selected_servers.push(selected_item.to_string());
+                                        },
+                                        1 => {
+                                            self.print(format!("Selected job: {}", selected_item));
+                                            self.selected_jobs.push(selected_item.to_string());
+                                        },
+                                        _ => panic!("Ye kaise hogaya?")
+                                    };
+                                }
                             }
-                            _ => println!("do nothing"),
+                            _ => {}
                         }
                     },
-                    Event::Mouse(event) => {
-                        println!("{:?}", event)
-                    },
-                    Event::Resize(..) => {
-                        println!("Resized!")
-                    }
+                    Event::Mouse(..) | Event::Resize(..) => {}
                 }
             }
 
@@ -296,7 +459,7 @@ impl ConsoleCLI {
                 // Render the footer
                 let text = vec![
                     Spans::from(vec![
-                        Span::raw("Basecamp is an application which allows you to execute shell jobs on multiple servers directly from your local machine. Update the server.yaml file to configure the servers and job.yaml file to configure the jobs!"),
+                        Span::raw("Basecamp is an application which allows you to execute shell jobs on multiple servers directly from your local machine. Update the server.yaml file to configure the servers and job.yaml file to configure the jobs! Press s to open a shell on the highlighted server, b to type commands on all the selected servers at once, t to open a tunnel through the highlighted server, l to list the tunnels and x to close one."),
                     ]),
                 ];
 
@@ -313,6 +476,362 @@ impl ConsoleCLI {
 
     }
 
+    /// Method to suspend the UI while an interactive shell
+    /// runs on the server, and to restore it once the shell exits
+    fn open_shell(&mut self, name: &str) {
+        let target = match self.inventory.resolve(name) {
+            Some(target) => target,
+            None => return self.print(format!("Unknown server {}", name))
+        };
+        if !self.access.allows(&target, Access::SHELL) {
+            return self.print(format!("Denied: {}", self.access.denial(&target, Access::SHELL)));
+        }
+        if let Err(err) = ConsoleCLI::audit_session(&self.user, &target, Access::SHELL, "interactive shell") {
+            return self.print(format!("Could not write the audit log: {}", err));
+        }
+
+        self.suspend();
+        ConsoleCLI::print_line(format!("Connecting to {} ({})...\n", name, target.address()));
+
+        let mut server = Server::new(target);
+        let exit_status = block_on(server.connect()).and_then(|_| server.shell());
+
+        self.resume();
+        match exit_status {
+            Ok(exit_status) => self.print(format!("Shell on {} exited with status {}", name, exit_status)),
+            Err(err) => self.print(format!("Could not open a shell on {}: {}", name, err))
+        }
+    }
+
+    /// Method to suspend the UI while a broadcast session
+    /// runs on the selected servers
+    fn open_broadcast(&mut self) {
+        let targets = match self.access.allowed_targets(self.selected_targets(), Access::BROADCAST) {
+            Ok(targets) => targets,
+            Err(err) => return self.print(format!("Denied: {}", err))
+        };
+        self.suspend();
+        ConsoleCLI::print_line(format!("Connecting to {}...\n", self.selected_servers.join(", ")));
+
+        let guardrails = self.settings.guardrails.clone();
+        let finished = Broadcast::connect(&self.user.username, targets, guardrails, self.inventory.protected_groups())
+            .and_then(|mut broadcast| broadcast.run());
+
+        self.resume();
+        match finished {
+            Ok(_) => self.print(String::from("Broadcast session ended")),
+            Err(err) => self.print(format!("Broadcast session failed: {}", err))
+        }
+    }
+
+    /// Method to hand the terminal over, leaving
+    /// a cleared screen with the default colors
+    fn suspend(&mut self) {
+        let _ = io::stdout().execute(ResetColor);
+        let _ = self.terminal.show_cursor();
+        ConsoleCLI::clear_screen();
+    }
+
+    /// Method to take the terminal back and
+    /// redraw the whole UI
+    fn resume(&mut self) {
+        let _ = io::stdout()
+            .execute(SetBackgroundColor( crossterm::style::Color::from( ConsoleCLI::BACKGROUND_COLOR_HEX ) ) );
+        let _ = self.terminal.clear();
+    }
+
+    /// Returns the selected servers with their group
+    /// variables applied
+    fn selected_targets(&self) -> Vec<Target> {
+        return self.selected_servers.iter()
+            .filter_map(|name| self.inventory.resolve(name))
+            .collect();
+    }
+
+    /// Returns the definitions of the selected jobs
+    fn selected_job_definitions(&self) -> Result<Vec<Job>, Box<dyn std::error::Error>> {
+        let selected: Vec<Job> = self.selected_jobs.iter()
+            .filter_map(|name| self.jobs.iter().find(|j| &j.name == name))
+            .cloned()
+            .collect();
+        // Add the jobs needed by the selected jobs
+        return workflow::with_dependencies(&selected, &self.jobs);
+    }
+
+    /// Method to prepare the execution of the selected jobs.
+    /// Prompts for the parameters which were not passed with
+    /// `--var` before showing the rendered commands
+    fn prepare_run(&mut self) {
+        let jobs = match self.selected_job_definitions() {
+            Ok(jobs) => jobs,
+            Err(err) => {
+                self.print(format!("Error: {}", err));
+                return;
+            }
+        };
+        match Executor::missing_params(&jobs, &self.params) {
+            Ok(missing) if !missing.is_empty() => {
+                self.pending_params = missing;
+                self.mode = InputMode::Prompt;
+                self.print(format!("Enter a value for params.{} (Esc to cancel)", self.pending_params[0]));
+            },
+            Ok(_) => self.confirm_run(),
+            Err(err) => self.print(format!("Error: {}", err))
+        }
+    }
+
+    /// Method to render the selected jobs for every selected
+    /// server and ask for a confirmation
+    fn confirm_run(&mut self) {
+        self.mode = InputMode::Select;
+        let plan = match self.selected_job_definitions().and_then(|jobs| Executor::plan(&self.selected_targets(), &jobs, &self.params)) {
+            Ok(plan) => plan,
+            Err(err) => {
+                self.print(format!("Error: {}", err));
+                return;
+            }
+        };
+
+        let denied = self.access.check(&plan);
+        if !denied.is_empty() {
+            for denial in denied {
+                self.print(format!("Denied: {}", denial));
+            }
+            return;
+        }
+
+        self.print(String::from("The following commands will be executed:"));
+        for assignment in &plan {
+            for job in &assignment.jobs {
+                for command in job.commands() {
+                    self.print(format!("[{}] {}: {}", assignment.target.name, job.name, command));
+                }
+            }
+        }
+        let violations = match self.settings.guardrails.check(&plan, &self.inventory.protected_groups()) {
+            Ok(violations) => violations,
+            Err(err) => {
+                self.print(format!("Error: {}", err));
+                return;
+            }
+        };
+        for violation in &violations {
+            self.print(format!("Warning: {}", violation.message));
+        }
+
+        // Nothing is confirmed nor run, the plan is printed once the UI stops
+        if self.dry_run {
+            self.plan = plan;
+            self.violations = violations;
+            self.render = false;
+            ConsoleCLI::clear_screen();
+            return;
+        }
+
+        match ConsoleCLI::preview_templates(&self.settings, &plan) {
+            Ok(lines) => lines.into_iter().for_each(|line| self.print(line)),
+            Err(err) => {
+                self.print(format!("Error: {}", err));
+                return;
+            }
+        }
+        self.print(String::from("Press y to execute or n to cancel"));
+        self.plan = plan;
+        self.violations = violations;
+        self.acknowledgement = Acknowledgement::default();
+        self.mode = InputMode::Confirm;
+    }
+
+    /// Method to start the confirmed run, or ask for the
+    /// confirmation of the next matched guardrail
+    fn start_run(&mut self) {
+        if let Some(violation) = self.violations.first() {
+            let text = format!("{}. Type `{}` to confirm (Esc to cancel)", violation.message, violation.confirmation);
+            self.print(text);
+            self.mode = InputMode::Guard;
+            return;
+        }
+
+        self.print(
+            format!(
+                "Executing {} jobs on {} servers!",
+                self.selected_jobs.len(),
+                self.selected_servers.len()
+            )
+        );
+        // Stop rendering the ui and
+        // clear the terminal
+        self.render = false;
+        ConsoleCLI::clear_screen();
+    }
+
+    /// Method to handle a key press while the confirmation
+    /// of a guardrail is being typed
+    fn handle_guard_key(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            },
+            KeyCode::Esc => {
+                self.cancel_run();
+            },
+            KeyCode::Enter => {
+                let typed = std::mem::take(&mut self.input);
+                let violation = self.violations.remove(0);
+                if typed != violation.confirmation {
+                    self.print(format!("> {}", typed));
+                    self.cancel_run();
+                    return;
+                }
+                self.print(format!("> {}", typed));
+                self.acknowledgement.violations.push(violation.message);
+                self.start_run();
+            },
+            _ => {}
+        }
+    }
+
+    /// Method to handle a key press while the spec of a tunnel
+    /// or the id of the tunnel to close is typed
+    fn handle_tunnel_key(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            },
+            KeyCode::Esc => {
+                self.input.clear();
+                self.mode = InputMode::Select;
+                self.print(String::from("Cancelled"));
+            },
+            KeyCode::Enter => {
+                let typed = std::mem::take(&mut self.input);
+                self.print(format!("> {}", typed));
+                if self.mode == InputMode::Tunnel {
+                    self.open_tunnel(&typed);
+                } else {
+                    self.close_tunnel(&typed);
+                }
+                self.mode = InputMode::Select;
+            },
+            _ => {}
+        }
+    }
+
+    /// Method to open a tunnel through the server
+    /// chosen when the tunnel was requested
+    fn open_tunnel(&mut self, line: &str) {
+        let spec = match TunnelSpec::parse_line(line) {
+            Ok(spec) => spec,
+            Err(err) => return self.print(format!("Error: {}", err))
+        };
+        let target = match self.inventory.resolve(&self.tunnel_server) {
+            Some(target) => target,
+            None => return self.print(format!("Unknown server {}", self.tunnel_server))
+        };
+        if !self.access.allows(&target, Access::TUNNEL) {
+            return self.print(format!("Denied: {}", self.access.denial(&target, Access::TUNNEL)));
+        }
+
+        if let Err(err) = ConsoleCLI::audit_session(&self.user, &target, Access::TUNNEL, &spec.to_string()) {
+            return self.print(format!("Could not write the audit log: {}", err));
+        }
+
+        let id = self.tunnels.iter().map(|t| t.id).max().unwrap_or(0) + 1;
+        match Tunnel::open(id, target, spec) {
+            Ok(tunnel) => {
+                self.print(format!("Opened tunnel {} through {}: {}", tunnel.id, tunnel.server, tunnel.spec));
+                self.tunnels.push(tunnel);
+            },
+            Err(err) => self.print(format!("Could not open the tunnel through {}: {}", self.tunnel_server, err))
+        }
+    }
+
+    /// Method to close the tunnel with the typed id
+    fn close_tunnel(&mut self, typed: &str) {
+        let index = typed.trim().parse::<usize>().ok()
+            .and_then(|id| self.tunnels.iter().position(|t| t.id == id));
+        match index {
+            Some(index) => {
+                let mut tunnel = self.tunnels.remove(index);
+                tunnel.close();
+                self.print(format!("Closed tunnel {}", tunnel.id));
+            },
+            None => self.print(format!("No tunnel has the id `{}`", typed.trim()))
+        }
+    }
+
+    /// Method to print the tunnels opened from the UI
+    fn list_tunnels(&mut self) {
+        if self.tunnels.is_empty() {
+            return self.print(String::from("No tunnels are open"));
+        }
+        let lines: Vec<String> = self.tunnels.iter().map(|tunnel| {
+            let state = match tunnel.error() {
+                Some(err) => format!("failed: {}", err),
+                None => format!("open, {} forwarded", tunnel.connections())
+            };
+            return format!("[{}] {} {} ({})", tunnel.id, tunnel.server, tunnel.spec, state);
+        }).collect();
+        for line in lines {
+            self.print(line);
+        }
+    }
+
+    /// Method to drop the plan waiting for confirmation
+    fn cancel_run(&mut self) {
+        self.plan.clear();
+        self.violations.clear();
+        self.input.clear();
+        self.mode = InputMode::Select;
+        self.print(String::from("Cancelled"));
+    }
+
+    /// Method to handle a key press while a parameter
+    /// is being prompted for
+    fn handle_prompt_key(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            },
+            KeyCode::Esc => {
+                self.pending_params.clear();
+                self.input.clear();
+                self.mode = InputMode::Select;
+                self.print(String::from("Cancelled"));
+            },
+            KeyCode::Enter => {
+                let name = self.pending_params.remove(0);
+                let value = std::mem::take(&mut self.input);
+                self.print(format!("> {}", value));
+                self.params.insert(name, value);
+
+                if self.pending_params.is_empty() {
+                    self.confirm_run();
+                } else {
+                    self.print(format!("Enter a value for params.{} (Esc to cancel)", self.pending_params[0]));
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// Method to handle a key press while the rendered
+    /// commands are waiting for a confirmation
+    fn handle_confirm_key(&mut self, key_code: KeyCode) {
+        match key_code {
+            KeyCode::Char('y') => {
+                self.start_run();
+            },
+            KeyCode::Char('n') | KeyCode::Esc => {
+                self.cancel_run();
+            },
+            _ => {}
+        }
+    }
+
     /// Method to print some text to the Console
     ///
     /// # Examples
@@ -340,79 +859,591 @@ impl ConsoleCLI {
     /// Method to execute the selected jobs on the
     /// selected servers asynchrouslly
     pub async fn execute_jobs(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let plan = std::mem::take(&mut self.plan);
+        let acknowledgement = std::mem::take(&mut self.acknowledgement);
+        let jobs = self.selected_job_definitions()?;
+        ConsoleCLI::run(&self.user, &self.events, plan, jobs, self.params.clone(), None, acknowledgement).await?;
+        return Ok(());
+    }
 
-        let mut user = User::new();
+    /// Method to run the planned jobs, display the results
+    /// and store the run in the history. The progress of the
+    /// run is printed from the events it publishes on the bus
+    async fn run(user: &User, events: &EventBus, plan: Vec<Assignment>, jobs: Vec<Job>, params: BTreeMap<String, String>, rerun_of: Option<String>, acknowledgement: Acknowledgement) -> Result<Run, Box<dyn std::error::Error>> {
+        let settings = Settings::load_default()?;
 
-        // Authenticate the user
-        let authenticated = user.authenticate().await?;
-        if authenticated {
-            println!("You have been logged in!");
-        } else {
-            println!("User not found (Running on TEST user)");
-        }
-        // Configuration for the thread pool
-        const NUM_WORKERS: usize = 5;
+        // Created before the progress is printed, as it may prompt for the password
+        let mut executor = ConsoleCLI::executor(&settings, user, &plan, &jobs, true)?;
 
-        // Create the jobs
-        let num_jobs: usize = self.selected_jobs.len();
+        let events = RunEvents::new(events.clone(), &Run::generate_id(&Utc::now()));
+        let progress = ConsoleCLI::follow(events.clone());
+        let mut run = ConsoleCLI::execute(&mut executor, events, user, plan, jobs, params, acknowledgement);
+        run.rerun_of = rerun_of;
+        let _ = progress.join();
+        ConsoleCLI::print_new_line();
 
-        // Holds the results of the jobs
-        let job_results = Arc::new(Mutex::new( Vec::with_capacity(num_jobs) ) );
+        // Display the results in the table
+        ConsoleCLI::display_table(&run.results);
 
-        // Receive the tasks from the user
-        // for _ in 0..NUM_JOBS {
-        //     let mut job_task = String::new();
-        //     io::stdin().read_line(&mut job_task)
-        //         .expect("Failed to read job");
-        //     job_task = job_task.parse().expect("Failed to parse job!");
-        //     task_list.push(job_task);
-        // }
+        ConsoleCLI::record(&settings, &run).await?;
+        ConsoleCLI::print_line(format!("Run {} saved to history\n", run.id));
+        return Ok(run);
+    }
 
-        // Show the loading text
-        let tx = ConsoleCLI::load(format!("Executing {} jobs", num_jobs));
+    /// Method to create the executor of the planned jobs,
+    /// checking first that the user can run all of them. Without
+    /// `prompt`, jobs running as another user need the become
+    /// password to be set in the environment or in its file
+    fn executor(settings: &Settings, user: &User, plan: &[Assignment], jobs: &[Job], prompt: bool) -> Result<Executor, Box<dyn std::error::Error>> {
+        let access = settings.access.for_user(&user.username);
+        let denied = access.check(plan);
+        if !denied.is_empty() {
+            return Err(denied.join("\n").into());
+        }
 
-        // Create a thread pool to run the SSH jobs in parallel
-        let pool = ThreadPool::new(NUM_WORKERS);
+        // The password is only asked for when a job runs as another user
+        let mut executor = Executor::new(Executor::NUM_WORKERS);
+        executor.set_access(access);
+        executor.set_guardrails(settings.guardrails.clone());
+        let escalating: Vec<&str> = jobs.iter().filter(|j| j.escalate).map(|j| j.name.as_str()).collect();
+        if !escalating.is_empty() {
+            let password = if prompt {
+                settings.escalation.password()?
+            } else {
+                Some(settings.escalation.stored_password()?.ok_or_else(|| format!(
+                    "Jobs {} run as another user and there is no terminal to ask for the become password: set {} or become.password_file",
+                    escalating.join(", "),
+                    escalation::PASSWORD_ENV
+                ))?)
+            };
+            if let Some(password) = password {
+                executor.set_become_password(password);
+            }
+        }
+        return Ok(executor);
+    }
+
+    /// Method to print the progress of the run as its events
+    /// are published, until it is over. The lines printed by
+    /// the jobs are left for the table of the results
+    fn follow(events: RunEvents) -> thread::JoinHandle<()> {
+        let feed = events.subscribe();
+        return thread::spawn(move || {
+            for event in feed.iter().filter(|e| e.run.as_deref() == Some(events.run_id())) {
+                match event.kind {
+                    EventKind::Output { .. } => continue,
+                    EventKind::RunFinished { .. } => {
+                        println!("{}", event);
+                        break;
+                    },
+                    _ => println!("{}", event)
+                }
+            }
+        });
+    }
+
+    /// Method to run the planned jobs and build the run,
+    /// publishing its progress to the events
+    fn execute(executor: &mut Executor, events: RunEvents, user: &User, plan: Vec<Assignment>, jobs: Vec<Job>, params: BTreeMap<String, String>, acknowledgement: Acknowledgement) -> Run {
+        let started_at = Utc::now();
+        let id = events.run_id().to_string();
+        executor.set_events(events);
 
         // Start the timer to time the duration for all the jobs
         // to be completed
         let timer = Timer::new();
 
-        // Execute the jobs using worker threads
-        for i in 0..num_jobs {
-            // Get the task for the current job
-            let job_task = self.selected_jobs[i].clone();
-            // Make a clone of the results
-            let clone = Arc::clone(&job_results);
-            pool.execute( move || {
-                // Get the vector storing the results
-                let mut result_vec = clone.lock().unwrap();
-                // Connect to the server and execute the SSH job
-                let mut server = Server::new();
-                block_on(server.connect()).unwrap();
-
-                // Create the new job for the worker thread
-                // and execute the job
-                let job = Job::new(job_task);
-                let res = block_on(server.execute(&job)).unwrap();
-                result_vec.push(String::from(res));
-            });
-        }
-
-        // Make a blocking call to wait for all the jobs to be
-        // completed
-        pool.join();
-
-        // Terminate the loading screen thread
-        let _ = tx.send(true);
-        ConsoleCLI::delete_prev_line();
-        ConsoleCLI::print_line(format!("Finished Jobs in {}s\n", timer.ellapsed().as_secs()));
-        drop(timer);
+        let results = executor.run(&plan);
+        let ellapsed = timer.ellapsed();
+
+        return Run {
+            id,
+            rerun_of: None,
+            schedule: None,
+            user: user.username.clone(),
+            started_at,
+            duration_ms: ellapsed.as_millis() as u64,
+            targets: plan.into_iter().map(|a| a.target).collect(),
+            jobs,
+            params,
+            guardrails: acknowledgement.violations,
+            override_reason: acknowledgement.override_reason,
+            results
+        };
+    }
 
-        // Display the results in the table
-        ConsoleCLI::display_table(&*Arc::clone(&job_results).lock().unwrap());
-        drop(job_results);
+    /// Method to record who ran what in the audit log, store
+    /// the run in the history and notify the webhooks
+    async fn record(settings: &Settings, run: &Run) -> Result<(), Box<dyn std::error::Error>> {
+        AuditLog::open_default().append(AuditRecord::from_run(run))?;
+        let path = History::open_default().save(run)?;
+        webhooks::notify(&settings.webhooks, &settings.http, run, &path).await;
+        return Ok(());
+    }
+
+    /// Method to run a schedule of the daemon. The inventory is
+    /// loaded for every run, as servers come and go between runs
+    async fn run_schedule(user: &User, events: &EventBus, schedule: &Schedule) -> Result<Run, Box<dyn std::error::Error>> {
+        let settings = Settings::load_default()?;
+        let notices: http::Notices = Arc::new(|message: &str| schedule::log(message));
+        let inventory = sources::load_inventory(&settings, &user.token, &notices).await?;
+        let catalog = Job::load_all(&paths::config_dir().join(Job::FILE_NAME))?;
+
+        let targets = inventory.select(&schedule.targets)?;
+        let jobs = ConsoleCLI::schedule_jobs(schedule, &catalog)?;
+        let plan = Executor::plan(&targets, &jobs, &schedule.vars)?;
+        let violations = settings.guardrails.check(&plan, &inventory.protected_groups())?;
+        let options = RunOptions {
+            dry_run: false,
+            json: false,
+            assume_yes: true,
+            override_reason: schedule.override_reason.clone()
+        };
+        let acknowledgement = match ConsoleCLI::enforce_guardrails(violations, &options)? {
+            Some(acknowledgement) => acknowledgement,
+            None => return Err(String::from("The guardrails were not confirmed").into())
+        };
+
+        let mut executor = ConsoleCLI::executor(&settings, user, &plan, &jobs, false)?;
+        let events = RunEvents::new(events.clone(), &Run::generate_id(&Utc::now()));
+        let mut run = ConsoleCLI::execute(&mut executor, events, user, plan, jobs, schedule.vars.clone(), acknowledgement);
+        run.schedule = Some(schedule.name.clone());
+        ConsoleCLI::record(&settings, &run).await?;
+        return Ok(run);
+    }
+
+    /// Method to get the jobs of the schedule with the jobs
+    /// they need, checking that their parameters are set
+    fn schedule_jobs(schedule: &Schedule, catalog: &[Job]) -> Result<Vec<Job>, Box<dyn std::error::Error>> {
+        let mut selected = Vec::with_capacity(schedule.jobs.len());
+        for name in &schedule.jobs {
+            match catalog.iter().find(|j| &j.name == name) {
+                Some(job) => selected.push(job.clone()),
+                None => return Err(format!("Unknown job {} in schedule {}", name, schedule.name).into())
+            }
+        }
+        let jobs = workflow::with_dependencies(&selected, catalog)?;
+
+        let missing = Executor::missing_params(&jobs, &schedule.vars)?;
+        if !missing.is_empty() {
+            return Err(format!("Schedule {} must set the vars {}", schedule.name, missing.join(", ")).into());
+        }
+        return Ok(jobs);
+    }
+
+    /// Method to run the schedules of `schedule.yaml` until the
+    /// process is interrupted. The user logs in once, the runs
+    /// are made as that user
+    async fn daemon() -> Result<(), Box<dyn std::error::Error>> {
+        let schedules = ScheduleFile::load(&paths::config_dir().join(ScheduleFile::FILE_NAME))?.schedules;
+        if schedules.is_empty() {
+            return Err(format!("No schedules are configured in {}", ScheduleFile::FILE_NAME).into());
+        }
+        let catalog = Job::load_all(&paths::config_dir().join(Job::FILE_NAME))?;
+        for schedule in &schedules {
+            ConsoleCLI::schedule_jobs(schedule, &catalog)?;
+        }
+        let user = Arc::new(User::login().await?);
 
+        let mut daemon = Daemon::new(schedules)?;
+        for (name, next) in daemon.upcoming() {
+            match next {
+                Some(next) => println!("{}: next run at {}", name, next.format("%Y-%m-%d %H:%M:%S")),
+                None => println!("{}: no upcoming run", name)
+            }
+        }
+
+        // The progress of the runs is logged from their events
+        let events = EventBus::new();
+        let feed = events.subscribe();
+        thread::spawn(move || {
+            for event in feed.iter().filter(|e| e.name() != "output") {
+                schedule::log(&format!("{} {}", event.run.as_deref().unwrap_or_default(), event));
+            }
+        });
+
+        // Every run gets its own runtime, as runs are made in
+        // their own thread and may outlast a tick of the daemon
+        daemon.run(Arc::new(move |schedule: &Schedule| {
+            let mut runtime = tokio::runtime::Runtime::new().map_err(|err| err.to_string())?;
+            return runtime.block_on(ConsoleCLI::run_schedule(&user, &events, schedule))
+                .map(|run| run.id)
+                .map_err(|err| err.to_string());
+        }));
+        return Ok(());
+    }
+
+    /// Method to run a run started through the API, letting
+    /// the API follow its results and cancel it
+    async fn run_prepared(prepared: Prepared, control: RunControl) -> Result<Run, Box<dyn std::error::Error>> {
+        let settings = Settings::load_default()?;
+        let user = User { username: prepared.user, ..User::new() };
+        let mut executor = ConsoleCLI::executor(&settings, &user, &prepared.plan, &prepared.jobs, false)?;
+        executor.set_cancel(control.cancel);
+
+        let run = ConsoleCLI::execute(&mut executor, control.events, &user, prepared.plan, prepared.jobs, prepared.params, prepared.acknowledgement);
+        ConsoleCLI::record(&settings, &run).await?;
+        return Ok(run);
+    }
+
+    /// Method to serve the REST API until the process is
+    /// interrupted. The user starting the server logs in once,
+    /// the inventory is fetched as that user
+    async fn serve(bind: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut settings = Settings::load_default()?;
+        if let Some(bind) = bind {
+            settings.api.bind = bind.to_string();
+        }
+        let user = User::login().await?;
+
+        // Every run gets its own runtime, as runs are made in their own thread
+        return api::serve(settings, &user.token, Arc::new(|prepared: Prepared, control: RunControl| {
+            let mut runtime = tokio::runtime::Runtime::new().map_err(|err| err.to_string())?;
+            return runtime.block_on(ConsoleCLI::run_prepared(prepared, control)).map_err(|err| err.to_string());
+        })).await;
+    }
+
+    /// Method to read a line from the standard input
+    fn prompt(text: &str) -> Result<String, Box<dyn std::error::Error>> {
+        ConsoleCLI::print_line(text);
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        return Ok(line.trim().to_string());
+    }
+
+    /// Method to print the rendered commands and the diffs of
+    /// the templates, and ask the user to confirm them on the
+    /// standard input
+    fn confirm_plan(settings: &Settings, plan: &[Assignment]) -> Result<bool, Box<dyn std::error::Error>> {
+        println!("The following commands will be executed:");
+        for assignment in plan {
+            for job in &assignment.jobs {
+                for command in job.commands() {
+                    println!("[{}] {}: {}", assignment.target.name, job.name, command);
+                }
+            }
+        }
+        for line in ConsoleCLI::preview_templates(settings, plan)? {
+            println!("{}", line);
+        }
+        let answer = ConsoleCLI::prompt("Execute? [y/N] ")?;
+        return Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"));
+    }
+
+    /// Method to run the jobs of a previous run again. With
+    /// `failed_only` only the servers on which a job failed,
+    /// timed out or could not be reached are targeted. The
+    /// parameters of the previous run can be overridden
+    async fn rerun(run_id: &str, failed_only: bool, vars: BTreeMap<String, String>, options: RunOptions) -> Result<(), Box<dyn std::error::Error>> {
+        let previous = History::open_default().load(run_id)?;
+
+        // The servers of the run may belong to protected groups
+        // of the inventory service, queried with the token of the user
+        let user = User::login().await?;
+        let settings = Settings::load_default()?;
+        let inventory = sources::load_inventory(&settings, &user.token, &http::stderr_notices()).await?;
+
+        let targets: Vec<Target> = if failed_only {
+            let failed = previous.failed_servers();
+            previous.targets.iter()
+                .filter(|t| failed.contains(&t.name))
+                .cloned()
+                .collect()
+        } else {
+            previous.targets.clone()
+        };
+
+        if targets.is_empty() {
+            ConsoleCLI::print_line(format!("Nothing to re-run, every job of run {} succeeded\n", previous.id));
+            return Ok(());
+        }
+
+        let mut params = previous.params.clone();
+        params.extend(vars);
+        for name in Executor::missing_params(&previous.jobs, &params)? {
+            let value = ConsoleCLI::prompt(&format!("Enter a value for params.{}: ", name))?;
+            params.insert(name, value);
+        }
+
+        let plan = Executor::plan(&targets, &previous.jobs, &params)?;
+        let violations = settings.guardrails.check(&plan, &inventory.protected_groups())?;
+        if options.dry_run {
+            return ConsoleCLI::print_plan(&settings, &plan, &violations, options.json);
+        }
+        if !options.assume_yes && !ConsoleCLI::confirm_plan(&settings, &plan)? {
+            println!("Cancelled");
+            return Ok(());
+        }
+        let acknowledgement = match ConsoleCLI::enforce_guardrails(violations, &options)? {
+            Some(acknowledgement) => acknowledgement,
+            None => {
+                println!("Cancelled");
+                return Ok(());
+            }
+        };
+
+        ConsoleCLI::run(&user, &EventBus::new(), plan, previous.jobs.clone(), params, Some(previous.id), acknowledgement).await?;
+        return Ok(());
+    }
+
+    /// Method to run jobs on servers selected from the command
+    /// line. Servers are selected by name, `group:NAME`, `tag:NAME`
+    /// or `all`, the jobs they need are added to the selected jobs
+    async fn run_selected(selectors: &[String], job_names: &[String], mut params: BTreeMap<String, String>, options: RunOptions) -> Result<(), Box<dyn std::error::Error>> {
+        // The user logs in first, the inventory service
+        // is queried with their token
+        let user = User::login().await?;
+        let settings = Settings::load_default()?;
+        let inventory = sources::load_inventory(&settings, &user.token, &http::stderr_notices()).await?;
+        let catalog = Job::load_all(&paths::config_dir().join(Job::FILE_NAME))?;
+
+        let targets = inventory.select(selectors)?;
+        let mut selected = Vec::with_capacity(job_names.len());
+        for name in job_names {
+            match catalog.iter().find(|j| &j.name == name) {
+                Some(job) => selected.push(job.clone()),
+                None => return Err(format!("Unknown job {}", name).into())
+            }
+        }
+        let jobs = workflow::with_dependencies(&selected, &catalog)?;
+
+        for name in Executor::missing_params(&jobs, &params)? {
+            let value = ConsoleCLI::prompt(&format!("Enter a value for params.{}: ", name))?;
+            params.insert(name, value);
+        }
+
+        let plan = Executor::plan(&targets, &jobs, &params)?;
+        let violations = settings.guardrails.check(&plan, &inventory.protected_groups())?;
+        if options.dry_run {
+            return ConsoleCLI::print_plan(&settings, &plan, &violations, options.json);
+        }
+        if !options.assume_yes && !ConsoleCLI::confirm_plan(&settings, &plan)? {
+            println!("Cancelled");
+            return Ok(());
+        }
+        let acknowledgement = match ConsoleCLI::enforce_guardrails(violations, &options)? {
+            Some(acknowledgement) => acknowledgement,
+            None => {
+                println!("Cancelled");
+                return Ok(());
+            }
+        };
+
+        ConsoleCLI::run(&user, &EventBus::new(), plan, jobs, params, None, acknowledgement).await?;
+        return Ok(());
+    }
+
+    /// Method to have the matched guardrails confirmed by typing
+    /// their confirmation text. Runs without confirmation (`--yes`)
+    /// are refused unless overridden with a reason. Returns `None`
+    /// if a confirmation was mistyped
+    fn enforce_guardrails(violations: Vec<Violation>, options: &RunOptions) -> Result<Option<Acknowledgement>, Box<dyn std::error::Error>> {
+        let messages: Vec<String> = violations.iter().map(|v| v.message.clone()).collect();
+        if violations.is_empty() || options.override_reason.is_some() {
+            return Ok(Some(Acknowledgement { violations: messages, override_reason: options.override_reason.clone() }));
+        }
+        if options.assume_yes {
+            return Err(format!(
+                "Refusing to run without a confirmation: {}. Pass --override with a --reason to run anyway",
+                messages.join("; ")
+            ).into());
+        }
+
+        for violation in &violations {
+            println!("Warning: {}", violation.message);
+            let typed = ConsoleCLI::prompt(&format!("Type `{}` to confirm: ", violation.confirmation))?;
+            if typed != violation.confirmation {
+                return Ok(None);
+            }
+        }
+        return Ok(Some(Acknowledgement { violations: messages, override_reason: None }));
+    }
+
+    /// Method to compare the templates of the plan with the remote
+    /// files, returning the lines of their diffs. Files only readable
+    /// as another user are read with the stored become password
+    fn preview_templates(settings: &Settings, plan: &[Assignment]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let password = settings.escalation.stored_password()?;
+        let mut lines = Vec::new();
+        for assignment in plan {
+            for (job, diff) in assignment.jobs.iter().zip(assignment.preview(password.as_ref())) {
+                if let Some(diff) = diff {
+                    lines.push(format!("[{}] {}:", assignment.target.name, job.name));
+                    lines.extend(diff.lines().map(String::from));
+                }
+            }
+        }
+        return Ok(lines);
+    }
+
+    /// Method to print how every server would be connected to,
+    /// the commands which would run there and the diffs of the
+    /// templates, as a table or as json. Nothing is executed
+    fn print_plan(settings: &Settings, plan: &[Assignment], violations: &[Violation], json: bool) -> Result<(), Box<dyn std::error::Error>> {
+        // Warnings go to stderr to keep the json output valid
+        for violation in violations {
+            eprintln!("Warning: {} (confirm by typing `{}`)", violation.message, violation.confirmation);
+        }
+
+        let password = settings.escalation.stored_password()?;
+        let mut planned: Vec<_> = plan.iter().map(|a| a.describe()).collect();
+        for (target, assignment) in planned.iter_mut().zip(plan) {
+            for (job, diff) in target.jobs.iter_mut().zip(assignment.preview(password.as_ref())) {
+                job.diff = diff;
+            }
+        }
+        if json {
+            println!("{}", serde_json::to_string_pretty(&planned)?);
+            return Ok(());
+        }
+
+        let mut table: Vec<Vec<CellStruct>> = Vec::new();
+        for target in &planned {
+            let jump = if target.jump_hosts.is_empty() { String::from("-") } else { target.jump_hosts.join(" -> ") };
+            for job in &target.jobs {
+                table.push(vec![
+                    (&target.server).cell(),
+                    format!("{}@{}:{}", target.user, target.host, target.port).cell(),
+                    jump.as_str().cell(),
+                    (&target.auth).cell(),
+                    (&job.job).cell(),
+                    job.commands.join("\n").cell()
+                ]);
+            }
+        }
+
+        let table_struct = table.table()
+            .title(vec![
+                "Server".cell().bold(true),
+                "Connection".cell().bold(true),
+                "Jump hosts".cell().bold(true),
+                "Auth".cell().bold(true),
+                "Job".cell().bold(true),
+                "Commands".cell().bold(true)
+            ])
+            .bold(true);
+        print_stdout(table_struct)?;
+        for target in &planned {
+            for job in &target.jobs {
+                if let Some(diff) = &job.diff {
+                    println!("[{}] {}:", target.server, job.job);
+                    print!("{}", diff);
+                }
+            }
+        }
+        println!("Dry run, nothing was executed");
+        return Ok(());
+    }
+
+    /// Method to compare two stored runs and print
+    /// what changed per server
+    fn diff(from: &str, to: &str, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let history = History::open_default();
+        let run_diff = diff::compare(&history.load(from)?, &history.load(to)?);
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&run_diff)?);
+            return Ok(());
+        }
+
+        // Formats an optional value, using `-` for missing values
+        fn describe<T: std::fmt::Display>(value: Option<T>) -> String {
+            return value.map(|v| v.to_string()).unwrap_or_else(|| String::from("-"));
+        }
+
+        for target in &run_diff.targets {
+            if !target.changed() {
+                println!("{} / {}: unchanged", target.server, target.job);
+                continue;
+            }
+
+            let mut changes: Vec<String> = Vec::new();
+            if target.reachability_changed {
+                changes.push(format!("status {} -> {}", describe(target.before), describe(target.after)));
+            }
+            if target.exit_status_changed {
+                changes.push(format!("exit status {} -> {}", describe(target.before_exit_status), describe(target.after_exit_status)));
+            }
+            if target.output_changed {
+                changes.push(String::from("output changed"));
+            }
+            println!("{} / {}: {}", target.server, target.job, changes.join(", "));
+            print!("{}", target.diff);
+        }
+        return Ok(());
+    }
+
+    /// Method to open tunnels through a server and keep
+    /// them open until the process is interrupted
+    async fn tunnel(server: &str, specs: Vec<TunnelSpec>) -> Result<(), Box<dyn std::error::Error>> {
+        let user = User::login().await?;
+        let settings = Settings::load_default()?;
+        let inventory = sources::load_inventory(&settings, &user.token, &http::stderr_notices()).await?;
+        let target = match inventory.resolve(server) {
+            Some(target) => target,
+            None => return Err(format!("Unknown server {}", server).into())
+        };
+        let target = settings.access.for_user(&user.username).allowed_targets(vec![target], Access::TUNNEL)?.remove(0);
+
+        let mut tunnels = Vec::with_capacity(specs.len());
+        for (i, spec) in specs.into_iter().enumerate() {
+            ConsoleCLI::audit_session(&user, &target, Access::TUNNEL, &spec.to_string())?;
+            let tunnel = Tunnel::open(i + 1, target.clone(), spec)?;
+            println!("Forwarding {} through {}", tunnel.spec, tunnel.server);
+            tunnels.push(tunnel);
+        }
+        println!("Press Ctrl-C to close the tunnels");
+
+        while tunnels.iter().any(|t| t.is_open()) {
+            thread::sleep(Duration::from_millis(500));
+        }
+        let errors: Vec<String> = tunnels.iter().filter_map(|t| t.error()).collect();
+        return Err(errors.join("\n").into());
+    }
+
+    /// Method to write the opening of an interactive session
+    /// (a shell or a tunnel) on the target to the audit log
+    fn audit_session(user: &User, target: &Target, kind: &str, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let id = Run::generate_id(&Utc::now());
+        let record = AuditRecord::from_session(&user.username, &id, std::slice::from_ref(target), kind, command, Vec::new());
+        AuditLog::open_default().append(record)?;
+        return Ok(());
+    }
+
+    /// Method to verify the hash chain of the audit log
+    fn verify_audit_log() -> Result<(), Box<dyn std::error::Error>> {
+        let log = AuditLog::open_default();
+        match log.verify() {
+            Ok((count, head)) => {
+                println!("Audit log {} is intact: {} entries, head {}", log.path().display(), count, head);
+                return Ok(());
+            },
+            Err(err) => {
+                return Err(format!("Audit log {} has been tampered with: {}", log.path().display(), err).into());
+            }
+        }
+    }
+
+    /// Method to add a user to the users file of the `file`
+    /// authentication backend, or change their password
+    fn add_user(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = match Settings::load_default()?.auth.backend {
+            auth::Backend::File { path } => auth::users_file(path.as_deref()),
+            _ => return Err("Users can only be added with the `file` authentication backend".into())
+        };
+
+        let password = rpassword::prompt_password(format!("Password of {}: ", name))?;
+        if password.is_empty() {
+            return Err("The password can not be empty".into());
+        }
+        if rpassword::prompt_password("Repeat the password: ")? != password {
+            return Err("The passwords do not match".into());
+        }
+
+        let mut users = UsersFile::load(&path)?;
+        users.set_password(name, &password)?;
+        users.save(&path)?;
+        println!("Saved {} to {}", name, path.display());
         return Ok(());
     }
 
@@ -428,7 +1459,7 @@ impl ConsoleCLI {
             let mut i : u8 = 1;
             loop {
                 ConsoleCLI::print_line(".");
-                if i % 4 == 0 {
+                if i.is_multiple_of(4) {
                     ConsoleCLI::print_line("\x08\x08\x08\x08");
                     ConsoleCLI::print_line("    ");
                     ConsoleCLI::print_line("\x08\x08\x08\x08");
@@ -449,18 +1480,35 @@ impl ConsoleCLI {
     }
 
 
-    fn display_table<T>(data: &Vec<T>)
-    where T : std::fmt::Display {
+    fn display_table(data: &[JobResult]) {
         let num_rows = data.len();
         let mut table: Vec<Vec<CellStruct>>= Vec::with_capacity(num_rows);
         for (i, v) in data.iter().enumerate() {
-            let row  = vec![i.cell().justify(Justify::Right), v.cell().justify(Justify::Right)];
+            let result = match &v.error {
+                Some(err) => err.clone(),
+                None => v.output.trim_end().to_string()
+            };
+            let status = match v.changed {
+                Some(true) => format!("{} (changed)", v.status),
+                Some(false) => format!("{} (unchanged)", v.status),
+                None => v.status.to_string()
+            };
+            let row  = vec![
+                i.cell().justify(Justify::Right),
+                (&v.server).cell(),
+                (&v.job).cell(),
+                status.cell(),
+                result.cell().justify(Justify::Right)
+            ];
             table.push(row);
         }
 
         let table_struct = table.table()
             .title(vec![
                 "Job #".cell().bold(true),
+                "Server".cell().bold(true),
+                "Job".cell().bold(true),
+                "Status".cell().bold(true),
                 "Result".cell().bold(true)
             ])
             .bold(true);
@@ -469,92 +1517,10 @@ impl ConsoleCLI {
     }
 }
 
-struct Server {
-    session: Option<Session>,
-}
-
-impl Server {
-    fn new() -> Self {
-        return Server {
-            session: Option::None
-        };
-    }
-
-    /// Method to connect (SSH) to a remote server using PEM encoded key asynchronously
-    ///
-    /// # Examples
-    /// ```no_run
-    /// let server = Server::new();
-    /// let connected = server.connect().await?;
-    ///
-    /// if connected {
-    ///     println!("Connected to server!");
-    /// }
-    /// ```
-    async fn connect(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
-        // Connect to the remote SSH server
-        let tcp_stream = TcpStream::connect("ec2-15-206-94-33.ap-south-1.compute.amazonaws.com:22").unwrap();
-        let mut sess = Session::new().unwrap();
-        sess.set_tcp_stream(tcp_stream);
-        sess.handshake().unwrap();
-
-        // Authenticate the user using PEM file
-        sess.userauth_pubkey_file("ubuntu", Option::None, Path::new("C:/Users/Yash/Downloads/test-new.pem"), Option::None).unwrap();
-
-        self.session = Some(sess);
-        return Ok(true);
-    }
-
-    /// Method to execute a SSH job on the remote server asynchronously
-    ///
-    /// # Examples
-    /// ```no_run
-    /// let server = Server::new();
-    /// let _ = server.connect().await?;
-    /// let res = server.execute("ls").await?;
-    /// ```
-    async fn execute(&self, job: &Job) -> Result<String, Box<dyn std::error::Error>> {
-        let session = match &self.session {
-            Some(sess) => sess,
-            None => {
-                panic!("Session not initialized!")
-            }
-        };
-        // Create a new channel
-        let mut channel = session.channel_session().unwrap();
-        // Execute the job on the server and get the output
-
-        let output = job.execute(&mut channel).await?;
-
-        // Close the channel
-        channel.wait_close().unwrap();
-        return Ok(output);
-    }
-
-
-}
-
-/// Wrapper class to handle HTTP requests
-struct HttpClient {
-    hostname: String
-}
-
-impl HttpClient {
-    pub fn new(hostname: String) -> Self {
-            return HttpClient {
-                hostname
-        }
-    }
-
-    pub async fn get(&self, url: String) -> Result<String, Box<dyn std::error::Error>> {
-        todo!()
-    }
-}
-
 // Struct to model the behaviour of the user
 struct User {
     username: String,
-    password: String,
+    password: Secret,
     token: String
 }
 
@@ -564,67 +1530,99 @@ impl User {
     fn new() -> Self {
         return User {
             username: String::new(),
-            password: String::new(),
+            password: Secret::new(String::new()),
             token: String::new()
         }
     }
 
     // Validate the credentials of the user
-    pub async fn validate(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
-        let mut request_params : HashMap<String, String> = HashMap::new();
-
-        request_params.insert(String::from("email"), self.username.clone());
-        request_params.insert(String::from("password"), self.password.clone());
-
-        let client = reqwest::Client::new();
-        let response = client.post("https://reqres.in/api/login")
-            .json(&request_params)
-            .send()
-            .await?
-            .json::<HashMap<String, String>>()
-            .await?;
-
-        if response.contains_key("token") {
-            match response.get("token") {
-                Some(v) => {
-                    // Set the token of the user
-                    self.token = v.clone();
-                    return Ok(true);
-                },
-                None => return Ok(false)
-            }
-        }
-        return Ok(false);
+    pub async fn validate(&mut self, authenticator: &dyn Authenticator) -> Result<bool, Box<dyn std::error::Error>> {
+        return match authenticator.authenticate(&self.username, self.password.expose()).await? {
+            Some(token) => {
+                // Set the token of the user
+                self.token = token;
+                Ok(true)
+            },
+            None => Ok(false)
+        };
     }
 
-    pub async fn authenticate(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+    pub async fn authenticate(&mut self, authenticator: &dyn Authenticator) -> Result<bool, Box<dyn std::error::Error>> {
         // Read the username from the user
         ConsoleCLI::print_line("Enter your username: ");
 
+        self.username.clear();
         io::stdin().read_line(&mut self.username)
             .expect("Error reading username");
         self.username = self.username.trim().parse().unwrap();
 
-        // Read the password from the user
-        ConsoleCLI::print_line("Enter your password: ");
-
-        io::stdin().read_line(&mut self.password)
-            .expect("Error reading password");
-        self.password = self.password.trim().parse().unwrap();
+        // Read the password without echoing it
+        self.password = User::read_password("Enter your password: ")?;
 
         let tx = ConsoleCLI::load("Authenticating");
 
-        let authenticated = self.validate().await?;
+        let authenticated = self.validate(authenticator).await;
         // Terminate the loading thread
         let _ = tx.send(true);
         ConsoleCLI::delete_prev_line();
 
-        // Validate the credentials`
-        return if authenticated {
-            Ok(true)
-        } else {
-            Ok(false)
+        return authenticated;
+    }
+
+    /// Method to read a password from the terminal without echo.
+    /// Without a terminal, e.g. when piped, it is read from stdin
+    fn read_password(prompt: &str) -> Result<Secret, Box<dyn std::error::Error>> {
+        if let Ok(password) = rpassword::prompt_password(prompt) {
+            return Ok(Secret::new(password));
         }
+
+        ConsoleCLI::print_line(prompt);
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        let length = password.trim_end_matches(&['\r', '\n'][..]).len();
+        password.truncate(length);
+        return Ok(Secret::new(password));
+    }
+
+    /// Method to log the user in with the configured backend,
+    /// prompting for the credentials unless a login of the
+    /// backend is remembered. Nothing runs unless the user
+    /// is authenticated
+    pub async fn login() -> Result<User, Box<dyn std::error::Error>> {
+        let mut user = User::new();
+        let settings = Settings::load_default()?;
+        let authenticator = settings.auth.backend.authenticator(&settings.http)?;
+        if !authenticator.needs_credentials() {
+            user.username = auth::local_username();
+            return Ok(user);
+        }
+
+        if let Some(login) = CachedLogin::load(&settings.auth) {
+            user.username = login.username;
+            user.token = login.token;
+            return Ok(user);
+        }
+
+        // Authenticate the user
+        let attempts = settings.auth.attempts.max(1);
+        for attempt in 1..=attempts {
+            if user.authenticate(authenticator.as_ref()).await? {
+                println!("You have been logged in!");
+                CachedLogin::save(&settings.auth, &user.username, &user.token)?;
+                return Ok(user);
+            }
+            if attempt < attempts {
+                println!("Invalid username or password ({} of {} attempts)", attempt, attempts);
+            }
+        }
+        return Err(format!("Login failed for {}: invalid username or password", user.username).into());
+    }
+
+    /// Method to forget the remembered login
+    fn logout() -> Result<(), Box<dyn std::error::Error>> {
+        CachedLogin::clear();
+        println!("Logged out");
+        return Ok(());
     }
 }
 
@@ -641,7 +1639,7 @@ where
 {
 
     fn new(items: Vec<T>, meta: Vec<T>) -> Self {
-        assert!(items.len() > 0);
+        assert!(!items.is_empty());
         let listener = Listener {
             items,
             meta,
@@ -658,11 +1656,7 @@ where
     }
 
     pub fn get_selected(&self) -> Option<&T> {
-        let elem = match self.state.selected() {
-            Some(i) => Some(&self.items[i]),
-            None => None
-        };
-        return elem;
+        return self.state.selected().map(|i| &self.items[i]);
     }
 
     pub fn next(&mut self) {
@@ -720,10 +1714,268 @@ where
 }
 
 
+/// Method to build the command line interface.
+/// Running without a subcommand starts the UI
+fn app() -> App<'static, 'static> {
+    return App::new("basecamp")
+        .about("Run shell jobs on multiple remote servers directly from your local system")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Prints the plan of the confirmed jobs instead of executing them")
+        )
+        .arg(
+            Arg::with_name("var")
+                .long("var")
+                .value_name("NAME=VALUE")
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .help("Sets a parameter available to the jobs as {{ params.NAME }}")
+        )
+        .subcommand(
+            SubCommand::with_name("rerun")
+                .about("Runs the jobs of a previous run again")
+                .arg(Arg::with_name("run-id").required(true).help("Id of the run to re-run"))
+                .arg(Arg::with_name("failed").long("failed").help("Only target the servers where a job failed, timed out or could not connect"))
+                .arg(Arg::with_name("yes").long("yes").short("y").help("Do not ask for a confirmation"))
+                .arg(Arg::with_name("dry-run").long("dry-run").help("Prints what would run and the diffs of the templates without running anything"))
+                .arg(Arg::with_name("json").long("json").requires("dry-run").help("Prints the plan as json"))
+                .args(&override_args())
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs jobs on the selected servers without the UI")
+                .arg(
+                    Arg::with_name("targets")
+                        .long("targets")
+                        .short("t")
+                        .value_name("SELECTOR")
+                        .required(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("Servers to run on: a name, group:NAME, tag:NAME or all")
+                )
+                .arg(
+                    Arg::with_name("jobs")
+                        .long("jobs")
+                        .short("j")
+                        .value_name("JOB")
+                        .required(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("Jobs to run, the jobs they need are added")
+                )
+                .arg(Arg::with_name("yes").long("yes").short("y").help("Do not ask for a confirmation"))
+                .arg(Arg::with_name("dry-run").long("dry-run").help("Prints what would run and the diffs of the templates without running anything"))
+                .arg(Arg::with_name("json").long("json").requires("dry-run").help("Prints the plan as json"))
+                .args(&override_args())
+        )
+        .subcommand(
+            SubCommand::with_name("broadcast")
+                .about("Sends every typed line to a shell on each of the selected servers")
+                .arg(
+                    Arg::with_name("targets")
+                        .long("targets")
+                        .short("t")
+                        .value_name("SELECTOR")
+                        .required(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .help("Servers to open a shell on: a name, group:NAME, tag:NAME or all")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("tunnel")
+                .about("Forwards ports through a server until interrupted")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .arg(Arg::with_name("server").required(true).help("Server to forward through"))
+                .arg(
+                    Arg::with_name("local")
+                        .short("L")
+                        .value_name("[BIND:]PORT:HOST:PORT")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Forwards the local port to HOST:PORT as seen from the server")
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .short("R")
+                        .value_name("PORT:HOST:PORT")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Forwards the port on the server to HOST:PORT as seen from this machine")
+                )
+                .arg(
+                    Arg::with_name("dynamic")
+                        .short("D")
+                        .value_name("[BIND:]PORT")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Opens a local SOCKS5 proxy connecting from the server")
+                )
+                .group(ArgGroup::with_name("forwards").args(&["local", "remote", "dynamic"]).multiple(true).required(true))
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compares two runs and reports what changed per server")
+                .arg(Arg::with_name("from").required(true).help("Id of the older run"))
+                .arg(Arg::with_name("to").required(true).help("Id of the newer run"))
+                .arg(Arg::with_name("json").long("json").help("Print the differences as json"))
+        )
+        .subcommand(
+            SubCommand::with_name("user")
+                .about("Manages the users of the `file` authentication backend")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Adds a user, or changes their password, prompting for the password")
+                        .arg(Arg::with_name("name").required(true).help("Name of the user"))
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Runs the schedules of config/schedule.yaml until interrupted, skipping a run while the previous one is still going")
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serves the REST API configured in the `api` section of basecamp.yaml until interrupted")
+                .arg(Arg::with_name("bind").long("bind").value_name("ADDRESS").help("Address to listen on instead of the configured one"))
+        )
+        .subcommand(
+            SubCommand::with_name("logout")
+                .about("Forgets the remembered login, so the credentials are asked for on the next run")
+        )
+        .subcommand(
+            SubCommand::with_name("audit")
+                .about("Manages the audit log of the runs")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("verify")
+                        .about("Verifies that the audit log has not been tampered with")
+                )
+        );
+}
+
+/// Returns the arguments overriding the guardrails
+fn override_args() -> Vec<Arg<'static, 'static>> {
+    return vec![
+        Arg::with_name("override")
+            .long("override")
+            .requires("reason")
+            .help("Runs even if guardrails match, without typed confirmation"),
+        Arg::with_name("reason")
+            .long("reason")
+            .value_name("TEXT")
+            .requires("override")
+            .help("Why the guardrails are overridden, recorded in the audit log")
+    ];
+}
+
+/// Method to read the run options of a subcommand
+fn run_options(args: &clap::ArgMatches) -> RunOptions {
+    return RunOptions {
+        dry_run: args.is_present("dry-run"),
+        json: args.is_present("json"),
+        assume_yes: args.is_present("yes"),
+        override_reason: args.value_of("reason").map(String::from)
+    };
+}
+
+/// Method to parse the `NAME=VALUE` pairs passed with `--var`
+fn parse_vars(values: Option<clap::Values>) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error>> {
+    let mut vars = BTreeMap::new();
+    for value in values.into_iter().flatten() {
+        match value.find('=') {
+            Some(i) if i > 0 => {
+                vars.insert(value[..i].to_string(), value[i + 1..].to_string());
+            },
+            _ => return Err(format!("Invalid --var `{}`, expected NAME=VALUE", value).into())
+        }
+    }
+    return Ok(vars);
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = app().get_matches();
+
+    match matches.subcommand() {
+        ("rerun", Some(args)) => {
+            let vars = parse_vars(args.values_of("var"))?;
+            return ConsoleCLI::rerun(args.value_of("run-id").unwrap(), args.is_present("failed"), vars, run_options(args)).await;
+        },
+        ("run", Some(args)) => {
+            let vars = parse_vars(args.values_of("var"))?;
+            let targets: Vec<String> = args.values_of("targets").unwrap().map(String::from).collect();
+            let jobs: Vec<String> = args.values_of("jobs").unwrap().map(String::from).collect();
+            return ConsoleCLI::run_selected(&targets, &jobs, vars, run_options(args)).await;
+        },
+        ("broadcast", Some(args)) => {
+            let selectors: Vec<String> = args.values_of("targets").unwrap().map(String::from).collect();
+            let user = User::login().await?;
+            let settings = Settings::load_default()?;
+            let inventory = sources::load_inventory(&settings, &user.token, &http::stderr_notices()).await?;
+            let targets = settings.access.for_user(&user.username).allowed_targets(inventory.select(&selectors)?, Access::BROADCAST)?;
+            return Broadcast::connect(&user.username, targets, settings.guardrails, inventory.protected_groups())?.run();
+        },
+        ("tunnel", Some(args)) => {
+            let mut specs = Vec::new();
+            for (name, kind) in &[("local", "-L"), ("remote", "-R"), ("dynamic", "-D")] {
+                for spec in args.values_of(name).into_iter().flatten() {
+                    specs.push(TunnelSpec::parse(kind, spec)?);
+                }
+            }
+            return ConsoleCLI::tunnel(args.value_of("server").unwrap(), specs).await;
+        },
+        ("diff", Some(args)) => {
+            return ConsoleCLI::diff(args.value_of("from").unwrap(), args.value_of("to").unwrap(), args.is_present("json"));
+        },
+        ("user", Some(args)) => {
+            if let ("add", Some(args)) = args.subcommand() {
+                return ConsoleCLI::add_user(args.value_of("name").unwrap());
+            }
+        },
+        ("logout", Some(_)) => {
+            return User::logout();
+        },
+        ("daemon", Some(_)) => {
+            return ConsoleCLI::daemon().await;
+        },
+        ("serve", Some(args)) => {
+            return ConsoleCLI::serve(args.value_of("bind")).await;
+        },
+        ("audit", Some(args)) => {
+            if let ("verify", Some(_)) = args.subcommand() {
+                return ConsoleCLI::verify_audit_log();
+            }
+        },
+        _ => {}
+    }
 
-    let cli = Arc::new(Mutex::new(ConsoleCLI::new().unwrap()));
+    let jobs = Job::load_all(&paths::config_dir().join(Job::FILE_NAME))?;
+
+    let params = parse_vars(matches.values_of("var"))?;
+
+    let settings = Settings::load_default()?;
+
+    // The user is known before the UI starts, so that
+    // it only lists what the user can run
+    let user = User::login().await?;
+    // The notices are shown once the UI is drawn
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let notices: http::Notices = {
+        let received = Arc::clone(&received);
+        Arc::new(move |message: &str| received.lock().unwrap().push(message.to_string()))
+    };
+    let inventory = sources::load_inventory(&settings, &user.token, &notices).await?;
+    let mut cli = ConsoleCLI::new(inventory, jobs, params, settings, user)?;
+    for message in received.lock().unwrap().drain(..) {
+        cli.print(message);
+    }
+    cli.dry_run = matches.is_present("dry-run");
+    let cli = Arc::new(Mutex::new(cli));
     let clone = Arc::clone(&cli);
 
     let render_handle = thread::spawn(move || {
@@ -734,7 +1986,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     render_handle.join().unwrap();
 
     // Execute the jobs
-    (*Arc::clone(&cli)).lock().unwrap().execute_jobs().await?;
+    let mut cli = match Arc::try_unwrap(cli) {
+        Ok(cli) => cli.into_inner().unwrap(),
+        Err(_) => panic!("UI thread still holds the cli")
+    };
+    if cli.dry_run {
+        return ConsoleCLI::print_plan(&cli.settings, &cli.plan, &cli.violations, false);
+    }
+    cli.execute_jobs().await?;
 
     return Ok(());
-}
\ No newline at end of file
+}
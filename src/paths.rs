@@ -0,0 +1,74 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Returns the directory holding the yaml configuration
+/// files. Can be overridden with `BASECAMP_CONFIG`
+pub fn config_dir() -> PathBuf {
+    return match env::var_os("BASECAMP_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from("config")
+    };
+}
+
+/// Returns the directory where basecamp keeps its state
+/// (run history, caches). Defaults to `~/.basecamp` and
+/// can be overridden with `BASECAMP_HOME`
+pub fn data_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("BASECAMP_HOME") {
+        return PathBuf::from(dir);
+    }
+
+    let home = env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    return home.join(".basecamp");
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Mutex, MutexGuard};
+    use tempfile::TempDir;
+
+    /// Serializes the tests changing the environment
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// A temporary config and data directory, set as
+    /// `BASECAMP_CONFIG` and `BASECAMP_HOME` while it lives
+    pub struct TestHome {
+        dir: TempDir,
+        _lock: MutexGuard<'static, ()>
+    }
+
+    impl TestHome {
+        pub fn new() -> Self {
+            let lock = ENV.lock().unwrap_or_else(|err| err.into_inner());
+            let dir = tempfile::tempdir().unwrap();
+            fs::create_dir(dir.path().join("config")).unwrap();
+            std::env::set_var("BASECAMP_CONFIG", dir.path().join("config"));
+            std::env::set_var("BASECAMP_HOME", dir.path().join("data"));
+            return TestHome { dir, _lock: lock };
+        }
+
+        /// Method to write a file of the config directory
+        pub fn config(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.dir.path().join("config").join(name);
+            fs::write(&path, contents).unwrap();
+            return path;
+        }
+
+        pub fn data_dir(&self) -> PathBuf {
+            return self.dir.path().join("data");
+        }
+    }
+
+    impl Drop for TestHome {
+        fn drop(&mut self) {
+            std::env::remove_var("BASECAMP_CONFIG");
+            std::env::remove_var("BASECAMP_HOME");
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use futures::executor::block_on;
+
+use crate::escalation::Secret;
+use crate::inventory::Target;
+use crate::server::{self, Server};
+
+/// The connection to a target, established once per pool
+type Slot = Arc<Mutex<Option<Result<Arc<Pooled>, ConnectError>>>>;
+
+/// Error of a target which could not be connected to. It is
+/// kept so the other jobs on the target fail without retrying
+#[derive(Clone, Debug)]
+pub struct ConnectError {
+    pub message: String,
+    pub timed_out: bool
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}", self.message);
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// A connected server with the number of jobs using it
+struct Pooled {
+    server: Server,
+    active: AtomicUsize,
+    /// Held while a keepalive is sent, so that no job
+    /// starts using the session meanwhile
+    gate: Mutex<()>
+}
+
+/// A session of the pool used by a job. The session is
+/// idle again once the lease is dropped
+pub struct Lease {
+    pooled: Arc<Pooled>
+}
+
+impl Deref for Lease {
+    type Target = Server;
+
+    fn deref(&self) -> &Server {
+        return &self.pooled.server;
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pooled.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Authenticated sessions shared by the jobs of a run, one per
+/// target. Jobs open their own channels on the shared session, and
+/// idle sessions are kept alive until the pool is dropped
+pub struct SessionPool {
+    slots: Arc<Mutex<HashMap<String, Slot>>>,
+    become_password: Option<Arc<Secret>>
+}
+
+impl SessionPool {
+    /// Interval of the keepalive messages sent on idle sessions
+    pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+    pub fn new(keepalive: Duration) -> Self {
+        let slots: Arc<Mutex<HashMap<String, Slot>>> = Arc::new(Mutex::new(HashMap::new()));
+        let weak = Arc::downgrade(&slots);
+        thread::spawn(move || SessionPool::send_keepalives(weak, keepalive));
+        return SessionPool { slots, become_password: None };
+    }
+
+    pub fn set_become_password(&mut self, password: Option<Arc<Secret>>) {
+        self.become_password = password;
+    }
+
+    /// Method to get the session of the target, connecting to it
+    /// on first use. Jobs asking for a target being connected to
+    /// wait for the connection instead of opening another one
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let pool = SessionPool::new(SessionPool::KEEPALIVE_INTERVAL);
+    /// let server = pool.get(&target)?;
+    /// let output = block_on(server.execute(&job, &|line| println!("{}", line)))?;
+    /// ```
+    pub fn get(&self, target: &Target) -> Result<Lease, ConnectError> {
+        let slot = Arc::clone(
+            self.slots.lock().unwrap()
+                .entry(target.name.clone())
+                .or_insert_with(|| Arc::new(Mutex::new(None)))
+        );
+
+        // Only the connection to this target is waited for
+        let mut slot = slot.lock().unwrap();
+        if slot.is_none() {
+            let mut server = Server::new(target.clone());
+            if let Some(password) = &self.become_password {
+                server.set_become_password(Arc::clone(password));
+            }
+            *slot = Some(match block_on(server.connect()) {
+                Ok(_) => Ok(Arc::new(Pooled { server, active: AtomicUsize::new(0), gate: Mutex::new(()) })),
+                Err(err) => Err(ConnectError {
+                    message: err.to_string(),
+                    timed_out: server::is_timeout(&*err)
+                })
+            });
+        }
+
+        let pooled = match slot.as_ref().unwrap() {
+            Ok(pooled) => Arc::clone(pooled),
+            Err(err) => return Err(err.clone())
+        };
+        {
+            let _gate = pooled.gate.lock().unwrap();
+            pooled.active.fetch_add(1, Ordering::SeqCst);
+        }
+        return Ok(Lease { pooled });
+    }
+
+    /// Method to send keepalive messages on the idle
+    /// sessions until the pool is dropped
+    fn send_keepalives(slots: Weak<Mutex<HashMap<String, Slot>>>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            let slots: Vec<Slot> = match slots.upgrade() {
+                Some(slots) => slots.lock().unwrap().values().cloned().collect(),
+                None => return
+            };
+            for slot in slots {
+                // Targets being connected to are not idle
+                let pooled = match slot.try_lock().ok().as_deref() {
+                    Some(Some(Ok(pooled))) => Arc::clone(pooled),
+                    _ => continue
+                };
+                let _gate = pooled.gate.lock().unwrap();
+                if pooled.active.load(Ordering::SeqCst) == 0 {
+                    // A broken session fails the next job using it
+                    let _ = pooled.server.keepalive();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use std::collections::BTreeMap;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+/// A run of jobs on servers started by the daemon, either
+/// at the times of a cron expression or at an interval
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    pub name: String,
+    /// Cron expression, `MIN HOUR DAY MONTH WEEKDAY` or
+    /// with seconds first, in the local time zone. Weekdays
+    /// are numbered from 0 (or 7) for Sunday, or named
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cron: Option<String>,
+    /// Interval between runs such as `90s`, `15m`, `2h` or `1d`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub every: Option<String>,
+    /// Servers selected by name, `group:NAME`, `tag:NAME` or `all`
+    pub targets: Vec<String>,
+    /// Jobs to run, the jobs they need are added
+    pub jobs: Vec<String>,
+    /// Run-level parameters of the jobs
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, String>,
+    /// Reason recorded when the run matches guardrails. Runs
+    /// matching guardrails are refused without one, as nobody
+    /// is there to confirm them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub override_reason: Option<String>
+}
+
+/// When a schedule runs
+#[derive(Clone, Debug)]
+enum Timing {
+    Cron(Box<cron::Schedule>),
+    Every(Duration)
+}
+
+impl Schedule {
+    /// Method to parse when the schedule runs
+    fn timing(&self) -> Result<Timing, Box<dyn std::error::Error>> {
+        return match (&self.cron, &self.every) {
+            (Some(expression), None) => {
+                // Standard cron expressions have no seconds
+                let mut fields: Vec<String> = expression.split_whitespace().map(String::from).collect();
+                if fields.len() == 5 {
+                    fields.insert(0, String::from("0"));
+                }
+                if let Some(weekdays) = fields.get_mut(5) {
+                    *weekdays = cron_weekdays(weekdays)
+                        .map_err(|err| format!("Invalid cron expression `{}` of schedule {}: {}", expression, self.name, err))?;
+                }
+                let expression = fields.join(" ");
+                let schedule = cron::Schedule::from_str(&expression)
+                    .map_err(|err| format!("Invalid cron expression `{}` of schedule {}: {}", expression, self.name, err))?;
+                Ok(Timing::Cron(Box::new(schedule)))
+            },
+            (None, Some(every)) => Ok(Timing::Every(parse_interval(every)
+                .map_err(|err| format!("Invalid interval of schedule {}: {}", self.name, err))?)),
+            _ => Err(format!("Schedule {} must have exactly one of cron or every", self.name).into())
+        };
+    }
+}
+
+impl Timing {
+    /// Returns the first time the schedule runs after `time`
+    fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        return match self {
+            Timing::Cron(schedule) => schedule.after(&time).next(),
+            Timing::Every(interval) => chrono::Duration::from_std(*interval).ok().map(|interval| time + interval)
+        };
+    }
+}
+
+/// Names of the weekdays, from Sunday
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// Method to convert the weekday field of a cron expression from
+/// the standard numbering, 0 or 7 for Sunday, to the names of the
+/// days, as the cron crate numbers them from 1 for Sunday
+fn cron_weekdays(field: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if field == "*" || field == "?" {
+        return Ok(field.to_string());
+    }
+
+    // Returns the day of a number or a name, Sunday being 0 or 7
+    let day = |text: &str| -> Result<usize, Box<dyn std::error::Error>> {
+        if let Ok(number) = text.parse::<usize>() {
+            return if number <= 7 { Ok(number) } else { Err(format!("`{}` is not a weekday, from 0 to 7", text).into()) };
+        }
+        return match WEEKDAYS.iter().position(|name| name.eq_ignore_ascii_case(text.get(..3).unwrap_or(text))) {
+            Some(day) => Ok(day),
+            None => Err(format!("`{}` is not a weekday", text).into())
+        };
+    };
+
+    let mut days = [false; 7];
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<usize>().ok().filter(|s| *s > 0)
+                .ok_or_else(|| format!("`{}` has an invalid step", item))?)),
+            None => (item, None)
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((first, last)) => (day(first)?, day(last)?),
+            // A single day with a step repeats until the end of the week
+            None if step.is_some() => (day(range)?, 6),
+            None => (day(range)?, day(range)?)
+        };
+        if first > last {
+            return Err(format!("`{}` is not an increasing range of weekdays", item).into());
+        }
+        for day in (first..=last).step_by(step.unwrap_or(1)) {
+            days[day % 7] = true;
+        }
+    }
+
+    let names: Vec<&str> = WEEKDAYS.iter().zip(&days).filter(|(_, set)| **set).map(|(name, _)| *name).collect();
+    return Ok(names.join(","));
+}
+
+/// Method to parse an interval made of a number and a unit,
+/// `s` for seconds, `m` for minutes, `h` for hours or `d` for days
+fn parse_interval(text: &str) -> Result<Duration, Box<dyn std::error::Error>> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("`{}` must start with a number", text))?;
+    let unit_seconds = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("`{}` must end with s, m, h or d", text).into())
+    };
+    let interval = number.checked_mul(unit_seconds)
+        .map(Duration::from_secs)
+        .filter(|interval| chrono::Duration::from_std(*interval).is_ok())
+        .ok_or_else(|| format!("`{}` is too long", text))?;
+    if interval == Duration::from_secs(0) {
+        return Err(format!("`{}` must be longer than zero", text).into());
+    }
+    return Ok(interval);
+}
+
+/// Struct to model the schedules configured in `schedule.yaml`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ScheduleFile {
+    #[serde(default)]
+    pub schedules: Vec<Schedule>
+}
+
+impl ScheduleFile {
+    /// Name of the schedules file inside the config directory
+    pub const FILE_NAME: &'static str = "schedule.yaml";
+
+    /// Method to load the schedules from a yaml file, checking
+    /// that their names are unique and that they can be timed
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let schedules = ScheduleFile::load(Path::new("config/schedule.yaml"))?.schedules;
+    /// ```
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read the schedules {}: {}", path.display(), err))?;
+        if contents.trim().is_empty() {
+            return Ok(ScheduleFile::default());
+        }
+
+        let file: ScheduleFile = serde_yaml::from_str(&contents)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        for (i, schedule) in file.schedules.iter().enumerate() {
+            if file.schedules[..i].iter().any(|s| s.name == schedule.name) {
+                return Err(format!("Schedule {} is defined twice", schedule.name).into());
+            }
+            if schedule.targets.is_empty() || schedule.jobs.is_empty() {
+                return Err(format!("Schedule {} must list targets and jobs", schedule.name).into());
+            }
+            schedule.timing()?;
+        }
+        return Ok(file);
+    }
+}
+
+/// Starts a run of a schedule and returns the id of the run
+pub type Trigger = dyn Fn(&Schedule) -> Result<String, String> + Send + Sync;
+
+/// A schedule with the time of its next run
+struct Entry {
+    schedule: Schedule,
+    timing: Timing,
+    next: Option<DateTime<Local>>,
+    /// Set while a run of the schedule is going
+    running: Arc<AtomicBool>
+}
+
+/// Runs the schedules when they are due. A run is skipped
+/// while the previous run of its schedule is still going
+pub struct Daemon {
+    entries: Vec<Entry>
+}
+
+impl Daemon {
+    /// Interval at which the daemon checks for due schedules
+    const TICK: Duration = Duration::from_secs(1);
+
+    pub fn new(schedules: Vec<Schedule>) -> Result<Self, Box<dyn std::error::Error>> {
+        let now = Local::now();
+        let mut entries = Vec::with_capacity(schedules.len());
+        for schedule in schedules {
+            let timing = schedule.timing()?;
+            entries.push(Entry {
+                next: timing.next_after(now),
+                schedule,
+                timing,
+                running: Arc::new(AtomicBool::new(false))
+            });
+        }
+        return Ok(Daemon { entries });
+    }
+
+    /// Returns the name and next run of every schedule
+    pub fn upcoming(&self) -> Vec<(String, Option<DateTime<Local>>)> {
+        return self.entries.iter().map(|e| (e.schedule.name.clone(), e.next)).collect();
+    }
+
+    /// Method to run the due schedules in their own thread,
+    /// forever. The outcome of every run is printed
+    ///
+    /// # Examples
+    /// ```no_run
+    /// Daemon::new(schedules)?.run(Arc::new(|schedule| start(schedule)));
+    /// ```
+    pub fn run(&mut self, trigger: Arc<Trigger>) {
+        loop {
+            let now = Local::now();
+            for entry in self.entries.iter_mut().filter(|e| e.next.map(|next| next <= now).unwrap_or(false)) {
+                entry.next = entry.timing.next_after(now);
+                let name = entry.schedule.name.clone();
+                if entry.running.swap(true, Ordering::SeqCst) {
+                    log(&format!("Skipping {}: the previous run is still going", name));
+                    continue;
+                }
+
+                log(&format!("Starting {}", name));
+                let schedule = entry.schedule.clone();
+                let running = Arc::clone(&entry.running);
+                let trigger = Arc::clone(&trigger);
+                thread::spawn(move || {
+                    // Cleared even if the trigger panics
+                    let _running = Running(running);
+                    match trigger(&schedule) {
+                        Ok(id) => log(&format!("Finished {}, run {} saved to history", schedule.name, id)),
+                        Err(err) => log(&format!("Run of {} failed: {}", schedule.name, err))
+                    }
+                });
+            }
+            thread::sleep(Daemon::TICK);
+        }
+    }
+}
+
+/// Clears the running flag of a schedule once dropped
+struct Running(Arc<AtomicBool>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Method to print a line prefixed with the local time
+pub fn log(message: &str) {
+    println!("[{}] {}", Local::now().format("%Y-%m-%d %H:%M:%S"), message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn intervals_have_a_number_and_a_unit() {
+        assert_eq!(parse_interval("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_interval("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(parse_interval(" 2h ").unwrap(), Duration::from_secs(2 * 60 * 60));
+        assert_eq!(parse_interval("1d").unwrap(), Duration::from_secs(24 * 60 * 60));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("m").is_err());
+        assert!(parse_interval("10").is_err());
+        assert!(parse_interval("10w").is_err());
+        assert!(parse_interval("99999999999999999d").is_err());
+        assert!(parse_interval("18446744073709551615s").is_err());
+    }
+
+    #[test]
+    fn panicking_runs_can_run_again() {
+        let running = Arc::new(AtomicBool::new(true));
+        let guarded = Arc::clone(&running);
+        let run = thread::spawn(move || {
+            let _running = Running(guarded);
+            panic!("the trigger failed");
+        });
+        assert!(run.join().is_err());
+        assert!(!running.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn schedules_have_a_cron_or_an_interval() {
+        let schedule = |cron: Option<&str>, every: Option<&str>| Schedule {
+            name: String::from("nightly"),
+            cron: cron.map(String::from),
+            every: every.map(String::from),
+            targets: vec![String::from("all")],
+            jobs: vec![String::from("backup")],
+            vars: BTreeMap::new(),
+            override_reason: None
+        };
+        let start = Local::now();
+        let next = schedule(None, Some("15m")).timing().unwrap().next_after(start).unwrap();
+        assert_eq!(next - start, chrono::Duration::minutes(15));
+        let next = schedule(Some("30 2 * * *"), None).timing().unwrap().next_after(start).unwrap();
+        assert_eq!(next.format("%H:%M:%S").to_string(), "02:30:00");
+
+        assert!(schedule(None, None).timing().is_err());
+        assert!(schedule(Some("30 2 * * 8"), None).timing().is_err());
+        assert!(schedule(Some("30 2 * * 5-1"), None).timing().is_err());
+        assert!(schedule(Some("30 2 * * *"), Some("1h")).timing().is_err());
+        assert!(schedule(Some("not cron"), None).timing().is_err());
+    }
+
+    #[test]
+    fn weekdays_are_numbered_from_sunday() {
+        let timing = |cron: &str| Schedule {
+            name: String::from("weekly"),
+            cron: Some(String::from(cron)),
+            every: None,
+            targets: vec![String::from("all")],
+            jobs: vec![String::from("backup")],
+            vars: BTreeMap::new(),
+            override_reason: None
+        }.timing().unwrap();
+        // A Saturday
+        let start = Local.ymd(2026, 10, 17).and_hms(12, 0, 0);
+        let next_day = |cron: &str| timing(cron).next_after(start).unwrap().format("%a %d %H:%M").to_string();
+
+        assert_eq!(next_day("30 2 * * 1-5"), "Mon 19 02:30");
+        assert_eq!(next_day("0 9 * * 0"), "Sun 18 09:00");
+        assert_eq!(next_day("0 9 * * 7"), "Sun 18 09:00");
+        assert_eq!(next_day("0 9 * * 6"), "Sat 24 09:00");
+        assert_eq!(next_day("0 9 * * mon-fri"), "Mon 19 09:00");
+        assert_eq!(next_day("0 9 * * 5-7"), "Sun 18 09:00");
+        assert_eq!(next_day("0 9 * * 1/2"), "Mon 19 09:00");
+        assert_eq!(next_day("0 0 9 * * 2"), "Tue 20 09:00");
+    }
+}
@@ -0,0 +1,328 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use ssh2::{Channel, ExtendedData, Session};
+
+use crate::escalation::Secret;
+use crate::inventory::Target;
+use crate::job::{Job, JobOutput};
+use crate::shell;
+
+/// Size of the buffer used to forward data
+const FORWARD_BUFFER_SIZE: usize = 32 * 1024;
+
+pub struct Server {
+    target: Target,
+    timeout: Duration,
+    session: Option<Session>,
+    /// Password answering the prompt of sudo or su
+    become_password: Option<Arc<Secret>>
+}
+
+impl Server {
+    /// Timeout applied to the TCP connect, the handshake and the
+    /// authentication. Jobs may run and stay quiet for longer
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(target: Target) -> Self {
+        return Server {
+            target,
+            timeout: Server::DEFAULT_TIMEOUT,
+            session: Option::None,
+            become_password: Option::None
+        };
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_become_password(&mut self, password: Arc<Secret>) {
+        self.become_password = Some(password);
+    }
+
+    pub fn session(&self) -> Option<&Session> {
+        return self.session.as_ref();
+    }
+
+    pub fn target(&self) -> &Target {
+        return &self.target;
+    }
+
+    /// Method to connect (SSH) to a remote server using PEM encoded key asynchronously.
+    /// Falls back to the ssh-agent when the target has no key configured. Targets
+    /// with a jump host are connected to through it
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let server = Server::new(target);
+    /// let connected = server.connect().await?;
+    ///
+    /// if connected {
+    ///     println!("Connected to server!");
+    /// }
+    /// ```
+    pub async fn connect(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        self.open()?;
+        return Ok(true);
+    }
+
+    /// Method to open the SSH session
+    fn open(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // Connect to the remote SSH server
+        let tcp_stream = match &self.target.via {
+            Some(jump) => self.connect_through(jump)?,
+            None => {
+                let address = match self.target.address().to_socket_addrs()?.next() {
+                    Some(address) => address,
+                    None => return Err(format!("Could not resolve {}", self.target.host).into())
+                };
+                TcpStream::connect_timeout(&address, self.timeout)?
+            }
+        };
+        let mut sess = Session::new()?;
+        sess.set_tcp_stream(tcp_stream);
+        sess.set_timeout(self.timeout.as_millis() as u32);
+        sess.handshake()?;
+
+        // Authenticate the user using PEM file
+        match &self.target.key {
+            Some(key) => sess.userauth_pubkey_file(&self.target.user, Option::None, Path::new(key), Option::None)?,
+            None => sess.userauth_agent(&self.target.user)?
+        };
+
+        // The blocking calls of the jobs wait for as long as the jobs run
+        sess.set_timeout(0);
+        self.session = Some(sess);
+        return Ok(());
+    }
+
+    /// Method to open a TCP stream to the target through the
+    /// jump host. The stream is a local socket forwarded over
+    /// a channel of the jump host session
+    fn connect_through(&self, jump: &Target) -> Result<TcpStream, Box<dyn std::error::Error>> {
+        let mut server = Server::new(jump.clone());
+        server.set_timeout(self.timeout);
+        server.open()
+            .map_err(|err| format!("Could not connect to jump host {}: {}", jump.name, err))?;
+
+        let session = server.session.take().unwrap();
+        let channel = session.channel_direct_tcpip(&self.target.host, self.target.port, None)
+            .map_err(|err| format!("Jump host {} could not reach {}: {}", jump.name, self.target.address(), err))?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let stream = TcpStream::connect(listener.local_addr()?)?;
+        let (local, _) = listener.accept()?;
+        thread::spawn(move || {
+            let _ = forward(session, channel, local);
+        });
+        return Ok(stream);
+    }
+
+    /// Method to execute a SSH job on the remote server asynchronously
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let server = Server::new(target);
+    /// let _ = server.connect().await?;
+    /// let res = server.execute(&job, &|line| println!("{}", line)).await?;
+    /// ```
+    pub async fn execute(&self, job: &Job, on_line: &dyn Fn(&str)) -> Result<JobOutput, Box<dyn std::error::Error>> {
+        let session = match &self.session {
+            Some(sess) => sess,
+            None => return Err(format!("Not connected to {}", self.target.name).into())
+        };
+        // Execute the steps of the job on the server
+        return job.execute(session, &self.target.name, self.become_password.as_deref(), on_line).await;
+    }
+
+    /// Method to start a shell without a PTY on the server, reading
+    /// commands from the channel. Its errors are merged with its output
+    pub fn start_shell(&self) -> Result<Channel, Box<dyn std::error::Error>> {
+        let session = match &self.session {
+            Some(session) => session,
+            None => return Err(format!("Not connected to {}", self.target.name).into())
+        };
+        let mut channel = session.channel_session()?;
+        channel.handle_extended_data(ExtendedData::Merge)?;
+        channel.shell()?;
+        return Ok(channel);
+    }
+
+    /// Method to send a keepalive message. Keepalives are only
+    /// enabled while sending it, as the blocking calls of libssh2
+    /// time out after the keepalive interval otherwise, so it must
+    /// not be called while a job is using the session
+    pub fn keepalive(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(session) = &self.session {
+            session.set_keepalive(false, 1);
+            let sent = session.keepalive_send();
+            session.set_keepalive(false, 0);
+            sent?;
+        }
+        return Ok(());
+    }
+
+    /// Method to switch the session between blocking
+    /// and non blocking mode
+    pub fn set_blocking(&self, blocking: bool) {
+        if let Some(session) = &self.session {
+            session.set_blocking(blocking);
+        }
+    }
+
+    /// Method to open an interactive shell on the server in the
+    /// local terminal, returning its exit status once it exits
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let mut server = Server::new(target);
+    /// let _ = server.connect().await?;
+    /// let exit_status = server.shell()?;
+    /// ```
+    pub fn shell(&self) -> Result<i32, Box<dyn std::error::Error>> {
+        return match &self.session {
+            Some(session) => shell::interactive(session),
+            None => Err(format!("Not connected to {}", self.target.name).into())
+        };
+    }
+}
+
+/// Method to write the whole buffer to a non blocking writer
+pub fn write_all(writer: &mut dyn Write, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => data = &data[written..],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(err) => return Err(err)
+        }
+    }
+    return Ok(());
+}
+
+/// Method to copy the data between a local stream and a
+/// channel in both directions, until either side closes.
+/// The session is switched to non blocking mode
+pub fn forward(session: Session, mut channel: Channel, mut stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
+    session.set_blocking(false);
+    stream.set_nonblocking(true)?;
+
+    let mut buffer = vec![0; FORWARD_BUFFER_SIZE];
+    loop {
+        let mut idle = true;
+
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                write_all(&mut channel, &buffer[..read])?;
+                idle = false;
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+            Err(err) => return Err(err.into())
+        }
+
+        match channel.read(&mut buffer) {
+            Ok(0) if channel.eof() => break,
+            Ok(0) => {},
+            Ok(read) => {
+                write_all(&mut stream, &buffer[..read])?;
+                idle = false;
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+            Err(err) => return Err(err.into())
+        }
+
+        if idle {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    let _ = channel.close();
+    return Ok(());
+}
+
+/// Returns true if the error was caused by a connection
+/// or a blocking SSH call running out of time
+pub fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<std::io::Error>() {
+        return err.kind() == std::io::ErrorKind::TimedOut
+            || err.kind() == std::io::ErrorKind::WouldBlock;
+    }
+    if let Some(err) = err.downcast_ref::<ssh2::Error>() {
+        // LIBSSH2_ERROR_TIMEOUT
+        return err.code() == ssh2::ErrorCode::Session(-9);
+    }
+    return false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    /// Returns the server of `BASECAMP_TEST_SSH` (`user@host:port`),
+    /// authenticated with the key of `BASECAMP_TEST_SSH_KEY`
+    fn test_target() -> Target {
+        let address = std::env::var("BASECAMP_TEST_SSH").expect("BASECAMP_TEST_SSH is not set");
+        let (user, address) = address.split_at(address.find('@').expect("BASECAMP_TEST_SSH must be user@host:port"));
+        let (host, port) = address[1..].split_at(address[1..].rfind(':').expect("BASECAMP_TEST_SSH must have a port"));
+        let yaml = format!("{{ name: test, host: '{}', port: {}, user: '{}' }}", host, &port[1..], user);
+        let mut target: Target = serde_yaml::from_str(&yaml).unwrap();
+        target.key = std::env::var("BASECAMP_TEST_SSH_KEY").ok();
+        return target;
+    }
+
+    #[test]
+    #[ignore = "needs an SSH server in BASECAMP_TEST_SSH"]
+    fn quiet_commands_outlast_the_connect_timeout() {
+        let mut server = Server::new(test_target());
+        server.set_timeout(Duration::from_secs(1));
+        block_on(server.connect()).unwrap();
+
+        let job = Job::new(String::from("slow"), String::from("sleep 3; echo done"));
+        let output = block_on(server.execute(&job, &|_| {})).unwrap();
+        assert_eq!(output.exit_status, 0);
+        assert_eq!(output.output, "done\n");
+    }
+
+    #[test]
+    #[ignore = "needs an SSH server in BASECAMP_TEST_SSH"]
+    fn failing_steps_fail_the_job_and_keep_the_results() {
+        let mut server = Server::new(test_target());
+        block_on(server.connect()).unwrap();
+
+        let job: Job = serde_yaml::from_str(r#"
+            name: steps
+            steps:
+              - command: echo first
+              - command: exit 3
+                continue_on_error: true
+              - command: echo after
+              - script: /nonexistent/script.sh
+              - command: echo never
+        "#).unwrap();
+        let output = block_on(server.execute(&job, &|_| {})).unwrap();
+        assert_eq!(output.exit_status, 3);
+        assert_eq!(output.output, "first\nafter\n");
+        assert_eq!(output.steps.len(), 4);
+        assert!(output.steps[3].error.as_ref().unwrap().contains("/nonexistent/script.sh"));
+        assert!(output.error.is_some());
+    }
+
+    #[test]
+    #[ignore = "needs an SSH server in BASECAMP_TEST_SSH"]
+    fn invalid_utf8_output_does_not_fail_the_job() {
+        let mut server = Server::new(test_target());
+        block_on(server.connect()).unwrap();
+
+        let job = Job::new(String::from("binary"), String::from("printf 'ok \\377\\n'"));
+        let output = block_on(server.execute(&job, &|_| {})).unwrap();
+        assert_eq!(output.exit_status, 0);
+        assert!(output.error.is_none());
+        assert_eq!(output.output, "ok \u{fffd}\n");
+    }
+}
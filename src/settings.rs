@@ -0,0 +1,67 @@
+use std::fs;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use crate::access::AccessPolicy;
+use crate::api::ApiSettings;
+use crate::auth::AuthSettings;
+use crate::escalation::EscalationSettings;
+use crate::guardrails::Guardrails;
+use crate::http::HttpSettings;
+use crate::sources::InventorySettings;
+use crate::webhooks::WebhookSettings;
+
+/// Settings of basecamp itself, configured in `basecamp.yaml`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub guardrails: Guardrails,
+    #[serde(default, rename = "become")]
+    pub escalation: EscalationSettings,
+    #[serde(default)]
+    pub auth: AuthSettings,
+    #[serde(default)]
+    pub access: AccessPolicy,
+    #[serde(default)]
+    pub http: HttpSettings,
+    #[serde(default)]
+    pub inventory: InventorySettings,
+    #[serde(default)]
+    pub webhooks: WebhookSettings,
+    #[serde(default)]
+    pub api: ApiSettings
+}
+
+impl Settings {
+    /// Name of the settings file inside the config directory
+    pub const FILE_NAME: &'static str = "basecamp.yaml";
+
+    /// Method to load the settings from a yaml file. A missing
+    /// or empty file gives the default settings
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let settings = Settings::load(Path::new("config/basecamp.yaml"))?;
+    /// ```
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Ok(Settings::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        if contents.trim().is_empty() {
+            return Ok(Settings::default());
+        }
+
+        let settings: Settings = serde_yaml::from_str(&contents)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        settings.guardrails.validate()?;
+        settings.access.validate()?;
+        return Ok(settings);
+    }
+
+    /// Method to load the settings stored in
+    /// the config directory
+    pub fn load_default() -> Result<Self, Box<dyn std::error::Error>> {
+        return Settings::load(&crate::paths::config_dir().join(Settings::FILE_NAME));
+    }
+}
@@ -0,0 +1,145 @@
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::terminal;
+use ssh2::{Channel, Session};
+
+/// Terminal type requested for the PTY
+pub const TERM: &str = "xterm";
+
+/// Method to open a shell on a PTY of the session sized like the
+/// local terminal, optionally running a command instead
+pub fn open(session: &Session, command: Option<&str>) -> Result<Channel, Box<dyn std::error::Error>> {
+    let (width, height) = terminal::size().unwrap_or((80, 24));
+    let mut channel = session.channel_session()?;
+    channel.request_pty(TERM, None, Some((width as u32, height as u32, 0, 0)))?;
+    match command {
+        Some(command) => channel.exec(command)?,
+        None => channel.shell()?
+    };
+    return Ok(channel);
+}
+
+/// Method to attach the local terminal to an interactive shell
+/// on the server until the shell exits. The terminal is in raw
+/// mode meanwhile, so every key is handled by the server.
+/// Returns the exit status of the shell
+///
+/// # Examples
+/// ```no_run
+/// let exit_status = shell::interactive(&session)?;
+/// ```
+pub fn interactive(session: &Session) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut channel = open(session, None)?;
+
+    terminal::enable_raw_mode()?;
+    session.set_blocking(false);
+    let attached = attach(&mut channel);
+    session.set_blocking(true);
+    terminal::disable_raw_mode()?;
+    attached?;
+
+    channel.wait_close()?;
+    return Ok(channel.exit_status()?);
+}
+
+/// Method to copy the key presses to the channel and its
+/// output to the terminal until the channel is closed
+fn attach(channel: &mut Channel) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stdout = io::stdout();
+    let mut buffer = [0; 4096];
+    loop {
+        let mut idle = true;
+
+        match channel.read(&mut buffer) {
+            Ok(0) if channel.eof() => break,
+            Ok(0) => {},
+            Ok(read) => {
+                stdout.write_all(&buffer[..read])?;
+                stdout.flush()?;
+                idle = false;
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+            Err(err) => return Err(err.into())
+        }
+
+        while poll(Duration::from_millis(0))? {
+            match read()? {
+                Event::Key(key) => write_all(channel, &key_bytes(key))?,
+                Event::Resize(width, height) => {
+                    let _ = channel.request_pty_size(width as u32, height as u32, None, None);
+                },
+                Event::Mouse(_) => {}
+            }
+            idle = false;
+        }
+
+        if idle {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+    return Ok(());
+}
+
+/// Method to write the whole buffer to the non blocking channel
+pub fn write_all(channel: &mut Channel, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match channel.write(data) {
+            Ok(written) => data = &data[written..],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+            Err(err) => return Err(err)
+        }
+    }
+    return channel.flush();
+}
+
+/// Returns the bytes a terminal sends for a key press
+pub fn key_bytes(key: KeyEvent) -> Vec<u8> {
+    let alt = key.modifiers.contains(KeyModifiers::ALT);
+    let mut bytes: Vec<u8> = match key.code {
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) && c.is_ascii() => {
+            match c.to_ascii_lowercase() {
+                c @ 'a'..='z' => vec![c as u8 - b'a' + 1],
+                '@' | ' ' => vec![0],
+                '[' => vec![0x1b],
+                '\\' => vec![0x1c],
+                ']' => vec![0x1d],
+                '^' => vec![0x1e],
+                '_' => vec![0x1f],
+                c => vec![c as u8]
+            }
+        },
+        KeyCode::Char(c) => c.to_string().into_bytes(),
+        KeyCode::Enter => vec![b'\r'],
+        KeyCode::Tab => vec![b'\t'],
+        KeyCode::BackTab => b"\x1b[Z".to_vec(),
+        KeyCode::Backspace => vec![0x7f],
+        KeyCode::Esc => vec![0x1b],
+        KeyCode::Up => b"\x1b[A".to_vec(),
+        KeyCode::Down => b"\x1b[B".to_vec(),
+        KeyCode::Right => b"\x1b[C".to_vec(),
+        KeyCode::Left => b"\x1b[D".to_vec(),
+        KeyCode::Home => b"\x1b[H".to_vec(),
+        KeyCode::End => b"\x1b[F".to_vec(),
+        KeyCode::Insert => b"\x1b[2~".to_vec(),
+        KeyCode::Delete => b"\x1b[3~".to_vec(),
+        KeyCode::PageUp => b"\x1b[5~".to_vec(),
+        KeyCode::PageDown => b"\x1b[6~".to_vec(),
+        KeyCode::F(n) => match n {
+            1 => b"\x1bOP".to_vec(),
+            2 => b"\x1bOQ".to_vec(),
+            3 => b"\x1bOR".to_vec(),
+            4 => b"\x1bOS".to_vec(),
+            5 => b"\x1b[15~".to_vec(),
+            6..=10 => format!("\x1b[{}~", n + 11).into_bytes(),
+            11 | 12 => format!("\x1b[{}~", n + 12).into_bytes(),
+            _ => Vec::new()
+        },
+        KeyCode::Null => vec![0]
+    };
+    if alt && !bytes.is_empty() {
+        bytes.insert(0, 0x1b);
+    }
+    return bytes;
+}
@@ -0,0 +1,340 @@
+use std::fs;
+use std::path::PathBuf;
+use std::collections::BTreeMap;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::ansible::AnsibleInventory;
+use crate::auth::write_private;
+use crate::http::{HttpClient, HttpSettings, Notices};
+use crate::inventory::{Inventory, Target};
+use crate::settings::Settings;
+
+/// Settings of the inventory, the `inventory` section of `basecamp.yaml`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InventorySettings {
+    /// Sources the servers of `server.yaml` are merged with
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<InventorySource>
+}
+
+/// A source of servers besides `server.yaml`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InventorySource {
+    /// JSON list of servers served by an inventory service,
+    /// fetched with the token of the user
+    Http {
+        url: String,
+        /// Minutes the fetched servers are used
+        /// for before they are fetched again
+        #[serde(default = "default_ttl_minutes")]
+        ttl_minutes: u64
+    },
+    /// Ansible dynamic inventory script, run with `--list`
+    AnsibleScript {
+        path: String
+    },
+    /// Static Ansible inventory in INI or YAML
+    Ansible {
+        path: String
+    }
+}
+
+fn default_ttl_minutes() -> u64 {
+    return 5;
+}
+
+impl InventorySource {
+    /// Method to get the groups and servers of the source. What
+    /// happened while fetching them is passed to the notices
+    pub async fn load(&self, http: &HttpSettings, token: &str, notices: &Notices) -> Result<Inventory, Box<dyn std::error::Error>> {
+        return match self {
+            InventorySource::Http { url, ttl_minutes } => {
+                Ok(Inventory::new(BTreeMap::new(), fetch_cached(url, *ttl_minutes, http, token, notices).await?))
+            },
+            InventorySource::AnsibleScript { path } => AnsibleInventory::from_script(&config_path(path))?.to_inventory(),
+            InventorySource::Ansible { path } => AnsibleInventory::from_file(&config_path(path))?.to_inventory()
+        };
+    }
+}
+
+/// Returns the path, relative paths being
+/// inside the config directory
+fn config_path(path: &str) -> PathBuf {
+    return crate::paths::config_dir().join(path);
+}
+
+/// Servers fetched from an inventory service, stored in
+/// `cache/inventory` of the data directory
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedServers {
+    url: String,
+    fetched_at: DateTime<Utc>,
+    servers: Vec<Target>
+}
+
+impl CachedServers {
+    /// Returns the path of the cache of the URL for the
+    /// token, as the service may answer every user differently
+    fn path(url: &str, token: &str) -> PathBuf {
+        let digest = hex::encode(Sha256::digest(format!("{}\n{}", url, token).as_bytes()));
+        return crate::paths::data_dir().join("cache").join("inventory").join(format!("{}.json", &digest[..32]));
+    }
+
+    fn load(url: &str, token: &str) -> Option<CachedServers> {
+        let contents = fs::read_to_string(CachedServers::path(url, token)).ok()?;
+        return serde_json::from_str::<CachedServers>(&contents).ok().filter(|cache| cache.url == url);
+    }
+
+    /// Method to store the servers in a file only the user can read
+    fn save(&self, token: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = CachedServers::path(&self.url, token);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        return write_private(&path, serde_json::to_string_pretty(self)?.as_bytes());
+    }
+
+    fn is_fresh(&self, ttl_minutes: u64) -> bool {
+        return self.fetched_at + Duration::minutes(ttl_minutes as i64) > Utc::now();
+    }
+}
+
+/// Method to get the servers of the inventory service. Servers
+/// fetched within the time to live are used as they are, and
+/// older ones when the service can not be reached
+async fn fetch_cached(url: &str, ttl_minutes: u64, http: &HttpSettings, token: &str, notices: &Notices) -> Result<Vec<Target>, Box<dyn std::error::Error>> {
+    let cached = CachedServers::load(url, token);
+    if let Some(cache) = &cached {
+        if cache.is_fresh(ttl_minutes) {
+            return Ok(cache.servers.clone());
+        }
+    }
+
+    let fetched = match fetch(url, http, token, notices).await {
+        Ok(servers) => servers,
+        Err(err) => return match cached {
+            Some(cache) => {
+                notices(&format!("Using the servers of {} fetched at {}: {}", url, cache.fetched_at.format("%Y-%m-%d %H:%M:%S"), err));
+                Ok(cache.servers)
+            },
+            None => Err(err)
+        }
+    };
+
+    let cache = CachedServers { url: url.to_string(), fetched_at: Utc::now(), servers: fetched };
+    cache.save(token)?;
+    return Ok(cache.servers);
+}
+
+/// Method to fetch the servers from the inventory service. It answers
+/// with a list of servers, or an object holding it in `servers`, each
+/// server having the fields of `server.yaml`. Servers without a name
+/// are named after their host
+async fn fetch(url: &str, http: &HttpSettings, token: &str, notices: &Notices) -> Result<Vec<Target>, Box<dyn std::error::Error>> {
+    let client = HttpClient::new(url, http)?.with_token(token).with_notices(notices);
+    let body: Value = client.get_json("").await?;
+    let entries = match body {
+        Value::Array(entries) => entries,
+        Value::Object(mut object) => match object.remove("servers") {
+            Some(Value::Array(entries)) => entries,
+            _ => return Err(format!("{} answered an object without a `servers` list", url).into())
+        },
+        _ => return Err(format!("{} did not answer a list of servers", url).into())
+    };
+
+    let mut servers = Vec::with_capacity(entries.len());
+    for (i, mut entry) in entries.into_iter().enumerate() {
+        if let Value::Object(fields) = &mut entry {
+            if !fields.contains_key("name") {
+                if let Some(host) = fields.get("host").cloned() {
+                    fields.insert(String::from("name"), host);
+                }
+            }
+        }
+        let server: Target = serde_json::from_value(entry)
+            .map_err(|err| format!("Invalid server {} from {}: {}", i + 1, url, err))?;
+        servers.push(server);
+    }
+    return Ok(servers);
+}
+
+/// Method to load the servers of `server.yaml` merged with the
+/// servers of the configured sources, fetched with the token of
+/// the user. `server.yaml` may be missing when sources are configured.
+/// Stale servers used while a source is down are passed to the notices
+///
+/// # Examples
+/// ```no_run
+/// let inventory = sources::load_inventory(&settings, &user.token, &http::stderr_notices()).await?;
+/// ```
+pub async fn load_inventory(settings: &Settings, token: &str, notices: &Notices) -> Result<Inventory, Box<dyn std::error::Error>> {
+    let path = crate::paths::config_dir().join(Inventory::FILE_NAME);
+    let mut inventory = if path.exists() || settings.inventory.sources.is_empty() {
+        Inventory::load(&path)?
+    } else {
+        Inventory::default()
+    };
+
+    for source in &settings.inventory.sources {
+        inventory.merge(source.load(&settings.http, token, notices).await?)?;
+    }
+    return Ok(inventory);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use crate::http::stderr_notices;
+    use crate::paths::testing::TestHome;
+
+    /// An inventory service answering every request with the same
+    /// JSON body, recording the requests it received
+    struct Stub {
+        url: String,
+        requests: Arc<Mutex<Vec<String>>>
+    }
+
+    impl Stub {
+        fn start(body: &'static str) -> Stub {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/servers", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let received = Arc::clone(&requests);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut request = String::new();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                            break;
+                        }
+                        request.push_str(&line);
+                    }
+                    received.lock().unwrap().push(request);
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                }
+            });
+            return Stub { url, requests };
+        }
+
+        fn hits(&self) -> usize {
+            return self.requests.lock().unwrap().len();
+        }
+    }
+
+    /// Returns the URL of a port nothing listens on
+    fn unreachable_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        return format!("http://{}/servers", listener.local_addr().unwrap());
+    }
+
+    fn http() -> HttpSettings {
+        return HttpSettings { retries: 0, ..HttpSettings::default() };
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        return tokio::runtime::Runtime::new().unwrap().block_on(future);
+    }
+
+    fn names(servers: &[Target]) -> Vec<&str> {
+        return servers.iter().map(|s| s.name.as_str()).collect();
+    }
+
+    #[test]
+    fn lists_and_objects_of_servers_are_accepted() {
+        let list = Stub::start(r#"[{"name": "web-1", "host": "10.0.0.1", "user": "deploy"}, {"host": "10.0.0.2", "user": "deploy"}]"#);
+        let servers = block_on(fetch(&list.url, &http(), "secret", &stderr_notices())).unwrap();
+        assert_eq!(names(&servers), vec!["web-1", "10.0.0.2"]);
+        assert!(list.requests.lock().unwrap()[0].to_lowercase().contains("authorization: bearer secret"));
+
+        let object = Stub::start(r#"{"servers": [{"name": "db-1", "host": "10.0.0.3", "user": "deploy", "port": 2222}]}"#);
+        let servers = block_on(fetch(&object.url, &http(), "", &stderr_notices())).unwrap();
+        assert_eq!((servers[0].name.as_str(), servers[0].port), ("db-1", 2222));
+
+        let invalid = Stub::start(r#"{"hosts": []}"#);
+        assert!(block_on(fetch(&invalid.url, &http(), "", &stderr_notices())).is_err());
+        let invalid = Stub::start(r#"[{"name": "no-host"}]"#);
+        assert!(block_on(fetch(&invalid.url, &http(), "", &stderr_notices())).is_err());
+    }
+
+    #[test]
+    fn servers_are_fetched_again_once_the_cache_expires() {
+        let _home = TestHome::new();
+        let stub = Stub::start(r#"[{"host": "10.0.0.1", "user": "deploy"}]"#);
+
+        let servers = block_on(fetch_cached(&stub.url, 5, &http(), "", &stderr_notices())).unwrap();
+        assert_eq!(names(&servers), vec!["10.0.0.1"]);
+        block_on(fetch_cached(&stub.url, 5, &http(), "", &stderr_notices())).unwrap();
+        assert_eq!(stub.hits(), 1);
+
+        // A cache older than the time to live is not used
+        let mut cache = CachedServers::load(&stub.url, "").unwrap();
+        cache.fetched_at = Utc::now() - Duration::minutes(6);
+        cache.save("").unwrap();
+        block_on(fetch_cached(&stub.url, 5, &http(), "", &stderr_notices())).unwrap();
+        assert_eq!(stub.hits(), 2);
+    }
+
+    #[test]
+    fn every_token_has_its_own_private_cache() {
+        let _home = TestHome::new();
+        let stub = Stub::start(r#"[{"host": "10.0.0.1", "user": "deploy"}]"#);
+
+        block_on(fetch_cached(&stub.url, 5, &http(), "alice", &stderr_notices())).unwrap();
+        block_on(fetch_cached(&stub.url, 5, &http(), "bob", &stderr_notices())).unwrap();
+        assert_eq!(stub.hits(), 2);
+        assert!(CachedServers::load(&stub.url, "carol").is_none());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(CachedServers::path(&stub.url, "alice")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn stale_servers_are_used_while_the_service_is_down() {
+        let _home = TestHome::new();
+        let url = unreachable_url();
+        let log = Arc::new(Mutex::new(Vec::<String>::new()));
+        let received = Arc::clone(&log);
+        let notices: Notices = Arc::new(move |message| received.lock().unwrap().push(message.to_string()));
+        assert!(block_on(fetch_cached(&url, 5, &http(), "", &notices)).is_err());
+
+        let servers: Vec<Target> = serde_json::from_str(r#"[{"name": "web-1", "host": "10.0.0.1", "user": "deploy"}]"#).unwrap();
+        let cache = CachedServers { url: url.clone(), fetched_at: Utc::now() - Duration::days(1), servers };
+        cache.save("").unwrap();
+        let servers = block_on(fetch_cached(&url, 5, &http(), "", &notices)).unwrap();
+        assert_eq!(names(&servers), vec!["web-1"]);
+        assert!(log.lock().unwrap().last().unwrap().starts_with(&format!("Using the servers of {}", url)));
+    }
+
+    #[test]
+    fn sources_are_merged_with_the_servers_file() {
+        let home = TestHome::new();
+        home.config("server.yaml", "servers: [{ name: web-1, host: 10.0.0.1, user: deploy }]");
+        let stub = Stub::start(r#"[{"name": "db-1", "host": "10.0.0.2", "user": "deploy"}]"#);
+        let settings: Settings = serde_yaml::from_str(&format!(
+            "{{ http: {{ retries: 0 }}, inventory: {{ sources: [{{ type: http, url: '{}' }}] }} }}",
+            stub.url
+        )).unwrap();
+        let inventory = block_on(load_inventory(&settings, "", &stderr_notices())).unwrap();
+        assert_eq!(names(inventory.servers()), vec!["web-1", "db-1"]);
+    }
+}
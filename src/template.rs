@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use serde_json::{json, Value};
+
+use crate::inventory::Target;
+
+/// Opening and closing delimiters of a variable
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+/// Method to build the variables available to the templates
+/// of a target:
+/// - `host`: name, host, port, user, groups and tags of the target
+/// - `vars`: variables of the target and its groups
+/// - `params`: run-level parameters (`--var` or prompted)
+pub fn context(target: &Target, params: &BTreeMap<String, String>) -> Value {
+    return json!({
+        "host": {
+            "name": target.name,
+            "host": target.host,
+            "port": target.port,
+            "user": target.user,
+            "groups": target.groups,
+            "tags": target.tags
+        },
+        "vars": target.vars,
+        "params": params
+    });
+}
+
+/// Filter inserting a value as is in a command
+const RAW: &str = "raw";
+
+/// A piece of a template
+enum Part<'a> {
+    /// Text copied as is, either outside of the delimiters
+    /// or a quoted string such as `{{ '{{' }}`
+    Text(&'a str),
+    Variable {
+        /// The expression between the delimiters, trimmed
+        expression: &'a str,
+        path: &'a str,
+        /// Set by the `raw` filter, the value is not quoted
+        raw: bool
+    }
+}
+
+/// Method to parse the expression of a variable:
+/// a dotted path optionally followed by `| raw`
+fn parse_variable<'a>(expression: &'a str, template: &str) -> Result<Part<'a>, Box<dyn std::error::Error>> {
+    let (path, raw) = match expression.split_once('|') {
+        Some((path, filter)) if filter.trim() == RAW => (path.trim(), true),
+        Some((_, filter)) => return Err(format!("Unknown filter `{}` in `{}`", filter.trim(), template).into()),
+        None => (expression, false)
+    };
+    let valid = !path.is_empty() && path.split('.').all(|segment| {
+        !segment.is_empty() && segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    });
+    if !valid {
+        return Err(format!("Invalid variable `{}` in `{}`", expression, template).into());
+    }
+    return Ok(Part::Variable { expression, path, raw });
+}
+
+/// Splits the template in the literal text and the variables.
+/// A quoted string between the delimiters is literal text, so
+/// `{{ '{{.Names}}' }}` renders as `{{.Names}}`
+fn parse(template: &str) -> Result<Vec<Part<'_>>, Box<dyn std::error::Error>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find(OPEN) {
+        parts.push(Part::Text(&rest[..start]));
+        let after = &rest[start + OPEN.len()..];
+        let trimmed = after.trim_start();
+
+        // A quoted string may contain the delimiters
+        if let Some(quote) = trimmed.chars().next().filter(|c| *c == '\'' || *c == '"') {
+            let literal = &trimmed[1..];
+            let end = match literal.find(quote) {
+                Some(end) => end,
+                None => return Err(format!("Unclosed string in `{}`", template).into())
+            };
+            rest = match literal[end + 1..].trim_start().strip_prefix(CLOSE) {
+                Some(rest) => rest,
+                None => return Err(format!("Expected `{}` after the string in `{}`", CLOSE, template).into())
+            };
+            parts.push(Part::Text(&literal[..end]));
+            continue;
+        }
+
+        let end = match after.find(CLOSE) {
+            Some(end) => end,
+            None => return Err(format!("Unclosed `{}` in `{}`", OPEN, template).into())
+        };
+        parts.push(parse_variable(after[..end].trim(), template)?);
+        rest = &after[end + CLOSE.len()..];
+    }
+    parts.push(Part::Text(rest));
+    return Ok(parts);
+}
+
+/// Returns the variables referenced by the template
+///
+/// # Examples
+/// ```no_run
+/// let names = template::variables("echo {{ params.version }}")?;
+/// assert_eq!(names, vec!["params.version"]);
+/// ```
+pub fn variables(template: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut names: Vec<String> = Vec::new();
+    for part in parse(template)? {
+        if let Part::Variable { path, .. } = part {
+            if !names.iter().any(|n| n == path) {
+                names.push(path.to_string());
+            }
+        }
+    }
+    return Ok(names);
+}
+
+/// Looks up a dotted path in the context
+fn lookup<'a>(context: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut value = context;
+    for segment in path.split('.') {
+        value = match value {
+            Value::Object(map) => map.get(segment)?,
+            Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None
+        };
+    }
+    return Some(value);
+}
+
+/// Quotes a value to be used as a single shell word. Words
+/// made of characters the shell does not interpret are kept as is
+fn quote_word(word: &str) -> String {
+    let safe = !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c));
+    if safe {
+        return word.to_string();
+    }
+    return format!("'{}'", word.replace('\'', "'\\''"));
+}
+
+/// Formats a value to be inserted in the text. Lists are
+/// joined with spaces. In commands every word is quoted
+fn format_value(path: &str, value: &Value, quote: bool) -> Result<String, Box<dyn std::error::Error>> {
+    return match value {
+        Value::Null => Err(format!("Undefined variable `{}`", path).into()),
+        Value::String(s) if quote => Ok(quote_word(s)),
+        Value::String(s) => Ok(s.clone()),
+        Value::Bool(_) | Value::Number(_) => Ok(value.to_string()),
+        Value::Array(items) => {
+            let formatted: Result<Vec<String>, _> = items.iter().map(|v| format_value(path, v, quote)).collect();
+            Ok(formatted?.join(" "))
+        },
+        Value::Object(_) => Err(format!("Variable `{}` is a map and can not be used in a command", path).into())
+    };
+}
+
+/// Method to render a template. Rendering is strict, using
+/// a variable which is not defined is an error
+///
+/// # Examples
+/// ```no_run
+/// let body = template::render("Run {{ run.id }} by {{ run.user }}", &summary)?;
+/// ```
+pub fn render(template: &str, context: &Value) -> Result<String, Box<dyn std::error::Error>> {
+    return render_partial(template, context, None);
+}
+
+/// Method to render a template leaving the variables under
+/// `deferred` untouched, so they can be shown before their
+/// values are known. Every other variable must be defined.
+/// Values are inserted as is
+///
+/// # Examples
+/// ```no_run
+/// // "deploy {{ outputs.build.version }}" is kept as is
+/// let dest = template::render_partial(&dest, &context, Some("outputs"))?;
+/// ```
+pub fn render_partial(template: &str, context: &Value, deferred: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+    return render_with(template, context, deferred, false);
+}
+
+/// Method to render a command run by the shell. Every value is
+/// quoted as a shell word, or as one word per item for lists,
+/// unless the variable uses the `raw` filter
+///
+/// # Examples
+/// ```no_run
+/// // "systemctl restart 'my service'"
+/// let command = template::render_command("systemctl restart {{ vars.service }}", &context, None)?;
+/// // "ls -la"
+/// let command = template::render_command("ls {{ params.flags | raw }}", &context, None)?;
+/// ```
+pub fn render_command(template: &str, context: &Value, deferred: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+    return render_with(template, context, deferred, true);
+}
+
+fn render_with(template: &str, context: &Value, deferred: Option<&str>, quote: bool) -> Result<String, Box<dyn std::error::Error>> {
+    let mut output = String::with_capacity(template.len());
+    for part in parse(template)? {
+        let (expression, path, raw) = match part {
+            Part::Text(text) => {
+                output.push_str(text);
+                continue;
+            },
+            Part::Variable { expression, path, raw } => (expression, path, raw)
+        };
+
+        if let Some(root) = deferred {
+            if path.split('.').next() == Some(root) {
+                output.push_str(&format!("{} {} {}", OPEN, expression, CLOSE));
+                continue;
+            }
+        }
+
+        match lookup(context, path) {
+            Some(value) => output.push_str(&format_value(path, value, quote && !raw)?),
+            None => return Err(format!("Undefined variable `{}`", path).into())
+        }
+    }
+    return Ok(output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Value {
+        return json!({
+            "host": { "name": "web-1", "tags": ["a b", "c"] },
+            "params": {
+                "message": "hello; rm -rf /",
+                "quote": "it's",
+                "flags": "-la --color",
+                "nested": "{{ outputs.build.version }}",
+                "unclosed": "{{foo"
+            }
+        });
+    }
+
+    #[test]
+    fn commands_quote_the_values() {
+        let render = |template| render_command(template, &context(), None).unwrap();
+        assert_eq!(render("echo {{ params.message }}"), "echo 'hello; rm -rf /'");
+        assert_eq!(render("echo {{ params.quote }}"), "echo 'it'\\''s'");
+        assert_eq!(render("ping {{ host.name }}"), "ping web-1");
+        assert_eq!(render("echo {{ host.tags }}"), "echo 'a b' c");
+    }
+
+    #[test]
+    fn raw_values_are_not_quoted() {
+        assert_eq!(render_command("ls {{ params.flags | raw }}", &context(), None).unwrap(), "ls -la --color");
+        assert_eq!(render("{{ params.message }}", &context()).unwrap(), "hello; rm -rf /");
+        assert!(render_command("{{ params.flags | upper }}", &context(), None).is_err());
+    }
+
+    #[test]
+    fn quoted_strings_are_literal_text() {
+        let command = render_command("docker ps --format '{{ \"{{.Names}}\" }}'", &context(), None).unwrap();
+        assert_eq!(command, "docker ps --format '{{.Names}}'");
+        assert_eq!(render("{{ '{{' }} host.name }}", &context()).unwrap(), "{{ host.name }}");
+        assert_eq!(variables("{{ '{{ params.hidden }}' }} {{ params.shown | raw }}").unwrap(), vec!["params.shown"]);
+        assert!(render("{{ 'unclosed }}", &context()).is_err());
+        assert!(render("{{ 'text' trailing }}", &context()).is_err());
+    }
+
+    #[test]
+    fn values_are_not_rendered_again() {
+        let text = "{{ params.nested }} {{ params.unclosed }} {{ outputs.build.version | raw }}";
+        let rendered = render_partial(text, &context(), Some("outputs")).unwrap();
+        assert_eq!(rendered, "{{ outputs.build.version }} {{foo {{ outputs.build.version | raw }}");
+    }
+
+    #[test]
+    fn undefined_variables_are_errors() {
+        assert!(render("{{ params.missing }}", &context()).is_err());
+        assert!(render("{{ params.message", &context()).is_err());
+        assert!(render("{{ host }}", &context()).is_err());
+    }
+}
@@ -0,0 +1,511 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use ssh2::{FileStat, OpenFlags, OpenType, Session};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::job::shell_quote;
+use crate::template;
+
+/// Size of the chunks in which files are copied
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Protocol used to copy the files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Sftp,
+    Scp
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        return Protocol::Sftp;
+    }
+}
+
+/// Whether the files are copied to or from the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download
+}
+
+/// A file or directory copied between the local machine and
+/// a server. Uploads copy the local `src` to the remote `dest`,
+/// downloads copy the remote `src` into `dest/<server name>/`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub src: String,
+    pub dest: String,
+    /// Octal permissions of the copied files, e.g. `"0644"`.
+    /// Defaults to the permissions of the source files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// Owner of the uploaded files, as `user` or `user:group`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Compare the sha256 of the copied files on both sides
+    #[serde(default = "Transfer::default_checksum")]
+    pub checksum: bool
+}
+
+/// Reports the progress of a file copy every 10%,
+/// as lines of the output of the job
+struct Progress<'a> {
+    label: String,
+    total: u64,
+    done: u64,
+    reported: u64,
+    on_line: &'a dyn Fn(&str)
+}
+
+impl<'a> Progress<'a> {
+    fn new(label: String, total: u64, on_line: &'a dyn Fn(&str)) -> Self {
+        return Progress { label, total, done: 0, reported: 0, on_line };
+    }
+
+    fn advance(&mut self, bytes: usize) {
+        self.done += bytes as u64;
+        let tenths = (self.done * 10).checked_div(self.total).unwrap_or(10);
+        if tenths > self.reported {
+            self.reported = tenths;
+            (self.on_line)(&format!("{}: {}% ({}/{} bytes)", self.label, tenths * 10, self.done, self.total));
+        }
+    }
+}
+
+/// Method to copy a reader into a writer reporting the
+/// progress. Returns the sha256 of the copied bytes
+fn copy(reader: &mut dyn Read, writer: &mut dyn Write, progress: &mut Progress) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..read])?;
+        hasher.update(&buffer[..read]);
+        progress.advance(read);
+    }
+    if progress.total == 0 {
+        progress.advance(0);
+    }
+    return Ok(hex::encode(hasher.finalize()));
+}
+
+/// Method to run a command on the server, failing
+/// if it exits with a non zero status
+pub fn remote_exec(session: &Session, command: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut channel = session.channel_session()?;
+    channel.exec(command)?;
+    let mut output = String::new();
+    channel.read_to_string(&mut output)?;
+    channel.wait_close()?;
+
+    let exit_status = channel.exit_status()?;
+    if exit_status != 0 {
+        return Err(format!("`{}` exited with status {}: {}", command, exit_status, output.trim_end()).into());
+    }
+    return Ok(output);
+}
+
+/// Template of the temporary files created on the servers
+pub const TEMP_TEMPLATE: &str = "/tmp/basecamp.XXXXXXXXXX";
+
+/// Method to create an empty file from the `mktemp` template with
+/// the command runner, returning its path. The file did not exist
+/// before, its name can not be guessed and only the user running
+/// the command can read and write it
+pub fn remote_mktemp<F>(exec: F, template: &str) -> Result<String, Box<dyn std::error::Error>>
+    where F : Fn(&str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = exec(&format!("mktemp {}", shell_quote(template)))?;
+    // Messages of sudo or su may come before the path
+    return match output.lines().map(str::trim).rfind(|line| !line.is_empty()) {
+        Some(path) => Ok(path.to_string()),
+        None => Err(format!("mktemp printed no path for {}", template).into())
+    };
+}
+
+/// Method to write the content to an existing file on the
+/// server over SCP, which keeps the permissions of the file
+pub fn scp_write(session: &Session, path: &str, content: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut channel = session.scp_send(Path::new(path), 0o600, content.len() as u64, None)?;
+    channel.write_all(content)?;
+    channel.send_eof()?;
+    channel.wait_eof()?;
+    channel.close()?;
+    channel.wait_close()?;
+    return Ok(());
+}
+
+/// Returns the sha256 of a file on the server
+pub fn remote_sha256(session: &Session, path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let output = remote_exec(session, &format!("sha256sum -- {}", shell_quote(path)))?;
+    return match output.split_whitespace().next() {
+        Some(hash) => Ok(hash.to_string()),
+        None => Err(format!("Could not compute the checksum of {}", path).into())
+    };
+}
+
+/// Method to check that a name, such as the name of a
+/// server, can be used as a single local directory name
+fn directory_name(name: &str) -> Result<&str, Box<dyn std::error::Error>> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\\') {
+        return Err(format!("`{}` can not be used as a directory name", name).into());
+    }
+    return Ok(name);
+}
+
+/// Returns the permission bits of a local file
+#[cfg(unix)]
+fn local_mode(metadata: &fs::Metadata) -> i32 {
+    use std::os::unix::fs::PermissionsExt;
+    return (metadata.permissions().mode() & 0o7777) as i32;
+}
+
+#[cfg(not(unix))]
+fn local_mode(_metadata: &fs::Metadata) -> i32 {
+    return 0o644;
+}
+
+/// Method to set the permission bits of a local file
+#[cfg(unix)]
+fn set_local_mode(path: &Path, mode: i32) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode as u32))?;
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+fn set_local_mode(_path: &Path, _mode: i32) -> Result<(), Box<dyn std::error::Error>> {
+    return Ok(());
+}
+
+/// Parses octal permissions such as `0644`
+pub fn parse_mode(mode: Option<&str>) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+    return match mode {
+        Some(mode) => match i32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+            Ok(mode) if (0..=0o7777).contains(&mode) => Ok(Some(mode)),
+            _ => Err(format!("invalid mode `{}`, expected octal permissions such as 0644", mode).into())
+        },
+        None => Ok(None)
+    };
+}
+
+/// Joins a relative path to a remote path
+fn remote_join(base: &str, relative: &Path) -> String {
+    let relative = relative.to_string_lossy().replace('\\', "/");
+    if relative.is_empty() {
+        return base.to_string();
+    }
+    return format!("{}/{}", base.trim_end_matches('/'), relative);
+}
+
+/// Returns the last component of a path
+fn file_name(path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    return match Path::new(path.trim_end_matches('/')).file_name() {
+        Some(name) => Ok(name.to_string_lossy().to_string()),
+        None => Err(format!("{} has no file name", path).into())
+    };
+}
+
+impl Transfer {
+    fn default_checksum() -> bool {
+        return true;
+    }
+
+    /// Returns the permissions set on the copied files, if any
+    fn parsed_mode(&self) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+        return parse_mode(self.mode.as_deref());
+    }
+
+    /// Method to check the transfer before it runs
+    pub fn validate(&self, direction: Direction) -> Result<(), Box<dyn std::error::Error>> {
+        if self.src.is_empty() || self.dest.is_empty() {
+            return Err("a transfer must have a src and a dest".into());
+        }
+        self.parsed_mode()?;
+        if direction == Direction::Download && self.owner.is_some() {
+            return Err("owner can only be set on uploads".into());
+        }
+        return Ok(());
+    }
+
+    /// Method to create a copy of the transfer with
+    /// its paths rendered using the given variables
+    pub fn render(&self, context: &Value, deferred: Option<&str>) -> Result<Transfer, Box<dyn std::error::Error>> {
+        let owner = match &self.owner {
+            Some(owner) => Some(template::render_partial(owner, context, deferred)?),
+            None => None
+        };
+        return Ok(Transfer {
+            src: template::render_partial(&self.src, context, deferred)?,
+            dest: template::render_partial(&self.dest, context, deferred)?,
+            owner,
+            ..self.clone()
+        });
+    }
+
+    /// Returns the templates used by the transfer
+    pub fn templates(&self) -> Vec<&str> {
+        let mut templates: Vec<&str> = vec![&self.src, &self.dest];
+        templates.extend(self.owner.as_deref());
+        return templates;
+    }
+
+    /// Returns a readable description of the transfer
+    pub fn describe(&self, direction: Direction) -> String {
+        let mut line = match direction {
+            Direction::Upload => format!("upload {} -> {}", self.src, self.dest),
+            Direction::Download => format!("download {} -> {}/<server>", self.src, self.dest)
+        };
+        if let Some(mode) = &self.mode {
+            line.push_str(&format!(" mode={}", mode));
+        }
+        if let Some(owner) = &self.owner {
+            line.push_str(&format!(" owner={}", owner));
+        }
+        let protocol = match self.protocol {
+            Protocol::Sftp => "sftp",
+            Protocol::Scp => "scp"
+        };
+        return format!("{} ({})", line, protocol);
+    }
+
+    /// Method to run the transfer on the session. Returns a
+    /// line per copied file with its size and checksum. The
+    /// progress of the copies is passed to `on_line`
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let summary = transfer.execute(Direction::Upload, &session, &target.name, &|line| println!("{}", line))?;
+    /// ```
+    pub fn execute(&self, direction: Direction, session: &Session, server: &str, on_line: &dyn Fn(&str)) -> Result<String, Box<dyn std::error::Error>> {
+        return match direction {
+            Direction::Upload => self.upload(session, on_line),
+            Direction::Download => self.download(session, server, on_line)
+        };
+    }
+
+    /// Method to copy the local file or directory to the server
+    fn upload(&self, session: &Session, on_line: &dyn Fn(&str)) -> Result<String, Box<dyn std::error::Error>> {
+        let src = Path::new(&self.src);
+        let metadata = fs::metadata(src)
+            .map_err(|err| format!("Could not read {}: {}", self.src, err))?;
+
+        // List the files to copy with their path relative to `src`
+        let mut files: Vec<PathBuf> = Vec::new();
+        let dest = if metadata.is_dir() {
+            let mut dirs = vec![PathBuf::new()];
+            while let Some(dir) = dirs.pop() {
+                for entry in fs::read_dir(src.join(&dir))? {
+                    let entry = entry?;
+                    let relative = dir.join(entry.file_name());
+                    if entry.file_type()?.is_dir() {
+                        dirs.push(relative);
+                    } else {
+                        files.push(relative);
+                    }
+                }
+            }
+            files.sort();
+            self.dest.clone()
+        } else {
+            files.push(PathBuf::new());
+            if self.dest.ends_with('/') {
+                format!("{}{}", self.dest, file_name(&self.src)?)
+            } else {
+                self.dest.clone()
+            }
+        };
+
+        // Create the remote directories
+        let mut dirs: Vec<String> = Vec::new();
+        for file in &files {
+            let remote = remote_join(&dest, file);
+            if let Some((dir, _)) = remote.rsplit_once('/') {
+                if !dir.is_empty() && !dirs.iter().any(|d| d == dir) {
+                    dirs.push(dir.to_string());
+                }
+            }
+        }
+        if !dirs.is_empty() {
+            let quoted: Vec<String> = dirs.iter().map(|d| shell_quote(d)).collect();
+            remote_exec(session, &format!("mkdir -p -- {}", quoted.join(" ")))?;
+        }
+
+        let mode = self.parsed_mode()?;
+        let sftp = match self.protocol {
+            Protocol::Sftp => Some(session.sftp()?),
+            Protocol::Scp => None
+        };
+
+        let mut summary = String::new();
+        for file in &files {
+            let local = if file.as_os_str().is_empty() { src.to_path_buf() } else { src.join(file) };
+            let remote = remote_join(&dest, file);
+            let local_metadata = fs::metadata(&local)?;
+            let file_mode = mode.unwrap_or_else(|| local_mode(&local_metadata));
+            let size = local_metadata.len();
+
+            let mut reader = fs::File::open(&local)?;
+            let mut progress = Progress::new(format!("upload {}", local.display()), size, on_line);
+            let hash = match &sftp {
+                Some(sftp) => {
+                    // The file is created with its mode, which is also set on
+                    // existing files, before any of the content is written
+                    let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+                    let mut writer = sftp.open_mode(Path::new(&remote), flags, file_mode, OpenType::File)?;
+                    sftp.setstat(Path::new(&remote), FileStat {
+                        size: None,
+                        uid: None,
+                        gid: None,
+                        perm: Some(file_mode as u32),
+                        atime: None,
+                        mtime: None
+                    })?;
+                    copy(&mut reader, &mut writer, &mut progress)?
+                },
+                None => {
+                    let mut channel = session.scp_send(Path::new(&remote), file_mode, size, None)?;
+                    let hash = copy(&mut reader, &mut channel, &mut progress)?;
+                    channel.send_eof()?;
+                    channel.wait_eof()?;
+                    channel.close()?;
+                    channel.wait_close()?;
+                    hash
+                }
+            };
+
+            if self.checksum {
+                let remote_hash = remote_sha256(session, &remote)?;
+                if remote_hash != hash {
+                    return Err(format!("Checksum mismatch for {}: local {}, remote {}", remote, hash, remote_hash).into());
+                }
+            }
+            summary.push_str(&format!("uploaded {} -> {} ({} bytes, mode {:04o}, sha256 {})\n", local.display(), remote, size, file_mode, hash));
+        }
+
+        if let Some(owner) = &self.owner {
+            remote_exec(session, &format!("chown -R -- {} {}", shell_quote(owner), shell_quote(&dest)))?;
+            summary.push_str(&format!("changed owner of {} to {}\n", dest, owner));
+        }
+        return Ok(summary);
+    }
+
+    /// Method to list the remote files under `src`, with their
+    /// path relative to `src`. A file is listed with an empty path
+    fn remote_files(&self, session: &Session) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        let src = self.src.trim_end_matches('/');
+        let mut files = Vec::new();
+        match self.protocol {
+            Protocol::Sftp => {
+                let sftp = session.sftp()?;
+                if !sftp.stat(Path::new(src))?.is_dir() {
+                    return Ok(vec![PathBuf::new()]);
+                }
+                let mut dirs = vec![PathBuf::new()];
+                while let Some(dir) = dirs.pop() {
+                    for (path, stat) in sftp.readdir(&Path::new(src).join(&dir))? {
+                        let relative = dir.join(path.file_name().unwrap_or_default());
+                        if stat.is_dir() {
+                            dirs.push(relative);
+                        } else {
+                            files.push(relative);
+                        }
+                    }
+                }
+            },
+            Protocol::Scp => {
+                // SCP has no listing, use find instead
+                let output = remote_exec(session, &format!("find {} -type f", shell_quote(src)))?;
+                for line in output.lines() {
+                    let relative = line.strip_prefix(src).unwrap_or(line).trim_start_matches('/');
+                    files.push(PathBuf::from(relative));
+                }
+            }
+        }
+        files.sort();
+        return Ok(files);
+    }
+
+    /// Method to copy the remote file or directory into
+    /// the directory of the server under `dest`. Nothing is
+    /// written outside of it
+    fn download(&self, session: &Session, server: &str, on_line: &dyn Fn(&str)) -> Result<String, Box<dyn std::error::Error>> {
+        let base = Path::new(&self.dest).join(directory_name(server)?).join(file_name(&self.src)?);
+        let mode = self.parsed_mode()?;
+        let sftp = match self.protocol {
+            Protocol::Sftp => Some(session.sftp()?),
+            Protocol::Scp => None
+        };
+
+        let mut summary = String::new();
+        for file in self.remote_files(session)? {
+            if !file.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(format!("Refusing to download {} outside of {}", file.display(), base.display()).into());
+            }
+            let remote = remote_join(self.src.trim_end_matches('/'), &file);
+            let local = if file.as_os_str().is_empty() { base.clone() } else { base.join(&file) };
+            if let Some(dir) = local.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut writer = fs::File::create(&local)?;
+
+            let (hash, size, remote_mode) = match &sftp {
+                Some(sftp) => {
+                    let stat = sftp.stat(Path::new(&remote))?;
+                    let size = stat.size.unwrap_or(0);
+                    let mut reader = sftp.open(Path::new(&remote))?;
+                    let mut progress = Progress::new(format!("download {}", remote), size, on_line);
+                    (copy(&mut reader, &mut writer, &mut progress)?, size, stat.perm.map(|p| (p & 0o7777) as i32))
+                },
+                None => {
+                    let (mut channel, stat) = session.scp_recv(Path::new(&remote))?;
+                    let size = stat.size();
+                    // The channel also sends a terminating byte after the file
+                    let mut reader = (&mut channel).take(size);
+                    let mut progress = Progress::new(format!("download {}", remote), size, on_line);
+                    let hash = copy(&mut reader, &mut writer, &mut progress)?;
+                    channel.send_eof()?;
+                    channel.wait_eof()?;
+                    channel.close()?;
+                    channel.wait_close()?;
+                    (hash, size, Some(stat.mode() & 0o7777))
+                }
+            };
+            drop(writer);
+
+            if let Some(file_mode) = mode.or(remote_mode) {
+                set_local_mode(&local, file_mode)?;
+            }
+            if self.checksum {
+                let remote_hash = remote_sha256(session, &remote)?;
+                if remote_hash != hash {
+                    return Err(format!("Checksum mismatch for {}: remote {}, local {}", remote, remote_hash, hash).into());
+                }
+            }
+            summary.push_str(&format!("downloaded {} -> {} ({} bytes, sha256 {})\n", remote, local.display(), size, hash));
+        }
+        return Ok(summary);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downloads_stay_in_the_directory_of_the_server() {
+        assert_eq!(directory_name("web-1.example.com").unwrap(), "web-1.example.com");
+        for name in &["", ".", "..", "../web-1", "web/1", "web\\1"] {
+            assert!(directory_name(name).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use futures::executor::block_on;
+use ssh2::{Channel, ErrorCode, Listener, Session};
+
+use crate::inventory::Target;
+use crate::server::{self, Server};
+
+/// libssh2 error returned by non blocking calls which would block
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+/// Address listened on when the spec gives none
+const DEFAULT_BIND: &str = "127.0.0.1";
+
+/// Time given to a SOCKS client to send its request
+const SOCKS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a tunnel forwards, written like the `-L`, `-R`
+/// and `-D` options of ssh
+#[derive(Clone, Debug, PartialEq)]
+pub enum TunnelSpec {
+    /// Connections to the local port are forwarded from
+    /// the server to `host:port`
+    Local { bind: String, port: u16, host: String, host_port: u16 },
+    /// Connections to the port on the server are forwarded
+    /// from the local machine to `host:port`. The server picks
+    /// the address it listens on, loopback unless its sshd has
+    /// `GatewayPorts` enabled
+    Remote { port: u16, host: String, host_port: u16 },
+    /// A local SOCKS5 proxy opening every connection
+    /// from the server
+    Dynamic { bind: String, port: u16 }
+}
+
+impl TunnelSpec {
+    /// Method to parse a spec given as `-L [bind:]port:host:port`,
+    /// `-R port:host:port` or `-D [bind:]port`
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let spec = TunnelSpec::parse("-L", "5432:db.internal:5432")?;
+    /// ```
+    pub fn parse(kind: &str, spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let port = |value: &str| -> Result<u16, Box<dyn std::error::Error>> {
+            return value.parse::<u16>().map_err(|_| format!("Invalid port `{}` in tunnel {} {}", value, kind, spec).into());
+        };
+        let forward = |parts: &[&str]| -> Result<(String, u16, String, u16), Box<dyn std::error::Error>> {
+            return match parts {
+                [bind_port, host, host_port] => Ok((DEFAULT_BIND.to_string(), port(bind_port)?, host.to_string(), port(host_port)?)),
+                [bind, bind_port, host, host_port] => Ok((bind.to_string(), port(bind_port)?, host.to_string(), port(host_port)?)),
+                _ => Err(format!("Tunnel {} {} must be [bind:]port:host:port", kind, spec).into())
+            };
+        };
+
+        return match kind {
+            "-L" => {
+                let (bind, port, host, host_port) = forward(&parts)?;
+                Ok(TunnelSpec::Local { bind, port, host, host_port })
+            },
+            // ssh2 frees the address of a remote forward before libssh2
+            // reads it, so the server default is always asked for
+            "-R" => match parts.as_slice() {
+                [bind_port, host, host_port] => Ok(TunnelSpec::Remote { port: port(bind_port)?, host: host.to_string(), host_port: port(host_port)? }),
+                [_, _, _, _] => Err(format!("Tunnel -R {} can not choose the address the server listens on, use port:host:port", spec).into()),
+                _ => Err(format!("Tunnel -R {} must be port:host:port", spec).into())
+            },
+            "-D" => match parts.as_slice() {
+                [bind_port] => Ok(TunnelSpec::Dynamic { bind: DEFAULT_BIND.to_string(), port: port(bind_port)? }),
+                [bind, bind_port] => Ok(TunnelSpec::Dynamic { bind: bind.to_string(), port: port(bind_port)? }),
+                _ => Err(format!("Tunnel -D {} must be [bind:]port", spec).into())
+            },
+            _ => Err(format!("Unknown tunnel kind `{}`, expected -L, -R or -D", kind).into())
+        };
+    }
+
+    /// Method to parse a spec typed as a single line,
+    /// e.g. `-L 5432:db.internal:5432`
+    pub fn parse_line(line: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        return match line.trim().split_once(char::is_whitespace) {
+            Some((kind, spec)) => TunnelSpec::parse(kind, spec.trim()),
+            None => Err(format!("Tunnel `{}` must be -L, -R or -D followed by its spec", line.trim()).into())
+        };
+    }
+}
+
+impl fmt::Display for TunnelSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            TunnelSpec::Local { bind, port, host, host_port } => write!(f, "-L {}:{} -> {}:{}", bind, port, host, host_port),
+            TunnelSpec::Remote { port, host, host_port } => write!(f, "-R {} (remote) -> {}:{}", port, host, host_port),
+            TunnelSpec::Dynamic { bind, port } => write!(f, "-D {}:{} (SOCKS5)", bind, port)
+        };
+    }
+}
+
+/// State of a tunnel shared with its thread
+#[derive(Default)]
+struct TunnelState {
+    stop: AtomicBool,
+    connections: AtomicUsize,
+    error: Mutex<Option<String>>
+}
+
+/// Where the connections of a tunnel come from
+enum Source {
+    Local(TcpListener),
+    Remote(Listener)
+}
+
+/// A connection forwarded over a channel
+struct Connection {
+    stream: TcpStream,
+    channel: Channel
+}
+
+/// A port forward over its own session to a server
+pub struct Tunnel {
+    pub id: usize,
+    pub server: String,
+    pub spec: TunnelSpec,
+    state: Arc<TunnelState>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl Tunnel {
+    /// Method to connect to the target, through its jump hosts,
+    /// and start forwarding. The tunnel runs on its own thread
+    /// until it is closed
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let spec = TunnelSpec::parse("-L", "5432:localhost:5432")?;
+    /// let tunnel = Tunnel::open(1, target, spec)?;
+    /// ```
+    pub fn open(id: usize, target: Target, spec: TunnelSpec) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = target.name.clone();
+        let mut server = Server::new(target);
+        block_on(server.connect())?;
+        let session = match server.session() {
+            Some(session) => session.clone(),
+            None => return Err(format!("Not connected to {}", name).into())
+        };
+
+        let source = match &spec {
+            TunnelSpec::Local { bind, port, .. } | TunnelSpec::Dynamic { bind, port } => {
+                let listener = TcpListener::bind((bind.as_str(), *port))
+                    .map_err(|err| format!("Could not listen on {}:{}: {}", bind, port, err))?;
+                listener.set_nonblocking(true)?;
+                Source::Local(listener)
+            },
+            TunnelSpec::Remote { port, .. } => {
+                let (listener, _) = session.channel_forward_listen(*port, None, None)
+                    .map_err(|err| format!("{} refused to listen on port {}: {}", name, port, err))?;
+                Source::Remote(listener)
+            }
+        };
+
+        let state = Arc::new(TunnelState::default());
+        let thread_state = Arc::clone(&state);
+        let thread_spec = spec.clone();
+        let handle = thread::spawn(move || {
+            // The server owns the session and the jump host connection
+            let _server = server;
+            if let Err(err) = Tunnel::run(&session, source, &thread_spec, &thread_state) {
+                *thread_state.error.lock().unwrap() = Some(err.to_string());
+            }
+        });
+
+        return Ok(Tunnel { id, server: name, spec, state, handle: Some(handle) });
+    }
+
+    /// Returns whether the tunnel is still forwarding
+    pub fn is_open(&self) -> bool {
+        return !self.state.stop.load(Ordering::SeqCst) && self.error().is_none();
+    }
+
+    /// Returns the error which stopped the tunnel
+    pub fn error(&self) -> Option<String> {
+        return self.state.error.lock().unwrap().clone();
+    }
+
+    /// Returns the number of connections forwarded so far
+    pub fn connections(&self) -> usize {
+        return self.state.connections.load(Ordering::SeqCst);
+    }
+
+    /// Method to stop forwarding and close the
+    /// connections of the tunnel
+    pub fn close(&mut self) {
+        self.state.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Method to accept the connections and copy their data
+    /// until the tunnel is closed
+    fn run(session: &Session, mut source: Source, spec: &TunnelSpec, state: &TunnelState) -> Result<(), Box<dyn std::error::Error>> {
+        session.set_blocking(false);
+        let mut connections: Vec<Connection> = Vec::new();
+        let mut buffer = vec![0; 32 * 1024];
+
+        while !state.stop.load(Ordering::SeqCst) {
+            let mut idle = true;
+
+            if let Some(connection) = Tunnel::accept(session, &mut source, spec)? {
+                state.connections.fetch_add(1, Ordering::SeqCst);
+                connections.push(connection);
+                idle = false;
+            }
+
+            let mut i = 0;
+            while i < connections.len() {
+                match Tunnel::pump(&mut connections[i], &mut buffer) {
+                    Ok(Some(active)) => {
+                        idle = idle && !active;
+                        i += 1;
+                    },
+                    // A closed or broken connection does not stop the tunnel
+                    Ok(None) | Err(_) => {
+                        let connection = connections.remove(i);
+                        let _ = connection.stream.shutdown(std::net::Shutdown::Both);
+                        let mut channel = connection.channel;
+                        let _ = channel.close();
+                    }
+                }
+            }
+
+            if idle {
+                thread::sleep(Duration::from_millis(2));
+            }
+        }
+
+        for mut connection in connections {
+            let _ = connection.channel.close();
+        }
+        return Ok(());
+    }
+
+    /// Method to accept a pending connection and open its
+    /// channel. Returns `None` when no connection is pending
+    fn accept(session: &Session, source: &mut Source, spec: &TunnelSpec) -> Result<Option<Connection>, Box<dyn std::error::Error>> {
+        match source {
+            Source::Local(listener) => {
+                let (mut stream, peer) = match listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                    Err(err) => return Err(err.into())
+                };
+                stream.set_nonblocking(false)?;
+                let (host, port) = match spec {
+                    TunnelSpec::Local { host, host_port, .. } => (host.clone(), *host_port),
+                    _ => match socks_request(&mut stream) {
+                        Ok(destination) => destination,
+                        // The client gave up or does not speak SOCKS5
+                        Err(_) => return Ok(None)
+                    }
+                };
+
+                let opened = retry(|| session.channel_direct_tcpip(&host, port, Some((&peer.ip().to_string(), peer.port()))));
+                if let TunnelSpec::Dynamic { .. } = spec {
+                    let reply: &[u8] = if opened.is_ok() { &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0] } else { &[5, 5, 0, 1, 0, 0, 0, 0, 0, 0] };
+                    let _ = stream.write_all(reply);
+                }
+                let channel = match opened {
+                    Ok(channel) => channel,
+                    // The server could not reach the destination
+                    Err(_) => return Ok(None)
+                };
+                stream.set_nonblocking(true)?;
+                return Ok(Some(Connection { stream, channel }));
+            },
+            Source::Remote(listener) => {
+                let channel = match listener.accept() {
+                    Ok(channel) => channel,
+                    Err(err) if err.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => return Ok(None),
+                    Err(err) => return Err(err.into())
+                };
+                let (host, port) = match spec {
+                    TunnelSpec::Remote { host, host_port, .. } => (host.clone(), *host_port),
+                    _ => unreachable!()
+                };
+                let stream = match TcpStream::connect((host.as_str(), port)) {
+                    Ok(stream) => stream,
+                    Err(_) => {
+                        let mut channel = channel;
+                        let _ = channel.close();
+                        return Ok(None);
+                    }
+                };
+                stream.set_nonblocking(true)?;
+                return Ok(Some(Connection { stream, channel }));
+            }
+        }
+    }
+
+    /// Method to copy the pending data of a connection in both
+    /// directions. Returns whether data was copied, or `None`
+    /// once either side closed the connection
+    fn pump(connection: &mut Connection, buffer: &mut [u8]) -> Result<Option<bool>, Box<dyn std::error::Error>> {
+        let mut active = false;
+        match connection.stream.read(buffer) {
+            Ok(0) => return Ok(None),
+            Ok(read) => {
+                server::write_all(&mut connection.channel, &buffer[..read])?;
+                active = true;
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+            Err(err) => return Err(err.into())
+        }
+        match connection.channel.read(buffer) {
+            Ok(0) if connection.channel.eof() => return Ok(None),
+            Ok(0) => {},
+            Ok(read) => {
+                server::write_all(&mut connection.stream, &buffer[..read])?;
+                active = true;
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {},
+            Err(err) => return Err(err.into())
+        }
+        return Ok(Some(active));
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Method to retry a call on a non blocking session
+/// until it does not need to wait anymore
+fn retry<T, F>(mut call: F) -> Result<T, ssh2::Error>
+where F : FnMut() -> Result<T, ssh2::Error> {
+    loop {
+        match call() {
+            Err(err) if err.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) => thread::sleep(Duration::from_millis(1)),
+            result => return result
+        }
+    }
+}
+
+/// Method to read the SOCKS5 handshake of a client and return
+/// the destination it asks to connect to. Only CONNECT
+/// requests without authentication are supported
+fn socks_request(stream: &mut TcpStream) -> Result<(String, u16), Box<dyn std::error::Error>> {
+    stream.set_read_timeout(Some(SOCKS_TIMEOUT))?;
+
+    let mut header = [0; 2];
+    stream.read_exact(&mut header)?;
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods)?;
+    if header[0] != 5 || !methods.contains(&0) {
+        stream.write_all(&[5, 0xff])?;
+        return Err("The SOCKS client does not support SOCKS5 without authentication".into());
+    }
+    stream.write_all(&[5, 0])?;
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request)?;
+    if request[1] != 1 {
+        stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0])?;
+        return Err("Only SOCKS5 CONNECT requests are supported".into());
+    }
+    let host = match request[3] {
+        1 => {
+            let mut address = [0; 4];
+            stream.read_exact(&mut address)?;
+            Ipv4Addr::from(address).to_string()
+        },
+        3 => {
+            let mut length = [0; 1];
+            stream.read_exact(&mut length)?;
+            let mut name = vec![0; length[0] as usize];
+            stream.read_exact(&mut name)?;
+            String::from_utf8(name)?
+        },
+        4 => {
+            let mut address = [0; 16];
+            stream.read_exact(&mut address)?;
+            Ipv6Addr::from(address).to_string()
+        },
+        _ => {
+            stream.write_all(&[5, 8, 0, 1, 0, 0, 0, 0, 0, 0])?;
+            return Err("Unsupported SOCKS5 address type".into());
+        }
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port)?;
+
+    stream.set_read_timeout(None)?;
+    return Ok((host, u16::from_be_bytes(port)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs_are_parsed_like_ssh_options() {
+        assert_eq!(TunnelSpec::parse("-L", "5432:db.internal:5432").unwrap(), TunnelSpec::Local {
+            bind: String::from("127.0.0.1"), port: 5432, host: String::from("db.internal"), host_port: 5432
+        });
+        assert_eq!(TunnelSpec::parse("-L", "0.0.0.0:8080:localhost:80").unwrap(), TunnelSpec::Local {
+            bind: String::from("0.0.0.0"), port: 8080, host: String::from("localhost"), host_port: 80
+        });
+        assert_eq!(TunnelSpec::parse("-R", "9000:localhost:3000").unwrap(), TunnelSpec::Remote {
+            port: 9000, host: String::from("localhost"), host_port: 3000
+        });
+        assert_eq!(TunnelSpec::parse_line(" -D 1080 ").unwrap(), TunnelSpec::Dynamic { bind: String::from("127.0.0.1"), port: 1080 });
+        assert_eq!(TunnelSpec::parse_line("-D 0.0.0.0:1080").unwrap().to_string(), "-D 0.0.0.0:1080 (SOCKS5)");
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        assert!(TunnelSpec::parse("-L", "5432:db.internal").is_err());
+        assert!(TunnelSpec::parse("-L", "99999:db.internal:5432").is_err());
+        assert!(TunnelSpec::parse("-R", "0.0.0.0:9000:localhost:3000").is_err());
+        assert!(TunnelSpec::parse("-D", "a:b:c").is_err());
+        assert!(TunnelSpec::parse("-X", "1080").is_err());
+        assert!(TunnelSpec::parse_line("-L").is_err());
+    }
+
+    /// Returns the result of reading the request written by a client
+    fn socks(request: &'static [u8]) -> Result<(String, u16), String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        client.write_all(request).unwrap();
+        return socks_request(&mut stream).map_err(|err| err.to_string());
+    }
+
+    #[test]
+    fn socks_connect_requests_are_read() {
+        assert_eq!(socks(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80]), Ok((String::from("10.0.0.1"), 80)));
+        assert_eq!(socks(b"\x05\x01\x00\x05\x01\x00\x03\x0bexample.com\x01\xbb"), Ok((String::from("example.com"), 443)));
+        assert!(socks(&[5, 1, 2]).is_err());
+        assert!(socks(&[5, 1, 0, 5, 2, 0, 1, 10, 0, 0, 1, 0, 80]).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::history::{Run, Status};
+use crate::http::{HttpClient, HttpSettings};
+use crate::template;
+
+/// Header holding the HMAC-SHA256 of the body, as `sha256=HEX`
+pub const SIGNATURE_HEADER: &str = "X-Basecamp-Signature";
+
+/// Header holding the event the request is sent for
+pub const EVENT_HEADER: &str = "X-Basecamp-Event";
+
+/// Webhooks notified of finished runs, the
+/// `webhooks` section of `basecamp.yaml`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WebhookSettings {
+    /// Link to a run for the readers of the notifications,
+    /// a template such as `https://ci.internal/runs/{{ run.id }}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub endpoints: Vec<Webhook>
+}
+
+/// Events a webhook can be notified of
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// A run finished, whatever the outcome of its jobs
+    RunFinished,
+    /// A job did not succeed on a target, sent once per target
+    TargetFailed
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Event::RunFinished => "run_finished",
+            Event::TargetFailed => "target_failed"
+        };
+        return write!(f, "{}", text);
+    }
+}
+
+/// An endpoint the summaries of runs are posted to as JSON
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default = "Webhook::all_events")]
+    pub events: Vec<Event>,
+    /// Name of the environment variable holding the secret the
+    /// body is signed with, so that it is not kept in the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_env: Option<String>,
+    /// Body to send instead of the summary. Its texts are templates
+    /// rendered with the summary, e.g. `Run {{ run.id }} finished`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<Value>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Retries of the endpoint, the `http` retries by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>
+}
+
+impl Webhook {
+    fn all_events() -> Vec<Event> {
+        return vec![Event::RunFinished, Event::TargetFailed];
+    }
+
+    /// Returns the secret the body is signed with, if any
+    fn secret(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        return match &self.secret_env {
+            Some(name) => match std::env::var(name) {
+                Ok(secret) if !secret.is_empty() => Ok(Some(secret)),
+                _ => Err(format!("The secret of the webhook {} is not set in {}", self.url, name).into())
+            },
+            None => Ok(None)
+        };
+    }
+
+    /// Method to post the summary of an event, rendered into
+    /// the payload when one is configured, and signed when
+    /// a secret is configured
+    pub async fn send(&self, event: Event, summary: &Value, http: &HttpSettings) -> Result<(), Box<dyn std::error::Error>> {
+        let body = match &self.payload {
+            Some(payload) => render_payload(payload, summary)?,
+            None => summary.clone()
+        };
+
+        let mut settings = http.clone();
+        if let Some(retries) = self.retries {
+            settings.retries = retries;
+        }
+        let mut client = HttpClient::new(&self.url, &settings)?
+            .with_header(EVENT_HEADER, &event.to_string());
+        for (name, value) in &self.headers {
+            client = client.with_header(name, value);
+        }
+        if let Some(secret) = self.secret()? {
+            client = client.with_header(SIGNATURE_HEADER, &sign(&secret, &serde_json::to_vec(&body)?)?);
+        }
+
+        let response = client.request(Method::POST, "", Some(&body)).await?;
+        if !response.status.is_success() {
+            return Err(format!("{} answered {}: {}", self.url, response.status, response.body.trim()).into());
+        }
+        return Ok(());
+    }
+}
+
+/// Returns the `sha256=HEX` HMAC signature of the body
+fn sign(secret: &str, body: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|err| format!("Invalid webhook secret: {}", err))?;
+    mac.update(body);
+    return Ok(format!("sha256={}", hex::encode(mac.finalize().into_bytes())));
+}
+
+/// Method to render every text of the payload as a template
+fn render_payload(payload: &Value, summary: &Value) -> Result<Value, Box<dyn std::error::Error>> {
+    return Ok(match payload {
+        Value::String(text) => Value::String(template::render(text, summary)?),
+        Value::Array(items) => {
+            let rendered: Result<Vec<Value>, _> = items.iter().map(|item| render_payload(item, summary)).collect();
+            Value::Array(rendered?)
+        },
+        Value::Object(fields) => {
+            let mut rendered = serde_json::Map::new();
+            for (name, value) in fields {
+                rendered.insert(name.clone(), render_payload(value, summary)?);
+            }
+            Value::Object(rendered)
+        },
+        _ => payload.clone()
+    });
+}
+
+/// Method to build the summary of the run sent for the event.
+/// Summaries of `target_failed` also hold the failed target
+fn summary(event: Event, run: &Run, target: Option<&str>, links: &Value) -> Value {
+    let statuses = [Status::Success, Status::Failed, Status::TimedOut, Status::Unreachable, Status::Skipped, Status::Denied, Status::Cancelled];
+    let counts: BTreeMap<String, usize> = statuses.iter()
+        .map(|status| {
+            let name = json!(status).as_str().unwrap_or_default().to_string();
+            (name, run.results.iter().filter(|r| r.status == *status).count())
+        })
+        .collect();
+
+    let mut summary = json!({
+        "event": event.to_string(),
+        "run": {
+            "id": run.id,
+            "user": run.user,
+            "started_at": run.started_at,
+            "duration_ms": run.duration_ms,
+            "duration_secs": run.duration_ms / 1000,
+            "rerun_of": run.rerun_of,
+            "success": run.failed_servers().is_empty()
+        },
+        "targets": run.targets.iter().map(|t| t.name.as_str()).collect::<Vec<&str>>(),
+        "jobs": run.jobs.iter().map(|j| j.name.as_str()).collect::<Vec<&str>>(),
+        "counts": counts,
+        "failed_targets": run.failed_servers(),
+        "links": links
+    });
+    if let Some(target) = target {
+        let results: Vec<Value> = run.results.iter()
+            .filter(|r| r.server == target)
+            .map(|r| json!({
+                "job": r.job,
+                "status": r.status,
+                "exit_status": r.exit_status,
+                "error": r.error
+            }))
+            .collect();
+        summary["target"] = json!({ "name": target, "results": results });
+    }
+    return summary;
+}
+
+/// Method to notify the webhooks of a finished run, stored in
+/// the history at `history_path`. Webhooks which can not be
+/// notified are reported without failing the run
+///
+/// # Examples
+/// ```no_run
+/// let path = History::open_default().save(&run)?;
+/// webhooks::notify(&settings.webhooks, &settings.http, &run, &path).await;
+/// ```
+pub async fn notify(settings: &WebhookSettings, http: &HttpSettings, run: &Run, history_path: &Path) {
+    if settings.endpoints.is_empty() {
+        return;
+    }
+
+    let mut links = json!({
+        "history": history_path.display().to_string(),
+        "rerun": format!("basecamp rerun {}", run.id)
+    });
+    if let Some(url) = &settings.history_url {
+        match template::render(url, &json!({ "run": { "id": run.id, "user": run.user } })) {
+            Ok(url) => links["url"] = Value::String(url),
+            Err(err) => eprintln!("Invalid history_url of the webhooks: {}", err)
+        }
+    }
+
+    let mut events = Vec::new();
+    for target in run.failed_servers() {
+        events.push((Event::TargetFailed, summary(Event::TargetFailed, run, Some(&target), &links)));
+    }
+    events.push((Event::RunFinished, summary(Event::RunFinished, run, None, &links)));
+
+    for webhook in &settings.endpoints {
+        for (event, summary) in events.iter().filter(|(event, _)| webhook.events.contains(event)) {
+            if let Err(err) = webhook.send(*event, summary, http).await {
+                eprintln!("Could not notify the webhook {} of {}: {}", webhook.url, event, err);
+            }
+        }
+    }
+}
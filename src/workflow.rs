@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::history::Status;
+use crate::job::Job;
+
+/// Root of the template variables holding the
+/// outputs of the upstream jobs
+pub const OUTPUTS: &str = "outputs";
+
+/// When a job runs, depending on the outcome of
+/// the jobs it needs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// Every needed job succeeded
+    OnSuccess,
+    /// At least one needed job did not succeed
+    OnFailure,
+    /// Every needed job finished, whatever the outcome
+    Always
+}
+
+impl Default for Condition {
+    fn default() -> Self {
+        return Condition::OnSuccess;
+    }
+}
+
+impl Condition {
+    /// Returns true if a job with this condition should run
+    /// given the statuses of the jobs it needs
+    pub fn is_met(&self, upstream: &[Status]) -> bool {
+        let succeeded = upstream.iter().all(|s| *s == Status::Success);
+        return match self {
+            Condition::OnSuccess => succeeded,
+            Condition::OnFailure => !succeeded,
+            Condition::Always => true
+        };
+    }
+}
+
+/// A named value captured from the output of a job, using
+/// either a regex or a dotted path into the json output
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputSpec {
+    /// The first capture group is used if there is one,
+    /// otherwise the whole match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub json: Option<String>
+}
+
+impl OutputSpec {
+    /// Method to check that exactly one capture method
+    /// is set and that the regex compiles
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.regex.is_some() == self.json.is_some() {
+            return Err("an output must have either a regex or a json path".into());
+        }
+        if let Some(regex) = &self.regex {
+            Regex::new(regex)?;
+        }
+        return Ok(());
+    }
+
+    /// Method to capture the value from the output of
+    /// a job. Returns `None` if nothing matched
+    pub fn capture(&self, output: &str) -> Option<String> {
+        if let Some(regex) = &self.regex {
+            let captures = Regex::new(regex).ok()?.captures(output)?;
+            let value = captures.get(1).or_else(|| captures.get(0))?;
+            return Some(value.as_str().to_string());
+        }
+
+        let path = self.json.as_ref()?;
+        let mut value: &Value = &serde_json::from_str::<Value>(output).ok()?;
+        for segment in path.split('.').filter(|s| !s.is_empty()) {
+            value = match value {
+                Value::Object(map) => map.get(segment)?,
+                Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+                _ => return None
+            };
+        }
+        return match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Null => None,
+            other => Some(other.to_string())
+        };
+    }
+}
+
+/// Method to add the jobs needed by the selected jobs, so
+/// that every dependency is part of the run. Dependencies
+/// are placed before the jobs which need them
+///
+/// # Examples
+/// ```no_run
+/// let jobs = workflow::with_dependencies(&selected, &catalog)?;
+/// ```
+pub fn with_dependencies(selected: &[Job], catalog: &[Job]) -> Result<Vec<Job>, Box<dyn std::error::Error>> {
+    fn visit(job: &Job, catalog: &[Job], path: &mut Vec<String>, ordered: &mut Vec<Job>) -> Result<(), Box<dyn std::error::Error>> {
+        if ordered.iter().any(|j| j.name == job.name) {
+            return Ok(());
+        }
+        if path.contains(&job.name) {
+            path.push(job.name.clone());
+            return Err(format!("Jobs depend on each other: {}", path.join(" -> ")).into());
+        }
+
+        path.push(job.name.clone());
+        for name in &job.needs {
+            let needed = match catalog.iter().find(|j| &j.name == name) {
+                Some(needed) => needed,
+                None => return Err(format!("Job {} needs unknown job {}", job.name, name).into())
+            };
+            visit(needed, catalog, path, ordered)?;
+        }
+        path.pop();
+        ordered.push(job.clone());
+        return Ok(());
+    }
+
+    let mut ordered = Vec::with_capacity(selected.len());
+    for job in selected {
+        visit(job, catalog, &mut Vec::new(), &mut ordered)?;
+    }
+    return Ok(ordered);
+}
+
+/// Method to check that the jobs only use the outputs of
+/// the jobs they need, directly or transitively, and that
+/// those jobs declare the outputs
+pub fn validate(jobs: &[Job]) -> Result<(), Box<dyn std::error::Error>> {
+    // Returns the names of every job needed by the job
+    fn upstream<'a>(job: &'a Job, jobs: &'a [Job], found: &mut Vec<&'a str>) {
+        for name in &job.needs {
+            if found.contains(&name.as_str()) {
+                continue;
+            }
+            found.push(name);
+            if let Some(needed) = jobs.iter().find(|j| &j.name == name) {
+                upstream(needed, jobs, found);
+            }
+        }
+    }
+
+    for job in jobs {
+        let mut needed = Vec::new();
+        upstream(job, jobs, &mut needed);
+
+        for variable in job.variables()? {
+            let mut segments = variable.split('.');
+            if segments.next() != Some(OUTPUTS) {
+                continue;
+            }
+            let (from, name) = match (segments.next(), segments.next(), segments.next()) {
+                (Some(from), Some(name), None) => (from, name),
+                _ => return Err(format!("Job {}: `{}` must be written as {}.JOB.NAME", job.name, variable, OUTPUTS).into())
+            };
+            if !needed.contains(&from) {
+                return Err(format!("Job {} uses the outputs of {} without needing it", job.name, from).into());
+            }
+            let declared = jobs.iter()
+                .find(|j| j.name == from)
+                .map(|j| j.outputs.contains_key(name))
+                .unwrap_or(false);
+            if !declared {
+                return Err(format!("Job {} uses output {} which job {} does not declare", job.name, name, from).into());
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Method to capture every output declared by the job
+pub fn capture_outputs(job: &Job, output: &str) -> BTreeMap<String, String> {
+    let mut outputs = BTreeMap::new();
+    for (name, spec) in &job.outputs {
+        if let Some(value) = spec.capture(output) {
+            outputs.insert(name.clone(), value);
+        }
+    }
+    return outputs;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs(yaml: &str) -> Vec<Job> {
+        return serde_yaml::from_str(yaml).unwrap();
+    }
+
+    fn names(jobs: &[Job]) -> Vec<&str> {
+        return jobs.iter().map(|j| j.name.as_str()).collect();
+    }
+
+    #[test]
+    fn dependencies_run_first() {
+        let catalog = jobs("
+- { name: build, command: make }
+- { name: test, command: make test, needs: [build] }
+- { name: deploy, command: make deploy, needs: [test, build] }
+");
+        let ordered = with_dependencies(&catalog[2..], &catalog).unwrap();
+        assert_eq!(names(&ordered), vec!["build", "test", "deploy"]);
+        let ordered = with_dependencies(&[catalog[1].clone(), catalog[0].clone()], &catalog).unwrap();
+        assert_eq!(names(&ordered), vec!["build", "test"]);
+    }
+
+    #[test]
+    fn cycles_and_unknown_jobs_are_rejected() {
+        let catalog = jobs("
+- { name: a, command: a, needs: [b] }
+- { name: b, command: b, needs: [a] }
+- { name: c, command: c, needs: [missing] }
+");
+        let err = with_dependencies(&catalog[..1], &catalog).unwrap_err().to_string();
+        assert_eq!(err, "Jobs depend on each other: a -> b -> a");
+        assert!(with_dependencies(&catalog[2..], &catalog).is_err());
+    }
+
+    #[test]
+    fn conditions_depend_on_the_needed_jobs() {
+        let failed = [Status::Success, Status::Failed];
+        assert!(Condition::OnSuccess.is_met(&[Status::Success]));
+        assert!(!Condition::OnSuccess.is_met(&failed));
+        assert!(Condition::OnFailure.is_met(&failed));
+        assert!(!Condition::OnFailure.is_met(&[Status::Success]));
+        assert!(Condition::Always.is_met(&failed));
+    }
+
+    #[test]
+    fn outputs_are_captured_with_a_regex_or_a_json_path() {
+        let job = &jobs(r#"
+- name: build
+  command: make
+  outputs:
+    version: { regex: 'version (\S+)' }
+    line: { regex: 'ok' }
+    image: { json: build.images.0 }
+    missing: { regex: 'nothing' }
+"#)[0];
+        let outputs = capture_outputs(job, "version 1.2.3 ok");
+        assert_eq!(outputs.get("version").map(String::as_str), Some("1.2.3"));
+        assert_eq!(outputs.get("line").map(String::as_str), Some("ok"));
+        assert!(!outputs.contains_key("missing"));
+
+        let outputs = capture_outputs(job, r#"{"build": {"images": ["app:1.2"]}}"#);
+        assert_eq!(outputs.get("image").map(String::as_str), Some("app:1.2"));
+    }
+
+    #[test]
+    fn outputs_must_come_from_needed_jobs() {
+        let valid = jobs("
+- { name: build, command: make, outputs: { version: { regex: '(.*)' } } }
+- { name: test, command: make test, needs: [build] }
+- { name: deploy, command: 'deploy {{ outputs.build.version }}', needs: [test] }
+");
+        assert!(validate(&valid).is_ok());
+
+        let not_needed = jobs("
+- { name: build, command: make, outputs: { version: { regex: '(.*)' } } }
+- { name: deploy, command: 'deploy {{ outputs.build.version }}' }
+");
+        assert!(validate(&not_needed).is_err());
+
+        let undeclared = jobs("
+- { name: build, command: make }
+- { name: deploy, command: 'deploy {{ outputs.build.version }}', needs: [build] }
+");
+        assert!(validate(&undeclared).is_err());
+    }
+}